@@ -1,3 +1,6 @@
+// These lints fire on the original benchmarks, which are left as they are.
+#![allow(clippy::single_component_path_imports, clippy::useless_vec)]
+
 use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
 use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use rand::prelude::*;
@@ -0,0 +1,29 @@
+//! Replays arbitrary bytes as a `KvStore` log.
+//!
+//! Opening may fail, but must do so with a `KvsError`. A store that opens must
+//! serve every key it indexed and survive a compaction.
+#![no_main]
+
+use kvs::{KvStore, KvsEngine, SimFs, Vfs};
+use libfuzzer_sys::fuzz_target;
+use std::io::Write;
+use std::path::Path;
+
+fuzz_target!(|data: &[u8]| {
+    let fs = SimFs::new();
+    let dir = Path::new("/data");
+    fs.create_dir_all(dir).unwrap();
+    fs.create_new(&dir.join("1.log"))
+        .unwrap()
+        .write_all(data)
+        .unwrap();
+
+    let Ok(mut store) = KvStore::open_with(fs.clone(), dir) else {
+        return;
+    };
+    for key in store.keys().unwrap() {
+        let _ = store.get(key);
+    }
+    let _ = store.compact();
+    let _ = store.set("key".to_owned(), "value".to_owned());
+});
@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes to a server speaking RESP as the command stream of a
+//! connection.
+//!
+//! The server must answer or reject the input with a `KvsError`, and never
+//! panic or wait for more input than it was given.
+#![no_main]
+
+use kvs::{KvsServer, MemoryKvsEngine, Protocol, ServerConfig};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+
+fuzz_target!(|data: &[u8]| {
+    let config = ServerConfig {
+        protocol: Protocol::Resp,
+        ..ServerConfig::default()
+    };
+    let mut server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+    let _ = server.serve_stream(data, io::sink());
+});
@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes to the server as the request stream of a connection.
+//!
+//! The server must answer or reject the input with a `KvsError`, and never
+//! panic or wait for more input than it was given.
+#![no_main]
+
+use kvs::{KvsServer, MemoryKvsEngine};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+
+fuzz_target!(|data: &[u8]| {
+    let mut server = KvsServer::new(MemoryKvsEngine::new());
+    let _ = server.serve_stream(data, io::sink());
+});
@@ -0,0 +1,375 @@
+use crate::client::retry_wait;
+use crate::codec::{
+    accept_server_hello, client_hello, encode_frame, read_frame_async, unexpected_eof,
+};
+use crate::common::{
+    CompactResponse, GetResponse, InfoResponse, PingResponse, RemoveResponse, Request,
+    RequestFrame, Response, ResponseFrame, ServerStatus, SetResponse, SlowLogResponse,
+    StatsResponse, unexpected_response, unsupported,
+};
+use crate::transport::connect_async;
+use crate::{
+    Address, ClientConfig, Codec, EngineStats, KvsError, Result, ServerInfo, SlowLogEntry,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::oneshot;
+use tokio::time;
+
+/// The requests still waiting for a response, by request ID, and the ID of
+/// the next request.
+///
+/// `None` once the connection has failed.
+type Pending = Arc<Mutex<Option<(HashMap<u64, oneshot::Sender<Result<Response>>>, u64)>>>;
+
+/// Key value store client for async code.
+///
+/// A client is cheap to clone, and all clones share one connection. Requests
+/// issued concurrently from many tasks are pipelined on that connection: each
+/// one is written right away, without waiting for earlier responses. The
+/// server runs them in the order they arrive, but may answer them in any
+/// order: every request carries an ID that its response echoes.
+#[derive(Clone)]
+pub struct AsyncKvsClient {
+    writer: Arc<Writer>,
+    pending: Pending,
+    codec: Codec,
+    server_info: Arc<ServerInfo>,
+    timeout: Option<Duration>,
+    max_throttle_wait: Option<Duration>,
+}
+
+/// The writing half of the connection, shared by all clones of a client.
+///
+/// Once the last clone is dropped, so is `_alive`, which stops the task
+/// reading responses. That drops the reading half too and closes the
+/// connection.
+struct Writer {
+    stream: tokio::sync::Mutex<BufWriter<Box<dyn AsyncWrite + Send + Unpin>>>,
+    _alive: oneshot::Sender<()>,
+}
+
+impl AsyncKvsClient {
+    /// Connect to `addr` to access `KvsServer` or `AsyncKvsServer`, over TCP
+    /// or a Unix domain socket.
+    ///
+    /// This must be called inside a tokio runtime, which runs the task reading
+    /// the responses.
+    pub async fn connect<A: Into<Address>>(addr: A) -> Result<Self> {
+        Self::connect_with_config(addr, &ClientConfig::default()).await
+    }
+
+    /// Connect to `addr` with the given settings.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ProtocolMismatch` if the server speaks another
+    /// protocol version, `KvsError::Tls` if the TLS handshake fails,
+    /// `KvsError::AuthenticationFailed` if the server turns the credentials
+    /// down, and `KvsError::Timeout` if connecting takes longer than
+    /// `ClientConfig::connect_timeout`.
+    pub async fn connect_with_config<A: Into<Address>>(
+        addr: A,
+        config: &ClientConfig,
+    ) -> Result<Self> {
+        let addr = addr.into();
+        let connecting = async {
+            let stream = connect_async(&addr).await?;
+            match &config.tls {
+                Some(tls) => {
+                    Self::from_stream(tls.connect_async(&addr, stream).await?, config).await
+                }
+                None => Self::from_stream(stream, config).await,
+            }
+        };
+        match config.connect_timeout {
+            Some(timeout) => time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| KvsError::Timeout("connecting to the server".to_owned()))?,
+            None => connecting.await,
+        }
+    }
+
+    /// Talks to a server over the given byte stream, such as an in-memory
+    /// pipe from `tokio::io::duplex` to `AsyncKvsServer::serve_stream`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ProtocolMismatch` if the server speaks another
+    /// protocol version.
+    pub async fn from_stream<S>(stream: S, config: &ClientConfig) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        writer
+            .write_all(&client_hello(
+                vec![config.codec],
+                config.credentials.clone(),
+            )?)
+            .await?;
+
+        let mut buf = Vec::new();
+        let hello = read_frame_async(&mut reader, &mut buf, Codec::Json, config.max_frame_len)
+            .await?
+            .ok_or_else(unexpected_eof)?;
+        let (codec, server_info) = accept_server_hello(hello, &[config.codec])?;
+
+        let pending: Pending = Arc::new(Mutex::new(Some((HashMap::new(), 0))));
+        let (alive, dropped) = oneshot::channel();
+        tokio::spawn(read_responses(
+            Box::new(reader),
+            buf,
+            codec,
+            config.max_frame_len,
+            Arc::clone(&pending),
+            dropped,
+        ));
+        Ok(AsyncKvsClient {
+            writer: Arc::new(Writer {
+                stream: tokio::sync::Mutex::new(BufWriter::new(Box::new(writer))),
+                _alive: alive,
+            }),
+            pending,
+            codec,
+            server_info: Arc::new(server_info),
+            timeout: config.timeout,
+            max_throttle_wait: config.max_throttle_wait,
+        })
+    }
+
+    /// Returns what the server told about itself when connecting.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server_info
+    }
+
+    /// Get the value of a given key from the server.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        match self.call(Request::Get { key }).await? {
+            Response::Get(GetResponse::Ok(value)) => Ok(value),
+            Response::Get(GetResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Set the value of a string key in the server.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        match self.call(Request::Set { key, value }).await? {
+            Response::Set(SetResponse::Ok(_)) => Ok(()),
+            Response::Set(SetResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Remove a string key in the server.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        match self.call(Request::Remove { key }).await? {
+            Response::Remove(RemoveResponse::Ok(_)) => Ok(()),
+            Response::Remove(RemoveResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Checks that the server answers.
+    pub async fn ping(&self) -> Result<()> {
+        match self.call(Request::Ping).await? {
+            Response::Ping(PingResponse::Ok(_)) => Ok(()),
+            Response::Ping(PingResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Asks the server what it is and how long it has been running.
+    ///
+    /// # Errors
+    ///
+    /// Like `stats` and `compact`, it returns `KvsError::PermissionDenied`
+    /// unless the user holds `Permission::Admin`, on a server with an ACL.
+    pub async fn info(&self) -> Result<ServerStatus> {
+        match self.call(Request::Info).await? {
+            Response::Info(InfoResponse::Ok(status)) => Ok(status),
+            Response::Info(InfoResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Asks for the statistics of the engine of the server.
+    pub async fn stats(&self) -> Result<EngineStats> {
+        match self.call(Request::Stats).await? {
+            Response::Stats(StatsResponse::Ok(stats)) => Ok(stats),
+            Response::Stats(StatsResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Compacts the engine of the server now, and returns once it is done.
+    pub async fn compact(&self) -> Result<()> {
+        match self.call(Request::Compact).await? {
+            Response::Compact(CompactResponse::Ok(_)) => Ok(()),
+            Response::Compact(CompactResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the latest `count` entries of the slow log of the server, the
+    /// newest first. A server without a slow log returns none, and one that
+    /// did not announce the `slowlog` feature is not asked at all.
+    pub async fn slow_log(&self, count: usize) -> Result<Vec<SlowLogEntry>> {
+        if !self.server_info.supports("slowlog") {
+            return Err(unsupported("slowlog"));
+        }
+        match self.call(Request::SlowLog { count }).await? {
+            Response::SlowLog(SlowLogResponse::Ok(entries)) => Ok(entries),
+            Response::SlowLog(SlowLogResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Sends `request` and waits for its response, retrying it while the
+    /// server throttles it, as long as `max_throttle_wait` allows.
+    async fn call(&self, request: Request) -> Result<Response> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let response = self.call_once(request.clone()).await?;
+            match retry_wait(&response, waited, self.max_throttle_wait) {
+                Some(wait) => {
+                    time::sleep(wait).await;
+                    waited += wait;
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// Sends `request` and waits for its response.
+    async fn call_once(&self, request: Request) -> Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            let (waiting, next_id) = pending.as_mut().ok_or_else(connection_closed)?;
+            let id = *next_id;
+            *next_id += 1;
+            waiting.insert(id, sender);
+            id
+        };
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+        let mut bytes = Vec::new();
+        encode_frame(self.codec, &RequestFrame { id, request }, &mut bytes)?;
+        let exchange = async {
+            {
+                let mut writer = self.writer.stream.lock().await;
+                let mut writing = Writing {
+                    pending: &self.pending,
+                    done: false,
+                };
+                writer.write_all(&bytes).await?;
+                writer.flush().await?;
+                writing.done = true;
+            }
+            receiver.await.map_err(|_| connection_closed())?
+        };
+        let Some(timeout) = self.timeout else {
+            return exchange.await;
+        };
+        match time::timeout(timeout, exchange).await {
+            Ok(response) => response,
+            // A late response finds the request gone, and is dropped.
+            Err(_) => Err(KvsError::Timeout("waiting for the server".to_owned())),
+        }
+    }
+}
+
+/// Takes a request out of `pending` once its call is over, including when
+/// the call times out or is dropped.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if let Some((waiting, _)) = self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_mut()
+        {
+            waiting.remove(&self.id);
+        }
+    }
+}
+
+/// Fails the connection if dropped before `done` is set, as a request that
+/// was cut off while being written leaves part of a frame on the connection
+/// that the server cannot make sense of.
+struct Writing<'a> {
+    pending: &'a Pending,
+    done: bool,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            fail_pending(self.pending, "a request was only partly sent");
+        }
+    }
+}
+
+/// Marks the connection as failed, and fails every request waiting on it.
+fn fail_pending(pending: &Pending, message: &str) {
+    if let Some((waiting, _)) = pending.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        for sender in waiting.into_values() {
+            let _ = sender.send(Err(KvsError::StringError(message.to_owned())));
+        }
+    }
+}
+
+fn connection_closed() -> KvsError {
+    KvsError::StringError("Connection to the server is closed".to_owned())
+}
+
+/// Hands every response read from `reader` to the request with its ID, until
+/// the connection fails or the client is dropped.
+async fn read_responses(
+    mut reader: Box<dyn AsyncRead + Send + Unpin>,
+    mut buf: Vec<u8>,
+    codec: Codec,
+    max_frame_len: usize,
+    pending: Pending,
+    mut dropped: oneshot::Receiver<()>,
+) {
+    let error = loop {
+        let read =
+            read_frame_async::<ResponseFrame, _>(&mut reader, &mut buf, codec, max_frame_len);
+        let read = tokio::select! {
+            read = read => read,
+            // Every clone of the client is gone, so nobody waits for more.
+            _ = &mut dropped => break connection_closed(),
+        };
+        match read {
+            Ok(Some(ResponseFrame { id, response })) => {
+                let mut state = pending.lock().unwrap_or_else(|e| e.into_inner());
+                let Some((waiting, next_id)) = state.as_mut() else {
+                    break connection_closed();
+                };
+                match waiting.remove(&id) {
+                    Some(sender) => {
+                        let _ = sender.send(Ok(response));
+                    }
+                    // Its request timed out or was dropped.
+                    None if id < *next_id => {}
+                    None => break unexpected_response(),
+                }
+            }
+            Ok(None) => break connection_closed(),
+            Err(e) => break e,
+        }
+    };
+
+    fail_pending(&pending, &error.to_string());
+}
@@ -0,0 +1,749 @@
+use crate::codec::{ServerFraming, unexpected_eof};
+use crate::common::{RequestFrame, Response, ResponseFrame};
+use crate::http;
+use crate::ratelimit::Budgets;
+use crate::resp::{self, Expiries, RespValue};
+use crate::server::{
+    Connection, RequestTrace, ServerState, Timeouts, handle_request, request_timed_out,
+};
+use crate::transport::{AsyncListener, AsyncStream, read_len};
+use crate::{
+    Acl, Address, KvsEngine, KvsError, Protocol, Result, ServerConfig, ServerTlsConfig,
+    ShutdownHandle,
+};
+
+use log::{debug, error, warn};
+use std::collections::VecDeque;
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use tracing::{Instrument, Span, debug_span};
+
+/// The number of requests of one connection that may wait to be answered.
+/// Further requests are not read until one of them is.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// A key-value store server that handles TCP connections on the tokio runtime.
+///
+/// Where `KvsServer` serves one connection at a time, `AsyncKvsServer` serves
+/// every connection as a task of its own, so many thousands of idle
+/// connections cost little. It speaks the same protocol as `KvsServer`.
+///
+/// Engine calls block, so they run on tokio's blocking thread pool through
+/// `spawn_blocking`, one at a time on an engine shared by all connections.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: Arc<Mutex<E>>,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
+    expiries: Arc<Expiries>,
+    state: Arc<ServerState>,
+}
+
+impl<E: KvsEngine + Send + 'static> AsyncKvsServer<E> {
+    /// Creates a new `AsyncKvsServer` instance with the provided key-value storage engine.
+    pub fn new(engine: E) -> Self {
+        Self::with_config(engine, ServerConfig::default())
+    }
+
+    /// Creates a new `AsyncKvsServer` instance with the given settings.
+    pub fn with_config(engine: E, config: ServerConfig) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            state: Arc::new(ServerState::new(&config)),
+            config,
+            shutdown: ShutdownHandle::new(),
+            expiries: Arc::default(),
+        }
+    }
+
+    /// Returns a handle that stops the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Binds to the given address, TCP or `unix:PATH`, and serves incoming
+    /// client connections.
+    ///
+    /// This must be awaited inside a tokio runtime.
+    ///
+    /// Once a shutdown is requested, the server stops accepting connections
+    /// and closes idle ones. Connections in the middle of a request get until
+    /// `ServerConfig::shutdown_timeout` to finish it. Then the engine is
+    /// synced and `run` returns.
+    pub async fn run<A: Into<Address>>(self, addr: A) -> Result<()> {
+        let listener = AsyncListener::bind(&addr.into()).await?;
+        let http_listener = bind_optional(self.config.http_addr).await?;
+        let metrics_listener = bind_optional(self.config.metrics_addr).await?;
+        let engine_name = self.engine_name()?;
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer_addr)) => {
+                        let Some(connection) = self.state.try_connect(&peer_addr) else {
+                            continue;
+                        };
+                        let tls = self.config.tls.clone();
+                        let served = self.serve_connection(stream, peer_addr, &engine_name, tls);
+                        connections.spawn(async move {
+                            let _connection = connection;
+                            if let Err(e) = served.await {
+                                error!("Error serving client: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Connection failed: {}", e),
+                },
+                accepted = accept_optional(&http_listener) => match accepted {
+                    Ok((stream, peer_addr)) => {
+                        if let Some(connection) = self.state.try_connect(&peer_addr) {
+                            let served =
+                                self.serve_http_connection(connection, stream, peer_addr, false);
+                            connections.spawn(served);
+                        }
+                    }
+                    Err(e) => error!("Connection failed: {}", e),
+                },
+                accepted = accept_optional(&metrics_listener) => match accepted {
+                    Ok((stream, peer_addr)) => {
+                        if let Some(connection) = self.state.try_connect(&peer_addr) {
+                            let served =
+                                self.serve_http_connection(connection, stream, peer_addr, true);
+                            connections.spawn(served);
+                        }
+                    }
+                    Err(e) => error!("Connection failed: {}", e),
+                },
+                // Reap finished connections so that the set stays small.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = self.shutdown.wait() => break,
+            }
+        }
+        drop(listener);
+        drop(http_listener);
+        drop(metrics_listener);
+
+        let drained = time::timeout(self.config.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Shutdown deadline passed, closing {} connections",
+                connections.len()
+            );
+            connections.shutdown().await;
+        }
+        with_engine(&self.engine, |engine| engine.sync()).await?
+    }
+
+    /// Serves a single connection over `stream`, speaking the configured
+    /// protocol, until the client closes it.
+    ///
+    /// `run` does this for every accepted connection. Any byte stream will do
+    /// though, such as an in-memory pipe from `tokio::io::duplex`, which lets
+    /// tests talk to the server without sockets. This must be awaited inside
+    /// a tokio runtime.
+    pub async fn serve_stream<S>(&self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let engine_name = self.engine_name()?;
+        let _connection = self.state.connect();
+        self.serve_connection(Box::new(stream), "stream".to_owned(), &engine_name, None)
+            .await
+    }
+
+    fn engine_name(&self) -> Result<String> {
+        Ok(self
+            .engine
+            .lock()
+            .map_err(|_| poisoned())?
+            .name()
+            .to_owned())
+    }
+
+    fn context(&self) -> Context<E> {
+        Context {
+            engine: Arc::clone(&self.engine),
+            state: Arc::clone(&self.state),
+            expiries: Arc::clone(&self.expiries),
+            acl: self.config.acl.clone(),
+            max_frame_len: self.config.max_frame_len,
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    fn serve_connection(
+        &self,
+        stream: Box<dyn AsyncStream>,
+        peer_addr: String,
+        engine_name: &str,
+        tls: Option<ServerTlsConfig>,
+    ) -> Pin<Box<dyn Future<Output = Result<()>> + Send>> {
+        let context = self.context();
+        let protocol = self.config.protocol;
+        let framing =
+            ServerFraming::new(context.max_frame_len, engine_name, self.config.acl.clone());
+        let span = debug_span!("connection", peer = %peer_addr);
+        Box::pin(
+            async move {
+                let stream = secure(tls, stream).await?;
+                match protocol {
+                    Protocol::Kvs => serve(context, stream, peer_addr, framing).await,
+                    Protocol::Resp => serve_resp(context, stream, peer_addr).await,
+                }
+            }
+            .instrument(span),
+        )
+    }
+
+    /// Serves a connection to the HTTP gateway, or to the metrics endpoint
+    /// if `metrics` is set.
+    fn serve_http_connection(
+        &self,
+        connection: Connection,
+        stream: Box<dyn AsyncStream>,
+        peer_addr: String,
+        metrics: bool,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let context = self.context();
+        let tls = self.config.tls.clone();
+        let span = debug_span!("connection", peer = %peer_addr);
+        async move {
+            let _connection = connection;
+            let served = async {
+                let stream = secure(tls, stream).await?;
+                serve_http(context, metrics, stream, peer_addr).await
+            };
+            if let Err(e) = served.await {
+                error!("Error serving HTTP client: {}", e);
+            }
+        }
+        .instrument(span)
+    }
+}
+
+/// What every connection of a server works with.
+struct Context<E> {
+    engine: Arc<Mutex<E>>,
+    state: Arc<ServerState>,
+    expiries: Arc<Expiries>,
+    acl: Option<Acl>,
+    max_frame_len: usize,
+    shutdown: ShutdownHandle,
+}
+
+/// Runs the TLS handshake on `stream` if TLS is configured.
+async fn secure(
+    tls: Option<ServerTlsConfig>,
+    stream: Box<dyn AsyncStream>,
+) -> Result<Box<dyn AsyncStream>> {
+    match tls {
+        Some(tls) => tls.accept_async(stream).await,
+        None => Ok(stream),
+    }
+}
+
+/// Handles a single client connection over the given stream.
+///
+/// Requests are read ahead while earlier ones run, but they run one at a time
+/// in the order they arrive, so each sees the effects of those before it.
+/// Refused requests are answered right away, ahead of the requests still
+/// waiting, which is fine as clients match responses up by ID.
+async fn serve<E: KvsEngine + Send + 'static>(
+    context: Context<E>,
+    stream: Box<dyn AsyncStream>,
+    peer_addr: String,
+    mut framing: ServerFraming,
+) -> Result<()> {
+    let metrics = &context.state.metrics;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = Vec::new();
+    let mut out = Vec::new();
+    // The requests waiting to run, and the one running.
+    let mut queued = VecDeque::new();
+    let mut running = None;
+    let mut reading = true;
+    // The refused requests answered in `out`, finished once it is written.
+    let mut refused = Vec::new();
+    let mut budgets = Budgets::default();
+    let timeouts = context.state.timeouts;
+    // When bytes last arrived, and when they did or a response was last sent.
+    let mut received = Instant::now();
+    let mut last_active = received;
+
+    loop {
+        let in_flight = queued.len() + usize::from(running.is_some());
+        if in_flight < MAX_IN_FLIGHT
+            && let Some(RequestFrame { id, request }) =
+                decode(&mut framing, &mut buf, &mut out).await?
+        {
+            let kind = request.kind();
+            let span = debug_span!("request", id, operation = kind);
+            span.in_scope(|| debug!("Receive request {} from {}: {:?}", id, peer_addr, request));
+            let mut trace = RequestTrace::new("kvs", kind, request.sizes(), received);
+            trace.audit(request.audited(), framing.user());
+            let access = request.access();
+            let allowed = framing
+                .authorize(&request)
+                .and_then(|()| context.state.throttle(&mut budgets, framing.user(), access));
+            if let Err(e) = allowed {
+                let response = Response::error(&request, e);
+                trace.answered(response.outcome(), None, response.error_message());
+                context.state.audit(&mut trace, &peer_addr);
+                refused.push(trace);
+                framing.encode(&ResponseFrame { id, response }, &mut out)?;
+                continue;
+            }
+            let engine = Arc::clone(&context.engine);
+            let state = Arc::clone(&context.state);
+            let peer = peer_addr.clone();
+            let run: Run = Box::pin(
+                async move {
+                    let response = with_engine(&engine, move |engine| {
+                        let response = if timeouts.expired(received) {
+                            Response::error(&request, request_timed_out())
+                        } else {
+                            trace.engine(|| handle_request(engine, &state, request))
+                        };
+                        trace.answered(
+                            response.outcome(),
+                            response.value_len(),
+                            response.error_message(),
+                        );
+                        state.audit(&mut trace, &peer);
+                        (ResponseFrame { id, response }, trace)
+                    });
+                    response.await
+                }
+                .instrument(span),
+            );
+            queued.push_back(run);
+            continue;
+        }
+        if running.is_none() {
+            running = queued.pop_front();
+        }
+        // the answer to a handshake or refused requests
+        if !out.is_empty() {
+            metrics.sent(out.len());
+            send(&mut writer, &out, &timeouts).await?;
+            out.clear();
+            last_active = Instant::now();
+        }
+        for trace in refused.drain(..) {
+            context.state.finish(trace, &peer_addr);
+        }
+        framing.check_open()?;
+        if !reading && running.is_none() {
+            break;
+        }
+
+        // A connection waiting for the engine is neither idle nor slow.
+        let idle = framing.is_idle(&buf);
+        let deadline = if idle && running.is_some() {
+            None
+        } else {
+            timeouts.deadline(idle, last_active)
+        };
+        tokio::select! {
+            read = reader.read_buf(&mut buf), if reading && in_flight < MAX_IN_FLIGHT => {
+                match read_len(read)? {
+                    0 => reading = false,
+                    len => {
+                        metrics.received(len);
+                        received = Instant::now();
+                        last_active = received;
+                    }
+                }
+            }
+            done = run_optional(&mut running) => {
+                running = None;
+                let (frame, trace) = done?;
+                framing.encode(&frame, &mut out)?;
+                metrics.sent(out.len());
+                send(&mut writer, &out, &timeouts).await?;
+                out.clear();
+                last_active = Instant::now();
+                context.state.finish(trace, &peer_addr);
+                debug!("Response sent to {}: {:?}", peer_addr, frame);
+            }
+            // On shutdown, stop reading unless a request is partly received,
+            // and close the connection once the requests read are answered.
+            _ = context.shutdown.wait(), if reading && framing.is_idle(&buf) => {
+                debug!("Closing connection to {} for shutdown", peer_addr);
+                reading = false;
+            }
+            _ = sleep_until(deadline), if reading => {
+                if !idle {
+                    return Err(KvsError::Timeout("receiving a request".to_owned()));
+                }
+                debug!("Closing idle connection to {}", peer_addr);
+                reading = false;
+            }
+        }
+    }
+
+    if framing.is_idle(&buf) {
+        Ok(())
+    } else {
+        Err(unexpected_eof())
+    }
+}
+
+/// Runs a request of a kvs connection and returns its response.
+type Run = Pin<Box<dyn Future<Output = Result<(ResponseFrame, RequestTrace)>> + Send>>;
+
+/// Waits for the request running, or forever without one.
+async fn run_optional(running: &mut Option<Run>) -> Result<(ResponseFrame, RequestTrace)> {
+    match running {
+        Some(run) => run.await,
+        None => future::pending().await,
+    }
+}
+
+/// Takes the next request off the front of `buf`, like
+/// `ServerFraming::decode`, on the blocking thread pool if it may check
+/// credentials.
+async fn decode(
+    framing: &mut ServerFraming,
+    buf: &mut Vec<u8>,
+    out: &mut Vec<u8>,
+) -> Result<Option<RequestFrame>> {
+    if !framing.authenticates() {
+        return framing.decode(buf, out);
+    }
+    let (mut moved, mut moved_buf, mut moved_out) =
+        (framing.clone(), mem::take(buf), mem::take(out));
+    let (moved, moved_buf, moved_out, decoded) = task::spawn_blocking(move || {
+        let decoded = moved.decode(&mut moved_buf, &mut moved_out);
+        (moved, moved_buf, moved_out, decoded)
+    })
+    .await
+    .map_err(|e| KvsError::StringError(format!("Handshake task failed: {}", e)))?;
+    (*framing, *buf, *out) = (moved, moved_buf, moved_out);
+    decoded
+}
+
+/// Handles a single client connection speaking RESP.
+///
+/// RESP clients expect replies in the order of their commands, so the
+/// commands of a connection run one batch at a time: all those read so far
+/// are run together and answered before more are read.
+async fn serve_resp<E: KvsEngine + Send + 'static>(
+    context: Context<E>,
+    stream: Box<dyn AsyncStream>,
+    peer_addr: String,
+) -> Result<()> {
+    let metrics = &context.state.metrics;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = Vec::new();
+    let mut out = Vec::new();
+    let mut user = None;
+    // The commands answered in `out`, finished once it is written.
+    let mut answered = Vec::new();
+    let mut budgets = Budgets::default();
+    let timeouts = context.state.timeouts;
+    // When bytes last arrived, and when they did or replies were last sent.
+    let mut received = Instant::now();
+    let mut last_active = received;
+
+    loop {
+        let mut commands = Vec::new();
+        let decoded = loop {
+            match resp::decode_command(&mut buf, context.max_frame_len) {
+                // Refused commands and AUTH are answered without the engine.
+                Ok(Some(args)) => {
+                    let kind = resp::command_kind(&args);
+                    let sizes = resp::command_sizes(&args);
+                    let mut trace = RequestTrace::new("resp", kind, sizes, received);
+                    trace.audit(resp::audited(&args), user.as_deref());
+                    let access = resp::command_access(&args);
+                    let refused = authorize_resp(&context.acl, &mut user, &args).await?;
+                    let refused = refused.or_else(|| {
+                        let state = &context.state;
+                        let throttled = state.throttle(&mut budgets, user.as_deref(), access);
+                        throttled.err().map(|e| resp::throttled_reply(&e))
+                    });
+                    let command = match refused {
+                        Some(reply) => Err(reply),
+                        None => Ok(args),
+                    };
+                    commands.push((trace, command));
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        if !commands.is_empty() {
+            debug!("Receive {} commands from {}", commands.len(), peer_addr);
+            let expiries = Arc::clone(&context.expiries);
+            let state = Arc::clone(&context.state);
+            let peer = peer_addr.clone();
+            let replies = with_engine(&context.engine, move |engine| {
+                commands
+                    .into_iter()
+                    .map(|(mut trace, command)| {
+                        let reply = match command {
+                            Ok(_) if timeouts.expired(received) => {
+                                resp::error_reply(&request_timed_out())
+                            }
+                            Ok(args) => trace.engine(|| resp::execute(engine, &expiries, args)),
+                            Err(reply) => reply,
+                        };
+                        trace.answered(
+                            resp::outcome(&reply),
+                            resp::reply_value_len(&reply),
+                            resp::reply_error(&reply),
+                        );
+                        state.audit(&mut trace, &peer);
+                        (reply, trace)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await?;
+            for (reply, trace) in replies {
+                reply.encode(&mut out);
+                answered.push(trace);
+            }
+        }
+        // Invalid input is answered like Redis does before the connection
+        // is closed.
+        if let Err(e) = &decoded {
+            resp::error_reply(e).encode(&mut out);
+        }
+        if !out.is_empty() {
+            metrics.sent(out.len());
+            send(&mut writer, &out, &timeouts).await?;
+            out.clear();
+            last_active = Instant::now();
+        }
+        for trace in answered.drain(..) {
+            context.state.finish(trace, &peer_addr);
+        }
+        decoded?;
+
+        let idle = buf.is_empty();
+        let deadline = timeouts.deadline(idle, last_active);
+        tokio::select! {
+            read = reader.read_buf(&mut buf) => match read_len(read)? {
+                0 => return if buf.is_empty() { Ok(()) } else { Err(unexpected_eof()) },
+                len => {
+                    metrics.received(len);
+                    received = Instant::now();
+                    last_active = received;
+                }
+            },
+            _ = context.shutdown.wait(), if buf.is_empty() => {
+                debug!("Closing connection to {} for shutdown", peer_addr);
+                return Ok(());
+            }
+            _ = sleep_until(deadline) => return close_inactive(idle, &peer_addr),
+        }
+    }
+}
+
+/// Checks a RESP command like `resp::authorize`, on the blocking thread pool
+/// if it is `AUTH`, which checks credentials.
+async fn authorize_resp(
+    acl: &Option<Acl>,
+    user: &mut Option<String>,
+    args: &[Vec<u8>],
+) -> Result<Option<RespValue>> {
+    if !resp::is_auth(args) {
+        return Ok(resp::authorize(acl.as_ref(), user, args));
+    }
+    let (acl, mut moved_user, args) = (acl.clone(), user.take(), args.to_vec());
+    let (moved_user, reply) = task::spawn_blocking(move || {
+        let reply = resp::authorize(acl.as_ref(), &mut moved_user, &args);
+        (moved_user, reply)
+    })
+    .await
+    .map_err(|e| KvsError::StringError(format!("AUTH task failed: {}", e)))?;
+    *user = moved_user;
+    Ok(reply)
+}
+
+/// Binds a listener to `addr`, if there is one.
+async fn bind_optional(addr: Option<SocketAddr>) -> Result<Option<AsyncListener>> {
+    Ok(match addr {
+        Some(addr) => Some(AsyncListener::bind(&addr.into()).await?),
+        None => None,
+    })
+}
+
+/// Accepts a connection on `listener`, or waits forever without one.
+async fn accept_optional(
+    listener: &Option<AsyncListener>,
+) -> io::Result<(Box<dyn AsyncStream>, String)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// Handles a single client connection to the HTTP gateway, or to the metrics
+/// endpoint if `metrics` is set.
+///
+/// Requests are answered one at a time, in order, until one of them closes
+/// the connection.
+async fn serve_http<E: KvsEngine + Send + 'static>(
+    context: Context<E>,
+    metrics: bool,
+    stream: Box<dyn AsyncStream>,
+    peer_addr: String,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = Vec::new();
+    let mut out = Vec::new();
+    let mut budgets = Budgets::default();
+    let timeouts = context.state.timeouts;
+    // When bytes last arrived, and when they did or a response was last sent.
+    let mut received = Instant::now();
+    let mut last_active = received;
+
+    loop {
+        match http::decode_request(&mut buf, context.max_frame_len) {
+            Ok(Some(request)) => {
+                debug!("Receive HTTP request from {}: {:?}", peer_addr, request);
+                let acl = context.acl.clone();
+                let state = Arc::clone(&context.state);
+                let peer = peer_addr.clone();
+                // The budgets go along with the request, and come back with
+                // its response.
+                let mut request_budgets = mem::take(&mut budgets);
+                let (response, trace, request_budgets) =
+                    with_engine(&context.engine, move |engine| {
+                        if metrics {
+                            let response =
+                                http::handle_metrics(request, || state.encode_metrics(engine));
+                            return (response, None, request_budgets);
+                        }
+                        let kind = http::request_kind(&request);
+                        let _span = debug_span!("request", operation = kind).entered();
+                        let sizes = http::request_sizes(&request);
+                        let mut trace = RequestTrace::new("http", kind, sizes, received);
+                        let audited = http::audited(&request);
+                        let access = http::request_access(&request);
+                        let (response, user) = if timeouts.expired(received) {
+                            (http::error_response(&request_timed_out()), None)
+                        } else {
+                            trace.engine(|| {
+                                http::handle(engine, acl.as_ref(), request, |user| {
+                                    state.throttle(&mut request_budgets, user, access)
+                                })
+                            })
+                        };
+                        trace.audit(audited, user.as_deref());
+                        trace.answered(response.outcome(), None, response.error_message());
+                        state.audit(&mut trace, &peer);
+                        (response, Some(trace), request_budgets)
+                    })
+                    .await?;
+                budgets = request_budgets;
+                debug!("HTTP response to {}: {:?}", peer_addr, response);
+                response.encode(&mut out);
+                context.state.metrics.sent(out.len());
+                send(&mut writer, &out, &timeouts).await?;
+                out.clear();
+                last_active = Instant::now();
+                if let Some(trace) = trace {
+                    context.state.finish(trace, &peer_addr);
+                }
+                if response.closes() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                http::error_response(&e).encode(&mut out);
+                context.state.metrics.sent(out.len());
+                send(&mut writer, &out, &timeouts).await?;
+                return Err(e);
+            }
+        }
+
+        let idle = buf.is_empty();
+        let deadline = timeouts.deadline(idle, last_active);
+        tokio::select! {
+            read = reader.read_buf(&mut buf) => match read_len(read)? {
+                0 => return if buf.is_empty() { Ok(()) } else { Err(unexpected_eof()) },
+                len => {
+                    context.state.metrics.received(len);
+                    received = Instant::now();
+                    last_active = received;
+                }
+            },
+            _ = context.shutdown.wait(), if buf.is_empty() => {
+                debug!("Closing connection to {} for shutdown", peer_addr);
+                return Ok(());
+            }
+            _ = sleep_until(deadline) => return close_inactive(idle, &peer_addr),
+        }
+    }
+}
+
+/// Writes `out` to `writer`, within the write timeout of the server.
+async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    out: &[u8],
+    timeouts: &Timeouts,
+) -> Result<()> {
+    let written = writer.write_all(out);
+    match timeouts.write {
+        Some(timeout) => time::timeout(timeout, written)
+            .await
+            .map_err(|_| KvsError::Timeout("sending a response".to_owned()))??,
+        None => written.await?,
+    }
+    Ok(())
+}
+
+/// Waits until `deadline`, or forever without one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline.into()).await,
+        None => future::pending().await,
+    }
+}
+
+/// Ends a connection that timed out, `idle` or in the middle of a request.
+fn close_inactive(idle: bool, peer_addr: &str) -> Result<()> {
+    if !idle {
+        return Err(KvsError::Timeout("receiving a request".to_owned()));
+    }
+    debug!("Closing idle connection to {}", peer_addr);
+    Ok(())
+}
+
+/// Runs `f` on the shared engine on the blocking thread pool.
+async fn with_engine<E, T, F>(engine: &Arc<Mutex<E>>, f: F) -> Result<T>
+where
+    E: KvsEngine + Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut E) -> T + Send + 'static,
+{
+    let engine = Arc::clone(engine);
+    // The call belongs to the span of the request it is made for.
+    let span = Span::current();
+    task::spawn_blocking(move || {
+        let _span = span.entered();
+        let mut engine = engine.lock().map_err(|_| poisoned())?;
+        Ok(f(&mut engine))
+    })
+    .await
+    .map_err(|e| KvsError::StringError(format!("Engine task failed: {}", e)))?
+}
+
+fn poisoned() -> KvsError {
+    KvsError::StringError("Engine lock poisoned".to_owned())
+}
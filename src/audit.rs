@@ -0,0 +1,198 @@
+//! The audit log: every `set` and `remove` a server was asked for, who asked
+//! and how it ended.
+//!
+//! Entries are appended to a file of their own as lines of JSON, by a thread
+//! of the log, so that requests never wait for the file. Once the file grows
+//! past `AuditConfig::max_file_len`, it is renamed to `PATH.1`, the previous
+//! `PATH.1` to `PATH.2` and so on, and a new file is started.
+
+use crate::Result;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+const DEFAULT_MAX_FILE_LEN: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 10;
+
+/// Settings of the audit log of a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// The file entries are appended to.
+    pub path: PathBuf,
+    /// The length past which the file is rotated, in bytes. The default is
+    /// 64 MiB.
+    pub max_file_len: u64,
+    /// How many rotated files are kept. Older ones are removed. The default
+    /// is 10.
+    pub max_files: usize,
+}
+
+impl AuditConfig {
+    /// The default settings for a log at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditConfig {
+            path: path.into(),
+            max_file_len: DEFAULT_MAX_FILE_LEN,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+/// A `set` or `remove` a server was asked for.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the request was received.
+    pub time: SystemTime,
+    /// The address of the client.
+    pub peer: String,
+    /// The user the client authenticated as, if any.
+    pub user: Option<String>,
+    /// The protocol of the request: `kvs`, `resp` or `http`.
+    pub protocol: String,
+    /// `set` or `remove`.
+    pub operation: String,
+    /// The key set or removed.
+    pub key: String,
+    /// `ok`, `error`, or `denied` if the ACL turned the request down.
+    pub result: String,
+    /// Why the request failed, unless it succeeded.
+    pub error: Option<String>,
+}
+
+/// The audit log of a running server.
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    sender: Option<Sender<AuditEntry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    /// Starts the thread writing the log.
+    pub(crate) fn new(config: AuditConfig) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("kvs-audit".to_owned())
+            .spawn(move || write_entries(&config, receiver));
+        let writer = match writer {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                error!("Cannot start writing the audit log: {}", e);
+                None
+            }
+        };
+        AuditLog {
+            sender: Some(sender),
+            writer,
+        }
+    }
+
+    /// Queues `entry` to be written.
+    pub(crate) fn record(&self, entry: AuditEntry) {
+        if let Some(sender) = &self.sender
+            && sender.send(entry).is_err()
+        {
+            error!("The audit log is no longer written");
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    /// Waits for the queued entries to be written.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Writes the entries received until the log is dropped.
+fn write_entries(config: &AuditConfig, receiver: Receiver<AuditEntry>) {
+    let mut file = None;
+    while let Ok(entry) = receiver.recv() {
+        // Entries queued together are written together.
+        let written = std::iter::once(entry)
+            .chain(receiver.try_iter())
+            .try_for_each(|entry| append(config, &mut file, &entry));
+        let flushed = written.and_then(|()| match &mut file {
+            Some(file) => Ok(file.writer.flush()?),
+            None => Ok(()),
+        });
+        if let Err(e) = flushed {
+            error!("Cannot write to the audit log: {}", e);
+            // Reopen the file for the next entries.
+            file = None;
+        }
+    }
+}
+
+/// The open file of the log and its length.
+struct AuditFile {
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+/// Appends `entry` to the file of the log, opening or rotating it first if
+/// need be.
+fn append(config: &AuditConfig, file: &mut Option<AuditFile>, entry: &AuditEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    if let Some(open) = file
+        && open.len > 0
+        && open.len + line.len() as u64 > config.max_file_len
+    {
+        open.writer.flush()?;
+        *file = None;
+        rotate(config)?;
+    }
+    let open = match file {
+        Some(open) => open,
+        None => {
+            let opened = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)?;
+            let len = opened.metadata()?.len();
+            file.insert(AuditFile {
+                writer: BufWriter::new(opened),
+                len,
+            })
+        }
+    };
+    open.writer.write_all(&line)?;
+    open.len += line.len() as u64;
+    Ok(())
+}
+
+/// Shifts the rotated files by one, dropping the oldest, and makes the
+/// current file the newest of them.
+fn rotate(config: &AuditConfig) -> Result<()> {
+    if config.max_files == 0 {
+        fs::remove_file(&config.path)?;
+        return Ok(());
+    }
+    let oldest = rotated_path(&config.path, config.max_files);
+    if oldest.exists() {
+        fs::remove_file(oldest)?;
+    }
+    for n in (1..config.max_files).rev() {
+        let from = rotated_path(&config.path, n);
+        if from.exists() {
+            fs::rename(from, rotated_path(&config.path, n + 1))?;
+        }
+    }
+    fs::rename(&config.path, rotated_path(&config.path, 1))?;
+    Ok(())
+}
+
+/// The path of the `n`th newest rotated file of the log at `path`.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
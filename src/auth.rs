@@ -0,0 +1,339 @@
+//! Authentication of clients and access control on key prefixes.
+//!
+//! A server given an `Acl` only serves the users listed in it. `KvsClient`
+//! and `AsyncKvsClient` authenticate in the opening handshake, RESP clients
+//! with `AUTH` and HTTP clients with an `Authorization` header, either with a
+//! user name and password or with a token. Every request is then checked
+//! against the grants of the user: the permissions it holds on the keys
+//! starting with each prefix.
+//!
+//! The ACL is a JSON file. Secrets are stored hashed, as printed by
+//! `kvs-admin hash-secret`:
+//!
+//! ```json
+//! {
+//!     "users": {
+//!         "alice": {
+//!             "password": "pbkdf2-sha256$100000$...$...",
+//!             "grants": { "app/": ["read", "write"] }
+//!         },
+//!         "ops": {
+//!             "tokens": ["sha256$..."],
+//!             "grants": { "": ["read", "admin"] }
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::{KvsError, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, pbkdf2};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::sync::Arc;
+
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+const TOKEN_SCHEME: &str = "sha256";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// Something a user may be allowed to do with keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Reading the values of keys and listing keys.
+    Read,
+    /// Setting and removing keys.
+    Write,
+    /// Administering the server. It is checked against the empty key, so
+    /// only a grant on the empty prefix gives it.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Admin => "admin",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// How a client proves who it is.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A user name and password.
+    Password {
+        /// The name of the user.
+        user: String,
+        /// The password of the user.
+        password: String,
+    },
+    /// A token standing for a user.
+    Token(String),
+}
+
+// Secrets stay out of logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Credentials::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    users: BTreeMap<String, UserEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    tokens: Vec<String>,
+    #[serde(default)]
+    grants: BTreeMap<String, Vec<Permission>>,
+}
+
+struct PasswordHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn parse(s: &str) -> Option<PasswordHash> {
+        let mut parts = s.split('$');
+        if parts.next()? != PASSWORD_SCHEME {
+            return None;
+        }
+        let hash = PasswordHash {
+            iterations: parts.next()?.parse().ok()?,
+            salt: from_hex(parts.next()?)?,
+            hash: from_hex(parts.next()?)?,
+        };
+        match parts.next() {
+            None => Some(hash),
+            Some(_) => None,
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            password.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+struct User {
+    password: Option<PasswordHash>,
+    grants: BTreeMap<String, Vec<Permission>>,
+}
+
+/// The users of a server, how they authenticate and what they may do.
+#[derive(Clone)]
+pub struct Acl(Arc<AclInner>);
+
+struct AclInner {
+    users: HashMap<String, User>,
+    /// User names by the SHA-256 digests of their tokens.
+    tokens: HashMap<Vec<u8>, String>,
+    /// Checked instead of the password of a user that does not exist or has
+    /// none, so that the time taken does not tell whether the user exists.
+    dummy_password: PasswordHash,
+}
+
+impl fmt::Debug for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut users: Vec<_> = self.0.users.keys().collect();
+        users.sort();
+        f.debug_struct("Acl").field("users", &users).finish()
+    }
+}
+
+impl Acl {
+    /// Parses the JSON of an ACL file.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidAcl` if the JSON does not describe an
+    /// ACL or holds a secret that is not a hash.
+    pub fn from_json(json: &str) -> Result<Acl> {
+        let file: AclFile =
+            serde_json::from_str(json).map_err(|e| KvsError::InvalidAcl(e.to_string()))?;
+        let mut users = HashMap::new();
+        let mut tokens = HashMap::new();
+        for (name, entry) in file.users {
+            let password = match &entry.password {
+                Some(password) => Some(PasswordHash::parse(password).ok_or_else(|| {
+                    KvsError::InvalidAcl(format!("the password of {} is not a hash", name))
+                })?),
+                None => None,
+            };
+            for token in &entry.tokens {
+                let digest = token
+                    .strip_prefix(TOKEN_SCHEME)
+                    .and_then(|rest| rest.strip_prefix('$'))
+                    .and_then(from_hex)
+                    .ok_or_else(|| {
+                        KvsError::InvalidAcl(format!("a token of {} is not a hash", name))
+                    })?;
+                if tokens.insert(digest, name.clone()).is_some() {
+                    return Err(KvsError::InvalidAcl(format!(
+                        "a token of {} belongs to another user too",
+                        name
+                    )));
+                }
+            }
+            let user = User {
+                password,
+                grants: entry.grants,
+            };
+            users.insert(name, user);
+        }
+        let dummy_password = PasswordHash {
+            iterations: NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt: vec![0; SALT_LEN],
+            hash: vec![0; digest::SHA256_OUTPUT_LEN],
+        };
+        Ok(Acl(Arc::new(AclInner {
+            users,
+            tokens,
+            dummy_password,
+        })))
+    }
+
+    /// Reads the ACL file at `path`.
+    pub fn from_file(path: &Path) -> Result<Acl> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| KvsError::InvalidAcl(format!("{}: {}", path.display(), e)))?;
+        Self::from_json(&json)
+    }
+
+    /// Returns the name of the user `credentials` belong to.
+    ///
+    /// Checking a password takes a deliberately slow hash, as long for an
+    /// unknown user as for a known one.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AuthenticationFailed` if they belong to nobody.
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<String> {
+        let user = match credentials {
+            Credentials::Password { user, password } => {
+                let hash = self
+                    .0
+                    .users
+                    .get(user)
+                    .and_then(|entry| entry.password.as_ref());
+                let verified = hash.unwrap_or(&self.0.dummy_password).verify(password);
+                (hash.is_some() && verified).then(|| user.clone())
+            }
+            Credentials::Token(token) => self.0.tokens.get(&token_digest(token)).cloned(),
+        };
+        user.ok_or_else(|| KvsError::AuthenticationFailed("invalid credentials".to_owned()))
+    }
+
+    /// Checks that `user` has `permission` on `key`, or on every key starting
+    /// with `key` when listing keys by prefix. `None` is a client that has not
+    /// authenticated.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PermissionDenied` if it does not.
+    pub fn check(&self, user: Option<&str>, permission: Permission, key: &str) -> Result<()> {
+        let Some(user) = user else {
+            return Err(KvsError::PermissionDenied("not authenticated".to_owned()));
+        };
+        let allowed = self.0.users.get(user).is_some_and(|entry| {
+            entry.grants.iter().any(|(prefix, permissions)| {
+                key.starts_with(prefix.as_str()) && permissions.contains(&permission)
+            })
+        });
+        if allowed {
+            Ok(())
+        } else if permission == Permission::Admin {
+            Err(KvsError::PermissionDenied(format!(
+                "{} may not administer the server",
+                user
+            )))
+        } else {
+            Err(KvsError::PermissionDenied(format!(
+                "{} may not {} {:?}",
+                user, permission, key
+            )))
+        }
+    }
+}
+
+/// Hashes `password` with a random salt, for the `password` of a user in an
+/// ACL file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| KvsError::StringError("no randomness for a salt".to_owned()))?;
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        iterations,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+/// Hashes `token`, for the `tokens` of a user in an ACL file.
+///
+/// Tokens are expected to be long and random, so unlike passwords they are
+/// hashed without a salt, which lets them be looked up by their hash.
+pub fn hash_token(token: &str) -> String {
+    format!("{}${}", TOKEN_SCHEME, to_hex(&token_digest(token)))
+}
+
+fn token_digest(token: &str) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
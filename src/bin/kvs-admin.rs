@@ -0,0 +1,136 @@
+use clap::{Parser, Subcommand};
+use kvs::{Command as LogCommand, KvStore, LogRecord, Result, hash_password, hash_token};
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process;
+
+const DEFAULT_DIRECTORY: &str = ".";
+
+/// Offline maintenance for `kvs` engine data directories and ACL files
+#[derive(Parser, Debug)]
+#[command(name = "kvs-admin")]
+#[command(author = env!("CARGO_PKG_AUTHORS"))]
+#[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check every log file and whether the index can be rebuilt
+    Verify {
+        /// The data directory
+        #[arg(default_value = DEFAULT_DIRECTORY)]
+        path: PathBuf,
+    },
+    /// Salvage every readable record into a fresh log file
+    Repair {
+        /// The data directory
+        #[arg(default_value = DEFAULT_DIRECTORY)]
+        path: PathBuf,
+    },
+    /// Print every record in a human-readable form
+    Dump {
+        /// The data directory
+        #[arg(default_value = DEFAULT_DIRECTORY)]
+        path: PathBuf,
+    },
+    /// Hash a password read from standard input, for an ACL file
+    HashSecret {
+        /// Hashes a token instead of a password
+        #[arg(long)]
+        token: bool,
+    },
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs the command and returns whether the data directory is healthy.
+fn run(cli: Cli) -> Result<bool> {
+    match cli.command {
+        Command::Verify { path } => {
+            let report = KvStore::verify(&path)?;
+            for record in &report.corrupt {
+                print_record(record);
+            }
+            if let Some(e) = &report.index_error {
+                println!("index cannot be rebuilt: {}", e);
+            }
+            println!(
+                "{} generations, {} records, {} corrupt, {} live keys",
+                report.generations.len(),
+                report.records,
+                report.corrupt.len(),
+                report.live_keys
+            );
+            Ok(report.is_ok())
+        }
+        Command::Repair { path } => {
+            let report = KvStore::repair(&path)?;
+            println!(
+                "salvaged {} records into {}.log, dropped {} corrupt regions ({} bytes)",
+                report.salvaged, report.generation, report.dropped, report.dropped_bytes
+            );
+            Ok(true)
+        }
+        Command::Dump { path } => {
+            let mut healthy = true;
+            for file_id in KvStore::generations(&path)? {
+                for record in KvStore::read_generation(&path, file_id)? {
+                    healthy &= matches!(record, LogRecord::Valid { .. });
+                    print_record(&record);
+                }
+            }
+            Ok(healthy)
+        }
+        Command::HashSecret { token } => {
+            let mut secret = String::new();
+            io::stdin().lock().read_line(&mut secret)?;
+            let secret = secret.trim_end_matches(['\r', '\n']);
+            if token {
+                println!("{}", hash_token(secret));
+            } else {
+                println!("{}", hash_password(secret)?);
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn print_record(record: &LogRecord) {
+    match record {
+        LogRecord::Valid {
+            file_id,
+            pos,
+            command,
+            ..
+        } => match command {
+            LogCommand::Set { key, value } => {
+                println!("{}.log@{}\tset {:?} {:?}", file_id, pos, key, value)
+            }
+            LogCommand::Remove { key } => println!("{}.log@{}\trm {:?}", file_id, pos, key),
+        },
+        LogRecord::Corrupt {
+            file_id,
+            pos,
+            len,
+            error,
+        } => println!(
+            "{}.log@{}\tcorrupt ({} bytes): {}",
+            file_id, pos, len, error
+        ),
+    }
+}
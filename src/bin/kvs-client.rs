@@ -1,20 +1,100 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvsClient, Result};
-use std::net::SocketAddr;
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{
+    Address, ClientConfig, ClientTlsConfig, Codec, Credentials, KvsClient, KvsError,
+    PROTOCOL_VERSION, Result,
+};
+use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const ADDRESS_FORMAT: &str = "IP:PORT";
+const ADDRESS_FORMAT: &str = "IP:PORT|unix:PATH";
+const DEFAULT_CONNECT_TIMEOUT: &str = "5";
+const DEFAULT_TIMEOUT: &str = "30";
 
 /// A simple key-value store
 #[derive(Parser, Debug)]
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(author = env!("CARGO_PKG_AUTHORS"))]
-#[command(version = env!("CARGO_PKG_VERSION"))]
+#[command(disable_version_flag = true, arg_required_else_help = true)]
 #[command(about = env!("CARGO_PKG_DESCRIPTION"), long_about = None)]
 struct Cli {
     #[command(subcommand)]
-    command: Command,
+    command: Option<Command>,
+
+    /// Print the versions of the client and of the server at --addr
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// Server address, over TCP or a Unix domain socket
+    #[arg(long, value_name = ADDRESS_FORMAT, default_value = DEFAULT_LISTENING_ADDRESS, global = true)]
+    addr: Address,
+
+    /// Sets the wire format; json is readable when debugging
+    #[arg(long, value_enum, global = true, default_value = "binary")]
+    codec: WireCodec,
+
+    /// Speaks TLS, trusting the certificate authorities in this PEM file
+    #[arg(long, value_name = "PATH", global = true)]
+    tls_ca: Option<PathBuf>,
+
+    /// Presents the certificate chain in this PEM file to the server
+    #[arg(long, value_name = "PATH", global = true, requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// Sets the PEM file of the private key of --tls-cert
+    #[arg(long, value_name = "PATH", global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Sets the name the server certificate must be valid for; defaults to
+    /// the host of --addr
+    #[arg(long, value_name = "NAME", global = true, requires = "tls_ca")]
+    tls_server_name: Option<String>,
+
+    /// Authenticates as this user, with --password
+    #[arg(long, value_name = "NAME", global = true, requires = "password")]
+    user: Option<String>,
+
+    /// Sets the password of --user
+    #[arg(long, env = "KVS_PASSWORD", hide_env_values = true, global = true)]
+    password: Option<String>,
+
+    /// Authenticates with this token
+    #[arg(
+        long,
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        global = true,
+        conflicts_with = "user"
+    )]
+    token: Option<String>,
+
+    /// Gives up connecting to the server after this long
+    #[arg(long, value_name = "SECONDS", global = true, default_value = DEFAULT_CONNECT_TIMEOUT)]
+    connect_timeout: u64,
+
+    /// Gives up on a request the server has not answered after this long
+    #[arg(long, value_name = "SECONDS", global = true, default_value = DEFAULT_TIMEOUT)]
+    timeout: u64,
+
+    /// Retries throttled requests as the server asks, for up to this long
+    #[arg(long, value_name = "SECONDS", global = true)]
+    max_throttle_wait: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum WireCodec {
+    Binary,
+    Json,
+}
+
+impl From<WireCodec> for Codec {
+    fn from(codec: WireCodec) -> Codec {
+        match codec {
+            WireCodec::Binary => Codec::Binary,
+            WireCodec::Json => Codec::Json,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -23,10 +103,6 @@ enum Command {
     Get {
         /// A string key
         key: String,
-
-        /// Server address
-        #[arg(long, value_name = ADDRESS_FORMAT, default_value = DEFAULT_LISTENING_ADDRESS)]
-        addr: SocketAddr,
     },
     /// Set the value of a string key to a string
     Set {
@@ -35,19 +111,25 @@ enum Command {
 
         /// The string value of the key
         value: String,
-
-        /// Server address
-        #[arg(long, value_name = ADDRESS_FORMAT, default_value = DEFAULT_LISTENING_ADDRESS)]
-        addr: SocketAddr,
     },
     /// Remove a given string key
     Rm {
         /// A string key
         key: String,
-
-        /// Server address
-        #[arg(long, value_name = ADDRESS_FORMAT, default_value = DEFAULT_LISTENING_ADDRESS)]
-        addr: SocketAddr,
+    },
+    /// Check that the server answers
+    Ping,
+    /// Print the version, engine, uptime and connections of the server
+    Info,
+    /// Print statistics about the data of the server's engine
+    Stats,
+    /// Compact the server's engine now
+    Compact,
+    /// Print the latest requests the server was slow to answer
+    Slowlog {
+        /// The number of requests to print, the newest first
+        #[arg(long, default_value_t = 10)]
+        count: usize,
     },
 }
 
@@ -61,22 +143,120 @@ fn main() {
 }
 
 fn run(cli: Cli) -> Result<()> {
-    match cli.command {
-        Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            match client.get(key)? {
-                Some(value) => println!("{}", value),
-                None => println!("Key not found"),
+    let config = ClientConfig {
+        codec: cli.codec.into(),
+        tls: tls_config(&cli)?,
+        credentials: credentials(&cli),
+        connect_timeout: Some(Duration::from_secs(cli.connect_timeout)),
+        timeout: Some(Duration::from_secs(cli.timeout)),
+        max_throttle_wait: cli.max_throttle_wait.map(Duration::from_secs),
+        ..ClientConfig::default()
+    };
+    let addr = cli.addr;
+    let Some(command) = cli.command else {
+        print_versions(addr, &config);
+        return Ok(());
+    };
+    let mut client = KvsClient::connect_with_config(addr, &config)?;
+    match command {
+        Command::Get { key } => match client.get(key)? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        Command::Set { key, value } => client.set(key, value)?,
+        Command::Rm { key } => match client.remove(key) {
+            Err(KvsError::KeyNotFound) => {
+                eprintln!("Key not found");
+                process::exit(1);
             }
+            removed => removed?,
+        },
+        Command::Ping => {
+            client.ping()?;
+            println!("PONG");
+        }
+        Command::Info => {
+            let status = client.info()?;
+            println!("version: {}", status.server_version);
+            println!("engine: {}", status.engine);
+            println!("uptime: {}s", status.uptime.as_secs());
+            println!("connections: {}", status.connections);
+            println!("total connections: {}", status.total_connections);
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+        Command::Stats => {
+            let stats = client.stats()?;
+            println!("keys: {}", stats.keys);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("stale bytes: {}", stats.stale_bytes);
+            println!("generations: {}", stats.generations);
+            println!("disk bytes: {}", stats.disk_bytes);
+            println!("compactions: {}", stats.compactions);
         }
-        Command::Rm { key, addr } => {
-            let mut client = KvsClient::connect(addr)?;
-            client.remove(key)?;
+        Command::Compact => client.compact()?,
+        Command::Slowlog { count } => {
+            for entry in client.slow_log(count)? {
+                println!(
+                    "{} {} {} {} key={}B value={}B engine={}us network={}us",
+                    entry.id,
+                    entry.peer,
+                    entry.protocol,
+                    entry.operation,
+                    entry.key_len,
+                    entry.value_len,
+                    entry.engine_time.as_micros(),
+                    entry.network_time.as_micros()
+                );
+            }
         }
     }
     Ok(())
 }
+
+fn tls_config(cli: &Cli) -> Result<Option<ClientTlsConfig>> {
+    let Some(ca) = &cli.tls_ca else {
+        return Ok(None);
+    };
+    let identity = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+        _ => None,
+    };
+    let tls = ClientTlsConfig::from_files(ca, identity)?;
+    Ok(Some(match &cli.tls_server_name {
+        Some(name) => tls.with_server_name(name),
+        None => tls,
+    }))
+}
+
+fn credentials(cli: &Cli) -> Option<Credentials> {
+    match (&cli.user, &cli.password, &cli.token) {
+        (Some(user), Some(password), _) => Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        (None, _, Some(token)) => Some(Credentials::Token(token.clone())),
+        _ => None,
+    }
+}
+
+/// Prints the client version, then the version of the server at `addr` if it
+/// can be reached.
+fn print_versions(addr: Address, config: &ClientConfig) {
+    println!(
+        "kvs-client {} (protocol {})",
+        env!("CARGO_PKG_VERSION"),
+        PROTOCOL_VERSION
+    );
+    match KvsClient::connect_with_config(&addr, config) {
+        Ok(client) => {
+            let info = client.server_info();
+            println!(
+                "kvs-server {} (protocol {}, engine {}, features: {})",
+                info.server_version,
+                info.protocol_version,
+                info.engine,
+                info.features.join(", ")
+            );
+        }
+        Err(e) => println!("kvs-server at {}: {}", addr, e),
+    }
+}
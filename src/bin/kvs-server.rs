@@ -1,15 +1,24 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use kvs::*;
 use log::{LevelFilter, error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::env::current_dir;
 use std::fmt;
-use std::fs;
+use std::fs::{self, File};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const ENGINE_FILE: &str = "engine";
+const MIGRATION_DIR: &str = "migration";
+const CLEAN_SHUTDOWN_FILE: &str = "clean-shutdown";
+const DEFAULT_SHUTDOWN_TIMEOUT: &str = "5";
 
 #[derive(Parser, Debug)]
 #[command(name = "kvs-server")]
@@ -22,9 +31,155 @@ struct Opt {
     #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
     addr: SocketAddr,
 
+    /// Listens on a Unix domain socket at this path instead of --addr
+    #[arg(long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+
     /// Sets the storage engine
     #[arg(long, value_enum)]
     engine: Option<Engine>,
+
+    /// Serves connections concurrently on the tokio runtime
+    #[arg(long = "async")]
+    async_server: bool,
+
+    /// Sets how long a shutdown waits for requests in flight
+    #[arg(long, value_name = "SECONDS", default_value = DEFAULT_SHUTDOWN_TIMEOUT)]
+    shutdown_timeout: u64,
+
+    /// Closes connections past this many
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+
+    /// Closes connections without a request for this long; 0 never does
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Closes connections stalling this long in the middle of a request; 0
+    /// never does
+    #[arg(long, value_name = "SECONDS")]
+    read_timeout: Option<u64>,
+
+    /// Closes connections taking this long to take a response; 0 never does
+    #[arg(long, value_name = "SECONDS")]
+    write_timeout: Option<u64>,
+
+    /// Answers requests that waited this long to be processed with an error
+    #[arg(long, value_name = "MS")]
+    request_timeout: Option<u64>,
+
+    /// Sets the largest message a client may send
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FRAME_LEN)]
+    max_frame_len: usize,
+
+    /// Sets the protocol spoken to clients; resp serves redis-cli and Redis
+    /// client libraries
+    #[arg(long, value_enum, default_value = "kvs")]
+    protocol: WireProtocol,
+
+    /// Also serves an HTTP/JSON gateway to the same engine on this address
+    #[arg(long, value_name = "IP:PORT")]
+    http: Option<SocketAddr>,
+
+    /// Serves metrics in the Prometheus text format at /metrics on this
+    /// address
+    #[arg(long, value_name = "IP:PORT")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Logs requests taking at least this many milliseconds to answer
+    #[arg(long, value_name = "MS")]
+    slow_log_threshold: Option<u64>,
+
+    /// Sets how many slow requests are kept for kvs-client slowlog
+    #[arg(long, value_name = "N", requires = "slow_log_threshold")]
+    slow_log_len: Option<usize>,
+
+    /// Also appends slow requests to this file, one JSON object per line
+    #[arg(long, value_name = "PATH", requires = "slow_log_threshold")]
+    slow_log_file: Option<PathBuf>,
+
+    /// Appends every set and remove to this audit log file
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Rotates the audit log once it grows past this many bytes
+    #[arg(long, value_name = "BYTES", requires = "audit_log")]
+    audit_log_max_len: Option<u64>,
+
+    /// Sets how many rotated audit log files are kept
+    #[arg(long, value_name = "N", requires = "audit_log")]
+    audit_log_files: Option<usize>,
+
+    /// Speaks TLS with the certificate chain in this PEM file
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Sets the PEM file of the private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Requires clients to present a certificate signed by a certificate
+    /// authority in this PEM file
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Only serves the users in this ACL file, as far as it allows them
+    #[arg(long, value_name = "PATH")]
+    acl: Option<PathBuf>,
+
+    /// Limits each connection to this many reads per second
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    read_limit: Option<u32>,
+
+    /// Limits each connection to this many writes per second
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    write_limit: Option<u32>,
+
+    /// Limits each user of the ACL to this many reads per second
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "acl",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    user_read_limit: Option<u32>,
+
+    /// Limits each user of the ACL to this many writes per second
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "acl",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    user_write_limit: Option<u32>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum WireProtocol {
+    Kvs,
+    Resp,
+}
+
+impl From<WireProtocol> for Protocol {
+    fn from(protocol: WireProtocol) -> Protocol {
+        match protocol {
+            WireProtocol::Kvs => Protocol::Kvs,
+            WireProtocol::Resp => Protocol::Resp,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy the stored data into another storage engine and switch to it
+    Migrate {
+        /// The storage engine to migrate to
+        #[arg(long, value_enum)]
+        to: Engine,
+    },
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -32,6 +187,17 @@ struct Opt {
 enum Engine {
     kvs,
     sled,
+    memory,
+}
+
+impl Engine {
+    /// Returns whether the engine keeps its data in the working directory.
+    ///
+    /// Only persistent engines are recorded in, and checked against, the
+    /// engine marker file.
+    fn is_persistent(self) -> bool {
+        self != Engine::memory
+    }
 }
 
 impl fmt::Display for Engine {
@@ -39,6 +205,7 @@ impl fmt::Display for Engine {
         let s = match self {
             Engine::kvs => "kvs",
             Engine::sled => "sled",
+            Engine::memory => "memory",
         };
         write!(f, "{}", s)
     }
@@ -51,6 +218,7 @@ impl FromStr for Engine {
         match s {
             "kvs" => Ok(Engine::kvs),
             "sled" => Ok(Engine::sled),
+            "memory" => Ok(Engine::memory),
             _ => Err(format!("Unknown engine: {}", s)),
         }
     }
@@ -67,17 +235,22 @@ fn main() {
 }
 
 fn try_main(opt: Opt) -> Result<()> {
+    if let Some(Command::Migrate { to }) = opt.command {
+        return migrate(to);
+    }
+
     let current = current_engine()?;
     let selected = opt.engine.unwrap_or(DEFAULT_ENGINE);
 
-    if let Some(existing) = current {
-        if existing != selected {
-            error!(
-                "Engine mismatch: previously used '{}', but '{}' was requested",
-                existing, selected
-            );
-            process::exit(1);
-        }
+    if let Some(existing) = current
+        && existing != selected
+        && selected.is_persistent()
+    {
+        error!(
+            "Engine mismatch: previously used '{}', but '{}' was requested",
+            existing, selected
+        );
+        process::exit(1);
     }
 
     run(opt, selected)
@@ -86,29 +259,289 @@ fn try_main(opt: Opt) -> Result<()> {
 fn run(opt: Opt, engine: Engine) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    info!("Listening on {}", listen_address(&opt));
+    if let WireProtocol::Resp = opt.protocol {
+        info!("Speaking RESP");
+    }
+    if let Some(http) = opt.http {
+        info!("Serving HTTP on {}", http);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        info!("Serving metrics on {}", metrics_addr);
+    }
+    if opt.tls_cert.is_some() {
+        info!("Speaking TLS");
+    }
+    if opt.tls_client_ca.is_some() {
+        info!("Requiring client certificates");
+    }
+    if let Some(acl) = &opt.acl {
+        info!("Access restricted by {}", acl.display());
+    }
+    if let Some(threshold) = opt.slow_log_threshold {
+        info!("Logging requests slower than {} ms", threshold);
+    }
+    if let Some(audit_log) = &opt.audit_log {
+        info!("Auditing sets and removes to {}", audit_log.display());
+    }
 
-    fs::write(current_dir()?.join("engine"), engine.to_string())?;
+    let clean_shutdown = current_dir()?.join(CLEAN_SHUTDOWN_FILE);
+    if engine.is_persistent() {
+        let engine_file = current_dir()?.join(ENGINE_FILE);
+        if engine_file.exists() && !clean_shutdown.exists() {
+            warn!("The previous run did not shut down cleanly");
+        }
+        if clean_shutdown.exists() {
+            fs::remove_file(&clean_shutdown)?;
+        }
+        fs::write(engine_file, engine.to_string())?;
+    }
 
     match engine {
         Engine::kvs => {
             let store = KvStore::open(current_dir()?)?;
-            run_with_engine(store, opt.addr)
+            run_with_engine(store, &opt)?;
         }
         Engine::sled => {
             let db = sled::open(current_dir()?)?;
             let store = SledKvsEngine::new(db);
-            run_with_engine(store, opt.addr)
+            run_with_engine(store, &opt)?;
         }
+        Engine::memory => run_with_engine(MemoryKvsEngine::new(), &opt)?,
+    }
+
+    // The engine is synced and closed by now.
+    if engine.is_persistent() {
+        fs::write(&clean_shutdown, "")?;
+    }
+    info!("Shut down cleanly");
+    Ok(())
+}
+
+fn run_with_engine<E: KvsEngine + Send + 'static>(engine: E, opt: &Opt) -> Result<()> {
+    let default = ServerConfig::default();
+    let config = ServerConfig {
+        shutdown_timeout: Duration::from_secs(opt.shutdown_timeout),
+        max_frame_len: opt.max_frame_len,
+        protocol: opt.protocol.into(),
+        http_addr: opt.http,
+        tls: tls_config(opt)?,
+        acl: opt.acl.as_deref().map(Acl::from_file).transpose()?,
+        metrics_addr: opt.metrics_addr,
+        slow_log: slow_log_config(opt),
+        audit_log: audit_config(opt),
+        max_connections: opt.max_connections,
+        idle_timeout: timeout(opt.idle_timeout, default.idle_timeout),
+        read_timeout: timeout(opt.read_timeout, default.read_timeout),
+        write_timeout: timeout(opt.write_timeout, default.write_timeout),
+        request_timeout: opt.request_timeout.map(Duration::from_millis),
+        rate_limit: rate_limit_config(opt),
+    };
+    if opt.async_server {
+        info!("Serving connections asynchronously");
+        let server = AsyncKvsServer::with_config(engine, config);
+        shutdown_on_signal(server.shutdown_handle())?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(server.run(listen_address(opt)))
+    } else {
+        let server = KvsServer::with_config(engine, config);
+        shutdown_on_signal(server.shutdown_handle())?;
+        server.run(listen_address(opt))
+    }
+}
+
+fn tls_config(opt: &Opt) -> Result<Option<ServerTlsConfig>> {
+    let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) else {
+        return Ok(None);
+    };
+    ServerTlsConfig::from_files(cert, key, opt.tls_client_ca.as_deref()).map(Some)
+}
+
+/// The timeout set by a flag in seconds, where 0 means none, or `default`.
+fn timeout(seconds: Option<u64>, default: Option<Duration>) -> Option<Duration> {
+    match seconds {
+        Some(0) => None,
+        Some(seconds) => Some(Duration::from_secs(seconds)),
+        None => default,
     }
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    KvsServer::new(engine).run(addr)
+fn slow_log_config(opt: &Opt) -> Option<SlowLogConfig> {
+    let threshold = opt.slow_log_threshold?;
+    let default = SlowLogConfig::default();
+    Some(SlowLogConfig {
+        threshold: Duration::from_millis(threshold),
+        capacity: opt.slow_log_len.unwrap_or(default.capacity),
+        path: opt.slow_log_file.clone(),
+    })
+}
+
+fn audit_config(opt: &Opt) -> Option<AuditConfig> {
+    let mut config = AuditConfig::new(opt.audit_log.as_ref()?);
+    if let Some(max_len) = opt.audit_log_max_len {
+        config.max_file_len = max_len;
+    }
+    if let Some(files) = opt.audit_log_files {
+        config.max_files = files;
+    }
+    Some(config)
+}
+
+fn rate_limit_config(opt: &Opt) -> Option<RateLimitConfig> {
+    let config = RateLimitConfig {
+        connection_reads: opt.read_limit.map(RateLimit::per_second),
+        connection_writes: opt.write_limit.map(RateLimit::per_second),
+        user_reads: opt.user_read_limit.map(RateLimit::per_second),
+        user_writes: opt.user_write_limit.map(RateLimit::per_second),
+    };
+    (config != RateLimitConfig::default()).then_some(config)
+}
+
+fn listen_address(opt: &Opt) -> Address {
+    match &opt.unix {
+        Some(path) => Address::Unix(path.clone()),
+        None => opt.addr.into(),
+    }
+}
+
+/// Asks the server to shut down on SIGINT or SIGTERM.
+///
+/// A second signal exits right away, for a shutdown that takes too long.
+fn shutdown_on_signal(handle: ShutdownHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if handle.is_shutdown() {
+                warn!("Received signal {} again, exiting immediately", signal);
+                process::exit(1);
+            }
+            info!("Received signal {}, shutting down", signal);
+            handle.shutdown();
+        }
+    });
+    Ok(())
+}
+
+fn open_engine(engine: Engine, path: &Path) -> Result<Box<dyn KvsEngine>> {
+    Ok(match engine {
+        Engine::kvs => Box::new(KvStore::open(path)?),
+        Engine::sled => Box::new(SledKvsEngine::new(sled::open(path)?)),
+        Engine::memory => Box::new(MemoryKvsEngine::new()),
+    })
+}
+
+/// Copies every live key of the current engine into `to` and switches over.
+///
+/// The new engine is built in a side directory first, so an interrupted
+/// migration never touches the original data. Its files are then moved next
+/// to the original ones one by one, which the engines tell apart by name.
+/// That is not atomic, but the original engine stays the live one until the
+/// engine marker is replaced, which is. The new engine and the directories
+/// are synced before the marker is written, and the old files are only
+/// removed after it is, so that a power loss cannot leave the marker naming
+/// an engine whose data never reached the disk. A migration interrupted at any point leaves one engine or the
+/// other live and whole, and running it again clears what is left over.
+fn migrate(to: Engine) -> Result<()> {
+    let dir = current_dir()?;
+    let from = current_engine()?.unwrap_or(DEFAULT_ENGINE);
+    if !from.is_persistent() || !to.is_persistent() {
+        return Err(KvsError::StringError(format!(
+            "Cannot migrate from '{}' to '{}': the memory engine stores no data to migrate",
+            from, to
+        )));
+    }
+    if from == to {
+        info!("Storage engine is already '{}', nothing to migrate", to);
+        return Ok(());
+    }
+    info!("Migrating storage engine from '{}' to '{}'", from, to);
+
+    // Leftovers of an interrupted migration are never live data.
+    let staging = dir.join(MIGRATION_DIR);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    remove_engine_files(&dir, to)?;
+
+    {
+        let mut source = open_engine(from, &dir)?;
+        let mut target = open_engine(to, &staging)?;
+        let keys = source.keys()?;
+        for key in &keys {
+            if let Some(value) = source.get(key.clone())? {
+                target.set(key.clone(), value)?;
+            }
+        }
+        let copied = target.keys()?.len();
+        if copied != keys.len() {
+            return Err(KvsError::StringError(format!(
+                "Migration aborted: read {} keys from '{}' but '{}' holds {}",
+                keys.len(),
+                from,
+                to,
+                copied
+            )));
+        }
+        info!("Copied {} keys", copied);
+        target.sync()?;
+    }
+
+    // The new engine is on disk before its files are moved.
+    sync_dir(&staging)?;
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    // The files of the new engine are in place before the marker names it.
+    sync_dir(&dir)?;
+    let marker = dir.join(ENGINE_FILE);
+    let tmp_marker = dir.join(format!("{}.tmp", ENGINE_FILE));
+    fs::write(&tmp_marker, to.to_string())?;
+    File::open(&tmp_marker)?.sync_all()?;
+    fs::rename(&tmp_marker, &marker)?;
+    sync_dir(&dir)?;
+
+    remove_engine_files(&dir, from)?;
+    fs::remove_dir_all(&staging)?;
+    info!("Storage engine is now '{}'", to);
+    Ok(())
+}
+
+/// Makes the renames in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Returns whether the directory entry `name` belongs to `engine`.
+fn is_engine_file(engine: Engine, name: &str) -> bool {
+    match engine {
+        Engine::kvs => name
+            .strip_suffix(".log")
+            .is_some_and(|id| id.parse::<u64>().is_ok()),
+        Engine::sled => matches!(name, "conf" | "db" | "blobs") || name.starts_with("snap."),
+        Engine::memory => false,
+    }
+}
+
+fn remove_engine_files(dir: &Path, engine: Engine) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !is_engine_file(engine, &name.to_string_lossy()) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
 }
 
 fn current_engine() -> Result<Option<Engine>> {
-    let path = current_dir()?.join("engine");
+    let path = current_dir()?.join(ENGINE_FILE);
     if !path.exists() {
         return Ok(None);
     }
@@ -1,56 +1,418 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use crate::codec::{
+    DEFAULT_MAX_FRAME_LEN, accept_server_hello, client_hello, encode_frame, read_frame,
+    unexpected_eof,
+};
+use crate::common::{
+    CompactResponse, GetResponse, InfoResponse, PingResponse, RemoveResponse, Request,
+    RequestFrame, Response, ResponseFrame, ServerStatus, SetResponse, SlowLogResponse,
+    StatsResponse, unexpected_response, unsupported,
+};
+use crate::tls::SharedStream;
+use crate::transport::{Stream, timed_out};
+use crate::{
+    Address, ClientTlsConfig, Codec, Credentials, EngineStats, Result, ServerInfo, SlowLogEntry,
+};
+use std::collections::HashMap;
+use std::io::{BufWriter, Read, Write};
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+/// Settings of `KvsClient` and `AsyncKvsClient`.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// The codec to ask the server for. `Codec::Json` keeps the traffic
+    /// readable, for debugging.
+    pub codec: Codec,
+    /// The largest response accepted, in bytes.
+    pub max_frame_len: usize,
+    /// The TLS settings, for servers that speak TLS. Plaintext is the
+    /// default, for local use.
+    pub tls: Option<ClientTlsConfig>,
+    /// What to authenticate with, for servers with an ACL.
+    pub credentials: Option<Credentials>,
+    /// How long connecting to the server may take, and then how long the
+    /// handshake may.
+    pub connect_timeout: Option<Duration>,
+    /// How long a request may take to send and to answer. A client whose
+    /// request timed out should not be used any further.
+    pub timeout: Option<Duration>,
+    /// How long a throttled request may wait in total to be retried, after
+    /// the delays the server asks for. By default a throttled request fails
+    /// with `KvsError::Throttled` right away.
+    pub max_throttle_wait: Option<Duration>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            codec: Codec::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            tls: None,
+            credentials: None,
+            connect_timeout: None,
+            timeout: None,
+            max_throttle_wait: None,
+        }
+    }
+}
 
 /// Key value store client
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Box<dyn Read + Send>,
+    buf: Vec<u8>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    codec: Codec,
+    server_info: ServerInfo,
+    max_frame_len: usize,
+    max_throttle_wait: Option<Duration>,
+    next_id: u64,
 }
 
 impl KvsClient {
-    /// Connect to `addr` to access `KvsServer`.
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+    /// Connect to `addr` to access `KvsServer`, over TCP or a Unix domain
+    /// socket.
+    pub fn connect<A: Into<Address>>(addr: A) -> Result<Self> {
+        Self::connect_with_config(addr, &ClientConfig::default())
+    }
+
+    /// Connect to `addr` with the given settings.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ProtocolMismatch` if the server speaks another
+    /// protocol version, `KvsError::Tls` if the TLS handshake fails,
+    /// `KvsError::AuthenticationFailed` if the server turns the credentials
+    /// down, and `KvsError::Timeout` if the server does not answer in time.
+    pub fn connect_with_config<A: Into<Address>>(addr: A, config: &ClientConfig) -> Result<Self> {
+        let addr = addr.into();
+        let stream = Stream::connect(&addr, config.connect_timeout)
+            .map_err(|e| timed_out(e.into(), "connecting to the server"))?;
+        // The handshake is part of connecting, and requests come after.
+        stream.set_read_timeout(config.connect_timeout)?;
+        stream.set_write_timeout(config.connect_timeout)?;
+        let client = match &config.tls {
+            Some(tls) => {
+                let stream = SharedStream::new(
+                    tls.connect(&addr, stream.try_clone()?)
+                        .map_err(|e| timed_out(e, "connecting to the server"))?,
+                );
+                Self::from_stream(stream.clone(), stream, config)
+            }
+            None => Self::from_stream(stream.try_clone()?, stream.try_clone()?, config),
+        }
+        .map_err(|e| timed_out(e, "connecting to the server"))?;
+        stream.set_read_timeout(config.timeout)?;
+        stream.set_write_timeout(config.timeout)?;
+        Ok(client)
+    }
+
+    /// Talks to a server over the given byte streams, such as the two ends of
+    /// in-memory pipes to `KvsServer::serve_stream`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ProtocolMismatch` if the server speaks another
+    /// protocol version.
+    pub fn from_stream<R, W>(reader: R, writer: W, config: &ClientConfig) -> Result<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let mut reader: Box<dyn Read + Send> = Box::new(reader);
+        let mut writer: BufWriter<Box<dyn Write + Send>> = BufWriter::new(Box::new(writer));
+        writer.write_all(&client_hello(
+            vec![config.codec],
+            config.credentials.clone(),
+        )?)?;
+        writer.flush()?;
+
+        let mut buf = Vec::new();
+        let hello = read_frame(&mut reader, &mut buf, Codec::Json, config.max_frame_len)?
+            .ok_or_else(unexpected_eof)?;
+        let (codec, server_info) = accept_server_hello(hello, &[config.codec])?;
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
+            reader,
+            buf,
+            writer,
+            codec,
+            server_info,
+            max_frame_len: config.max_frame_len,
+            max_throttle_wait: config.max_throttle_wait,
+            next_id: 0,
         })
     }
+
+    /// Returns what the server told about itself when connecting.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.server_info
+    }
+
     /// Get the value of a given key from the server.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        match self.call(Request::Get { key })? {
+            Response::Get(GetResponse::Ok(value)) => Ok(value),
+            Response::Get(GetResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
         }
     }
 
     /// Set the value of a string key in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        match self.call(Request::Set { key, value })? {
+            Response::Set(SetResponse::Ok(_)) => Ok(()),
+            Response::Set(SetResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
         }
     }
 
     /// Remove a string key in the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        match self.call(Request::Remove { key })? {
+            Response::Remove(RemoveResponse::Ok(_)) => Ok(()),
+            Response::Remove(RemoveResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
         }
     }
+
+    /// Checks that the server answers.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(Request::Ping)? {
+            Response::Ping(PingResponse::Ok(_)) => Ok(()),
+            Response::Ping(PingResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Asks the server what it is and how long it has been running.
+    ///
+    /// # Errors
+    ///
+    /// Like `stats` and `compact`, it returns `KvsError::PermissionDenied`
+    /// unless the user holds `Permission::Admin`, on a server with an ACL.
+    pub fn info(&mut self) -> Result<ServerStatus> {
+        match self.call(Request::Info)? {
+            Response::Info(InfoResponse::Ok(status)) => Ok(status),
+            Response::Info(InfoResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Asks for the statistics of the engine of the server.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        match self.call(Request::Stats)? {
+            Response::Stats(StatsResponse::Ok(stats)) => Ok(stats),
+            Response::Stats(StatsResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Compacts the engine of the server now, and returns once it is done.
+    pub fn compact(&mut self) -> Result<()> {
+        match self.call(Request::Compact)? {
+            Response::Compact(CompactResponse::Ok(_)) => Ok(()),
+            Response::Compact(CompactResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the latest `count` entries of the slow log of the server, the
+    /// newest first. A server without a slow log returns none, and one that
+    /// did not announce the `slowlog` feature is not asked at all.
+    pub fn slow_log(&mut self, count: usize) -> Result<Vec<SlowLogEntry>> {
+        if !self.server_info.supports("slowlog") {
+            return Err(unsupported("slowlog"));
+        }
+        match self.call(Request::SlowLog { count })? {
+            Response::SlowLog(SlowLogResponse::Ok(entries)) => Ok(entries),
+            Response::SlowLog(SlowLogResponse::Err(err)) => Err(err.into()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Starts a batch of requests that are sent without waiting for each
+    /// response in turn.
+    ///
+    /// ```rust,no_run
+    /// # use kvs::{KvsClient, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+    /// let results = client
+    ///     .pipeline()
+    ///     .set("key1".to_owned(), "value1".to_owned())
+    ///     .set("key2".to_owned(), "value2".to_owned())
+    ///     .get("key3".to_owned())
+    ///     .execute()?;
+    /// assert_eq!(results.len(), 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Sends `request` and waits for its response, retrying it while the
+    /// server throttles it, as long as `max_throttle_wait` allows.
+    fn call(&mut self, request: Request) -> Result<Response> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let response = self.call_once(request.clone())?;
+            match retry_wait(&response, waited, self.max_throttle_wait) {
+                Some(wait) => {
+                    thread::sleep(wait);
+                    waited += wait;
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+
+    /// Sends `request` and waits for its response.
+    fn call_once(&mut self, request: Request) -> Result<Response> {
+        let (id, _) = self.send(request)?;
+        self.flush()?;
+        let frame = self.receive()?;
+        if frame.id != id {
+            return Err(unexpected_response());
+        }
+        Ok(frame.response)
+    }
+
+    /// Writes `request` to the buffer and returns its ID and its length.
+    fn send(&mut self, request: Request) -> Result<(u64, usize)> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut bytes = Vec::new();
+        encode_frame(self.codec, &RequestFrame { id, request }, &mut bytes)?;
+        self.writer
+            .write_all(&bytes)
+            .map_err(|e| timed_out(e.into(), "sending a request"))?;
+        Ok((id, bytes.len()))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .map_err(|e| timed_out(e.into(), "sending a request"))
+    }
+
+    fn receive(&mut self) -> Result<ResponseFrame> {
+        read_frame(
+            &mut self.reader,
+            &mut self.buf,
+            self.codec,
+            self.max_frame_len,
+        )
+        .map_err(|e| timed_out(e, "waiting for the server"))?
+        .ok_or_else(unexpected_eof)
+    }
+}
+
+/// How long to wait before retrying the request `response` answers, if it
+/// was throttled and waiting that long keeps the time `waited` so far within
+/// `max_wait`.
+pub(crate) fn retry_wait(
+    response: &Response,
+    waited: Duration,
+    max_wait: Option<Duration>,
+) -> Option<Duration> {
+    let wait = response.retry_after()?;
+    let max_wait = max_wait?;
+    (waited.saturating_add(wait) <= max_wait).then_some(wait)
+}
+
+/// How many requests of a pipeline may wait for a response at once.
+const PIPELINE_WINDOW: usize = 128;
+
+/// How many bytes of requests of a pipeline may wait for a response at once.
+const PIPELINE_WINDOW_BYTES: usize = 64 * 1024;
+
+/// A batch of requests built by `KvsClient::pipeline`.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Adds a request for the value of `key`.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+
+    /// Adds a request setting `key` to `value`.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+
+    /// Adds a request removing `key`.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+
+    /// Sends every request and waits for all responses.
+    ///
+    /// Returns one result per request, in the order the requests were added:
+    /// the value for a get and `None` for a set or remove. The server runs
+    /// the requests in that order too.
+    ///
+    /// Batches of any size may be sent. Once 128 requests, or 64 KiB of
+    /// them, are waiting for a response, half of them are answered before
+    /// more requests are sent, so that the server is never stuck writing
+    /// responses nobody reads.
+    ///
+    /// Throttled requests are not retried, whatever
+    /// `ClientConfig::max_throttle_wait` says: their results are
+    /// `KvsError::Throttled`.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error without any results if the connection fails.
+    pub fn execute(&mut self) -> Result<Vec<Result<Option<String>>>> {
+        let requests = mem::take(&mut self.requests);
+        let mut results: Vec<_> = (0..requests.len()).map(|_| None).collect();
+        // The index and the length of the requests waiting for a response.
+        let mut in_flight = HashMap::new();
+        let mut in_flight_bytes = 0;
+        for (index, request) in requests.into_iter().enumerate() {
+            if in_flight.len() >= PIPELINE_WINDOW || in_flight_bytes >= PIPELINE_WINDOW_BYTES {
+                // Half the window is read at once, so that requests and
+                // responses still go in batches.
+                self.client.flush()?;
+                while in_flight.len() > PIPELINE_WINDOW / 2
+                    || in_flight_bytes > PIPELINE_WINDOW_BYTES / 2
+                {
+                    in_flight_bytes -= self.receive(&mut in_flight, &mut results)?;
+                }
+            }
+            let (id, len) = self.client.send(request)?;
+            in_flight.insert(id, (index, len));
+            in_flight_bytes += len;
+        }
+        self.client.flush()?;
+        while !in_flight.is_empty() {
+            self.receive(&mut in_flight, &mut results)?;
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Reads the response to one of the requests `in_flight` into `results`,
+    /// and returns the length of the request.
+    fn receive(
+        &mut self,
+        in_flight: &mut HashMap<u64, (usize, usize)>,
+        results: &mut [Option<Result<Option<String>>>],
+    ) -> Result<usize> {
+        let frame = self.client.receive()?;
+        let (index, len) = in_flight
+            .remove(&frame.id)
+            .ok_or_else(unexpected_response)?;
+        results[index] = Some(frame.response.into_result());
+        Ok(len)
+    }
 }
@@ -0,0 +1,591 @@
+use crate::auth::Permission;
+use crate::common::{
+    ClientHello, Request, RequestFrame, ResponseError, ResponseFrame, ServerHello,
+};
+use crate::transport::read_len;
+use crate::{Acl, Credentials, KvsError, Result};
+use bincode::Options;
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Deserializer, Value};
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The default limit on the size of a single message.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const LEN_PREFIX: usize = 4;
+
+/// How the messages of a connection are encoded.
+///
+/// A client proposes codecs in its opening handshake and the server picks
+/// one. A client that skips the handshake and sends a JSON request right away
+/// is served in JSON, which makes it easy to talk to a server by hand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    /// Compact binary messages, each prefixed with its length as a 32-bit
+    /// big-endian integer.
+    #[default]
+    Binary,
+    /// A stream of concatenated JSON values.
+    Json,
+}
+
+fn bincode() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Appends `value`, encoded as one frame of `codec`, to `out`.
+pub fn encode_frame<T: Serialize>(codec: Codec, value: &T, out: &mut Vec<u8>) -> Result<()> {
+    match codec {
+        Codec::Binary => {
+            let len = bincode().serialized_size(value)?;
+            let len = u32::try_from(len).map_err(|_| KvsError::FrameTooLarge(u32::MAX as usize))?;
+            out.extend_from_slice(&len.to_be_bytes());
+            bincode().serialize_into(out, value)?;
+        }
+        Codec::Json => serde_json::to_writer(out, value)?,
+    }
+    Ok(())
+}
+
+/// Takes the next frame of `codec` off the front of `buf`.
+///
+/// Returns `None` if `buf` does not hold a complete frame yet.
+///
+/// # Errors
+///
+/// It returns `KvsError::FrameTooLarge` as soon as the frame is known to be
+/// longer than `max_len` bytes, so that a peer cannot make the buffer grow
+/// without bound.
+pub fn decode_frame<T: DeserializeOwned>(
+    codec: Codec,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+) -> Result<Option<T>> {
+    decode_frame_scanned(codec, buf, max_len, &mut JsonScan::default())
+}
+
+/// Like `decode_frame`, remembering in `scan` how far a JSON value has been
+/// looked at, so that a value arriving in many pieces is parsed once.
+fn decode_frame_scanned<T: DeserializeOwned>(
+    codec: Codec,
+    buf: &mut Vec<u8>,
+    max_len: usize,
+    scan: &mut JsonScan,
+) -> Result<Option<T>> {
+    match codec {
+        Codec::Binary => {
+            let Some(prefix) = buf.first_chunk::<LEN_PREFIX>() else {
+                return Ok(None);
+            };
+            let len = u32::from_be_bytes(*prefix) as usize;
+            if len > max_len {
+                return Err(KvsError::FrameTooLarge(max_len));
+            }
+            if buf.len() < LEN_PREFIX + len {
+                return Ok(None);
+            }
+            let value = bincode().deserialize(&buf[LEN_PREFIX..LEN_PREFIX + len])?;
+            buf.drain(..LEN_PREFIX + len);
+            Ok(Some(value))
+        }
+        Codec::Json => {
+            let buffered = buf.len();
+            let value = if scan.may_be_complete(buf) {
+                decode_json(buf)?
+            } else {
+                None
+            };
+            let len = if value.is_some() {
+                *scan = JsonScan::default();
+                buffered - buf.len()
+            } else {
+                buffered
+            };
+            if len > max_len {
+                return Err(KvsError::FrameTooLarge(max_len));
+            }
+            Ok(value)
+        }
+    }
+}
+
+/// Takes the next JSON value off the front of `buf`.
+///
+/// Returns `None` if `buf` does not hold a complete value yet.
+fn decode_json<T: DeserializeOwned>(buf: &mut Vec<u8>) -> Result<Option<T>> {
+    let mut stream = Deserializer::from_slice(buf).into_iter::<T>();
+    match stream.next() {
+        Some(Ok(value)) => {
+            let len = stream.byte_offset();
+            buf.drain(..len);
+            Ok(Some(value))
+        }
+        Some(Err(e)) if !e.is_eof() => Err(e.into()),
+        // incomplete value or only whitespace so far
+        _ => Ok(None),
+    }
+}
+
+/// How far the JSON value at the front of a buffer has been looked at, and
+/// what was found, without parsing it.
+///
+/// Parsing an incomplete value after every read would take time quadratic
+/// in its length. Finding where it may end takes a look at each byte once.
+#[derive(Clone, Debug, Default)]
+struct JsonScan {
+    /// How many bytes have been looked at.
+    scanned: usize,
+    /// How deep inside objects and arrays the last byte looked at is.
+    depth: usize,
+    in_string: bool,
+    /// Whether the last byte looked at is a backslash inside a string.
+    escaped: bool,
+    /// Whether the value is a number or literal, or not JSON at all.
+    scalar: bool,
+}
+
+impl JsonScan {
+    /// Looks at the bytes of `buf` not looked at yet, and returns whether the
+    /// value at its front may be complete, and is worth parsing.
+    ///
+    /// Anything that does not start like an object, an array or a string is
+    /// worth parsing right away, which turns garbage into an error early.
+    fn may_be_complete(&mut self, buf: &[u8]) -> bool {
+        while let Some(&byte) = buf.get(self.scanned) {
+            self.scanned += 1;
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => {
+                        self.in_string = false;
+                        if self.depth == 0 {
+                            return true;
+                        }
+                    }
+                    _ => {}
+                }
+            } else if self.scalar {
+                if byte.is_ascii_whitespace() || b"{}[],:\"".contains(&byte) {
+                    return true;
+                }
+            } else {
+                match byte {
+                    b'"' => self.in_string = true,
+                    b'{' | b'[' => self.depth += 1,
+                    b'}' | b']' if self.depth > 0 => {
+                        self.depth -= 1;
+                        if self.depth == 0 {
+                            return true;
+                        }
+                    }
+                    _ if self.depth > 0 || byte.is_ascii_whitespace() => {}
+                    _ => {
+                        self.scalar = true;
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Returns whether `buf` holds no part of a frame of `codec`.
+pub fn is_idle(codec: Codec, buf: &[u8]) -> bool {
+    match codec {
+        Codec::Binary => buf.is_empty(),
+        Codec::Json => buf.iter().all(u8::is_ascii_whitespace),
+    }
+}
+
+/// The error for a stream that ends in the middle of a message.
+pub fn unexpected_eof() -> KvsError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended mid-message").into()
+}
+
+/// Reads the next frame of `codec` from `reader`, keeping bytes that arrived
+/// early in `buf` for the next call.
+///
+/// Returns `None` once the stream ends cleanly between two frames.
+pub fn read_frame<T, R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    codec: Codec,
+    max_len: usize,
+) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: Read,
+{
+    let mut chunk = [0; 8 * 1024];
+    loop {
+        if let Some(value) = decode_frame(codec, buf, max_len)? {
+            return Ok(Some(value));
+        }
+        let len = read_len(reader.read(&mut chunk))?;
+        if len == 0 {
+            return if is_idle(codec, buf) {
+                Ok(None)
+            } else {
+                Err(unexpected_eof())
+            };
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// Like `read_frame`, for async code.
+pub async fn read_frame_async<T, R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    codec: Codec,
+    max_len: usize,
+) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(value) = decode_frame(codec, buf, max_len)? {
+            return Ok(Some(value));
+        }
+        if read_len(reader.read_buf(buf).await)? == 0 {
+            return if is_idle(codec, buf) {
+                Ok(None)
+            } else {
+                Err(unexpected_eof())
+            };
+        }
+    }
+}
+
+/// The version of the protocol spoken by this crate.
+///
+/// It is bumped whenever a change to the messages would break clients or
+/// servers built from an earlier version.
+///
+/// Version 3 added the `Ping`, `Info`, `Stats` and `Compact` requests, which
+/// a server speaking version 2 cannot decode.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// The optional protocol features this server supports, announced in the
+/// handshake.
+///
+/// Clients announce the same list in their handshake, and are only sent the
+/// `ResponseError` variants of the features they announced.
+const FEATURES: &[&str] = &[
+    "pipelining",
+    "binary-codec",
+    "auth",
+    "admin",
+    "slowlog",
+    "timeouts",
+    "rate-limit",
+];
+
+pub(crate) const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What a server tells about itself in the handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// The protocol version the server speaks.
+    pub protocol_version: u32,
+    /// The version of the server.
+    pub server_version: String,
+    /// The name of the storage engine the server runs.
+    pub engine: String,
+    /// The optional protocol features the server supports.
+    pub features: Vec<String>,
+}
+
+impl ServerInfo {
+    /// Returns whether the server announced `feature` in the handshake.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// The client's opening handshake, proposing `codecs` in order of preference
+/// and authenticating with `credentials`.
+pub fn client_hello(codecs: Vec<Codec>, credentials: Option<Credentials>) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&ClientHello::Hello {
+        codecs,
+        protocol_version: PROTOCOL_VERSION,
+        client_version: CRATE_VERSION.to_owned(),
+        credentials,
+        features: FEATURES.iter().map(|&f| f.to_owned()).collect(),
+    })?)
+}
+
+/// Checks the server's answer to `client_hello` and returns the codec it
+/// picked along with what it told about itself.
+///
+/// # Errors
+///
+/// It returns `KvsError::ProtocolMismatch` if the server speaks another
+/// protocol version, and `KvsError::AuthenticationFailed` if it turned the
+/// credentials down.
+pub fn accept_server_hello(hello: ServerHello, proposed: &[Codec]) -> Result<(Codec, ServerInfo)> {
+    let (codec, info) = match hello {
+        ServerHello::Welcome {
+            codec,
+            protocol_version,
+            server_version,
+            engine,
+            features,
+        } => (
+            codec,
+            ServerInfo {
+                protocol_version,
+                server_version,
+                engine,
+                features,
+            },
+        ),
+        ServerHello::Rejected {
+            protocol_version,
+            server_version,
+        } => {
+            return Err(KvsError::ProtocolMismatch {
+                client: PROTOCOL_VERSION,
+                server: protocol_version,
+                server_version,
+            });
+        }
+        ServerHello::Denied { message } => return Err(KvsError::AuthenticationFailed(message)),
+    };
+    if info.protocol_version != PROTOCOL_VERSION {
+        return Err(KvsError::ProtocolMismatch {
+            client: PROTOCOL_VERSION,
+            server: info.protocol_version,
+            server_version: info.server_version,
+        });
+    }
+    if !proposed.contains(&codec) {
+        return Err(KvsError::StringError(format!(
+            "Server picked codec {:?}, which was not proposed",
+            codec
+        )));
+    }
+    Ok((codec, info))
+}
+
+/// Why a client was turned away in the handshake.
+#[derive(Clone)]
+enum Refusal {
+    /// The client speaks this other protocol version.
+    Version(u32),
+    /// The client did not authenticate.
+    Credentials(String),
+}
+
+/// The server side of the framing of a connection: the opening handshake and
+/// the codec it settles on.
+#[derive(Clone)]
+pub struct ServerFraming {
+    codec: Option<Codec>,
+    max_frame_len: usize,
+    engine: String,
+    acl: Option<Acl>,
+    /// The user the client authenticated as.
+    user: Option<String>,
+    /// The optional features the client announced in the handshake.
+    client_features: Vec<String>,
+    refused: Option<Refusal>,
+    scan: JsonScan,
+}
+
+impl ServerFraming {
+    pub fn new(max_frame_len: usize, engine: &str, acl: Option<Acl>) -> Self {
+        ServerFraming {
+            codec: None,
+            max_frame_len,
+            engine: engine.to_owned(),
+            acl,
+            user: None,
+            client_features: Vec::new(),
+            refused: None,
+            scan: JsonScan::default(),
+        }
+    }
+
+    /// Takes the next request off the front of `buf`.
+    ///
+    /// An opening handshake is answered by appending the reply to `out`.
+    /// A client speaking another protocol version, or failing to
+    /// authenticate when the server has an ACL, is sent a rejection, and
+    /// `check_open` fails from then on.
+    pub fn decode(&mut self, buf: &mut Vec<u8>, out: &mut Vec<u8>) -> Result<Option<RequestFrame>> {
+        if self.refused.is_some() {
+            return Ok(None);
+        }
+        if let Some(codec) = self.codec {
+            return decode_frame_scanned(codec, buf, self.max_frame_len, &mut self.scan);
+        }
+
+        // The first message is JSON: either a handshake or a request.
+        let Some(opening) =
+            decode_frame_scanned::<Value>(Codec::Json, buf, self.max_frame_len, &mut self.scan)?
+        else {
+            return Ok(None);
+        };
+        match serde_json::from_value::<ClientHello>(opening.clone()) {
+            Ok(ClientHello::Hello {
+                codecs,
+                protocol_version,
+                client_version,
+                credentials,
+                features,
+            }) => {
+                debug!(
+                    "Client {} speaks protocol version {}",
+                    client_version, protocol_version
+                );
+                if protocol_version != PROTOCOL_VERSION {
+                    serde_json::to_writer(
+                        out,
+                        &ServerHello::Rejected {
+                            protocol_version: PROTOCOL_VERSION,
+                            server_version: CRATE_VERSION.to_owned(),
+                        },
+                    )?;
+                    self.refused = Some(Refusal::Version(protocol_version));
+                    return Ok(None);
+                }
+                if let Some(acl) = &self.acl {
+                    let user = match &credentials {
+                        Some(credentials) => acl.authenticate(credentials),
+                        None => Err(KvsError::AuthenticationFailed(
+                            "credentials required".to_owned(),
+                        )),
+                    };
+                    match user {
+                        Ok(user) => {
+                            debug!("Client authenticated as {}", user);
+                            self.user = Some(user);
+                        }
+                        Err(e) => {
+                            let message = match e {
+                                KvsError::AuthenticationFailed(message) => message,
+                                e => e.to_string(),
+                            };
+                            serde_json::to_writer(
+                                out,
+                                &ServerHello::Denied {
+                                    message: message.clone(),
+                                },
+                            )?;
+                            self.refused = Some(Refusal::Credentials(message));
+                            return Ok(None);
+                        }
+                    }
+                }
+                let codec = codecs.first().copied().unwrap_or(Codec::Json);
+                let welcome = ServerHello::Welcome {
+                    codec,
+                    protocol_version: PROTOCOL_VERSION,
+                    server_version: CRATE_VERSION.to_owned(),
+                    engine: self.engine.clone(),
+                    features: FEATURES.iter().map(|&f| f.to_owned()).collect(),
+                };
+                serde_json::to_writer(out, &welcome)?;
+                self.codec = Some(codec);
+                self.client_features = features;
+                self.decode(buf, &mut Vec::new())
+            }
+            Err(_) => {
+                self.codec = Some(Codec::Json);
+                Ok(Some(serde_json::from_value(opening)?))
+            }
+        }
+    }
+
+    /// Appends `frame` to `out` in the codec of the connection.
+    ///
+    /// An error of a feature the client did not announce is sent as
+    /// `ResponseError::Other` instead, which any client can decode.
+    pub fn encode(&self, frame: &ResponseFrame, out: &mut Vec<u8>) -> Result<()> {
+        let codec = self.codec.unwrap_or(Codec::Json);
+        match frame.response.err().and_then(|err| self.fallback(err)) {
+            Some(err) => {
+                let frame = ResponseFrame {
+                    id: frame.id,
+                    response: frame.response.with_err(err),
+                };
+                encode_frame(codec, &frame, out)
+            }
+            None => encode_frame(codec, frame, out),
+        }
+    }
+
+    /// The error to send in place of `err` if the client did not announce
+    /// the feature it belongs to.
+    fn fallback(&self, err: &ResponseError) -> Option<ResponseError> {
+        let (feature, message) = match err {
+            ResponseError::Timeout(what) => ("timeouts", KvsError::Timeout(what.clone())),
+            ResponseError::Throttled(wait) => ("rate-limit", KvsError::Throttled(*wait)),
+            _ => return None,
+        };
+        let announced = self.client_features.iter().any(|f| f == feature);
+        (!announced).then(|| ResponseError::Other(message.to_string()))
+    }
+
+    /// Returns whether `buf` holds no part of a message.
+    pub fn is_idle(&self, buf: &[u8]) -> bool {
+        is_idle(self.codec.unwrap_or(Codec::Json), buf)
+    }
+
+    /// Fails if the client was turned away in the handshake, after which the
+    /// connection should be closed.
+    pub fn check_open(&self) -> Result<()> {
+        match &self.refused {
+            Some(Refusal::Version(client)) => Err(KvsError::ProtocolMismatch {
+                client: *client,
+                server: PROTOCOL_VERSION,
+                server_version: CRATE_VERSION.to_owned(),
+            }),
+            Some(Refusal::Credentials(message)) => {
+                Err(KvsError::AuthenticationFailed(message.clone()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns whether the next message may be a handshake that checks
+    /// credentials, which takes a deliberately slow hash.
+    pub fn authenticates(&self) -> bool {
+        self.acl.is_some() && self.codec.is_none() && self.refused.is_none()
+    }
+
+    /// The user the client authenticated as, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Checks `request` against the ACL, if the server has one. `Info`,
+    /// `Stats`, `Compact` and `SlowLog` need `Permission::Admin`, and anyone
+    /// may ping.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::PermissionDenied` if the user of the connection
+    /// may not make the request.
+    pub fn authorize(&self, request: &Request) -> Result<()> {
+        let Some(acl) = &self.acl else {
+            return Ok(());
+        };
+        let (permission, key) = match request {
+            Request::Get { key } => (Permission::Read, key.as_str()),
+            Request::Set { key, .. } | Request::Remove { key } => (Permission::Write, key.as_str()),
+            Request::Ping => return Ok(()),
+            Request::Info | Request::Stats | Request::Compact | Request::SlowLog { .. } => {
+                (Permission::Admin, "")
+            }
+        };
+        acl.check(self.user.as_deref(), permission, key)
+    }
+}
@@ -1,33 +1,374 @@
+use crate::metrics::Outcome;
+use crate::ratelimit::Access;
+use crate::{Codec, Credentials, EngineStats, KvsError, Result, SlowLogEntry};
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+/// The message a client opens a connection with. It is always JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientHello {
+    Hello {
+        codecs: Vec<Codec>,
+        // Missing from clients that predate versioning, which count as 0.
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        client_version: String,
+        #[serde(default)]
+        credentials: Option<Credentials>,
+        // The optional features the client understands, which clients that
+        // predate them send none of.
+        #[serde(default)]
+        features: Vec<String>,
+    },
+}
+
+/// The server's answer to a `ClientHello`. It is always JSON, and every later
+/// message in either direction uses the codec it names.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerHello {
+    Welcome {
+        codec: Codec,
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        server_version: String,
+        #[serde(default)]
+        engine: String,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    /// The client speaks another protocol version. The server closes the
+    /// connection after sending this.
+    Rejected {
+        protocol_version: u32,
+        server_version: String,
+    },
+    /// The server requires credentials, and the client sent none or wrong
+    /// ones. The server closes the connection after sending this.
+    Denied { message: String },
+}
+
+/// A request together with the ID its response will echo.
+///
+/// IDs let a client send many requests before reading any response, and let
+/// a server answer them in any order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestFrame {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// A response together with the ID of the request it answers.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub id: u64,
+    pub response: Response,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Response {
     Get(GetResponse),
     Set(SetResponse),
     Remove(RemoveResponse),
+    Ping(PingResponse),
+    Info(InfoResponse),
+    Stats(StatsResponse),
+    Compact(CompactResponse),
+    SlowLog(SlowLogResponse),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Response {
+    /// The response to `request` failing with `err`.
+    pub fn error(request: &Request, err: KvsError) -> Response {
+        let err = ResponseError::from(err);
+        match request {
+            Request::Get { .. } => Response::Get(GetResponse::Err(err)),
+            Request::Set { .. } => Response::Set(SetResponse::Err(err)),
+            Request::Remove { .. } => Response::Remove(RemoveResponse::Err(err)),
+            Request::Ping => Response::Ping(PingResponse::Err(err)),
+            Request::Info => Response::Info(InfoResponse::Err(err)),
+            Request::Stats => Response::Stats(StatsResponse::Err(err)),
+            Request::Compact => Response::Compact(CompactResponse::Err(err)),
+            Request::SlowLog { .. } => Response::SlowLog(SlowLogResponse::Err(err)),
+        }
+    }
+
+    /// The length of the value returned, if any, for the slow log.
+    pub fn value_len(&self) -> Option<usize> {
+        match self {
+            Response::Get(GetResponse::Ok(Some(value))) => Some(value.len()),
+            _ => None,
+        }
+    }
+
+    /// How the request ended, for metrics.
+    pub fn outcome(&self) -> Outcome {
+        match self.err() {
+            None => Outcome::Ok,
+            Some(ResponseError::PermissionDenied(_)) => Outcome::Denied,
+            Some(ResponseError::Throttled(_)) => Outcome::Throttled,
+            Some(_) => Outcome::Error,
+        }
+    }
+
+    /// Why the request failed, for the audit log.
+    pub fn error_message(&self) -> Option<String> {
+        Some(match self.err()? {
+            ResponseError::KeyNotFound => KvsError::KeyNotFound.to_string(),
+            ResponseError::Timeout(what) => KvsError::Timeout(what.clone()).to_string(),
+            ResponseError::Throttled(wait) => KvsError::Throttled(*wait).to_string(),
+            ResponseError::PermissionDenied(message)
+            | ResponseError::Io(message)
+            | ResponseError::Other(message) => message.clone(),
+        })
+    }
+
+    /// How long to wait before retrying, if the request was throttled.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.err()? {
+            ResponseError::Throttled(wait) => Some(*wait),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn err(&self) -> Option<&ResponseError> {
+        let err = match self {
+            Response::Get(GetResponse::Err(err))
+            | Response::Set(SetResponse::Err(err))
+            | Response::Remove(RemoveResponse::Err(err))
+            | Response::Ping(PingResponse::Err(err))
+            | Response::Info(InfoResponse::Err(err))
+            | Response::Stats(StatsResponse::Err(err))
+            | Response::Compact(CompactResponse::Err(err))
+            | Response::SlowLog(SlowLogResponse::Err(err)) => err,
+            _ => return None,
+        };
+        Some(err)
+    }
+
+    /// The same response, failing with `err` instead.
+    pub(crate) fn with_err(&self, err: ResponseError) -> Response {
+        match self {
+            Response::Get(_) => Response::Get(GetResponse::Err(err)),
+            Response::Set(_) => Response::Set(SetResponse::Err(err)),
+            Response::Remove(_) => Response::Remove(RemoveResponse::Err(err)),
+            Response::Ping(_) => Response::Ping(PingResponse::Err(err)),
+            Response::Info(_) => Response::Info(InfoResponse::Err(err)),
+            Response::Stats(_) => Response::Stats(StatsResponse::Err(err)),
+            Response::Compact(_) => Response::Compact(CompactResponse::Err(err)),
+            Response::SlowLog(_) => Response::SlowLog(SlowLogResponse::Err(err)),
+        }
+    }
+
+    /// Turns the response into the result of its request: the value for a
+    /// `Get`, `None` for the others.
+    pub fn into_result(self) -> Result<Option<String>> {
+        match self {
+            Response::Get(GetResponse::Ok(value)) => Ok(value),
+            Response::Set(SetResponse::Ok(()))
+            | Response::Remove(RemoveResponse::Ok(()))
+            | Response::Ping(PingResponse::Ok(()))
+            | Response::Info(InfoResponse::Ok(_))
+            | Response::Stats(StatsResponse::Ok(_))
+            | Response::Compact(CompactResponse::Ok(()))
+            | Response::SlowLog(SlowLogResponse::Ok(_)) => Ok(None),
+            Response::Get(GetResponse::Err(err))
+            | Response::Set(SetResponse::Err(err))
+            | Response::Remove(RemoveResponse::Err(err))
+            | Response::Ping(PingResponse::Err(err))
+            | Response::Info(InfoResponse::Err(err))
+            | Response::Stats(StatsResponse::Err(err))
+            | Response::Compact(CompactResponse::Err(err))
+            | Response::SlowLog(SlowLogResponse::Err(err)) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Checks that the server answers.
+    Ping,
+    /// Asks what the server is and how long it has been running.
+    Info,
+    /// Asks for the statistics of the engine.
+    Stats,
+    /// Compacts the engine.
+    Compact,
+    /// Asks for the latest `count` entries of the slow log.
+    SlowLog {
+        count: usize,
+    },
+}
+
+impl Request {
+    /// The type of the request, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "remove",
+            Request::Ping => "ping",
+            Request::Info => "info",
+            Request::Stats => "stats",
+            Request::Compact => "compact",
+            Request::SlowLog { .. } => "slowlog",
+        }
+    }
+
+    /// Whether the request reads or writes, for rate limits.
+    pub(crate) fn access(&self) -> Access {
+        match self {
+            Request::Set { .. } | Request::Remove { .. } => Access::Write,
+            _ => Access::Read,
+        }
+    }
+
+    /// The operation and the key of a `Set` or a `Remove`, for the audit log.
+    pub fn audited(&self) -> Option<(&'static str, &str)> {
+        match self {
+            Request::Set { key, .. } => Some(("set", key)),
+            Request::Remove { key } => Some(("remove", key)),
+            _ => None,
+        }
+    }
+
+    /// The lengths of the key and of the value of the request, for the slow
+    /// log.
+    pub fn sizes(&self) -> (usize, usize) {
+        match self {
+            Request::Get { key } | Request::Remove { key } => (key.len(), 0),
+            Request::Set { key, value } => (key.len(), value.len()),
+            _ => (0, 0),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ResponseError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ResponseError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Ok(()),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InfoResponse {
+    Ok(ServerStatus),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    Ok(()),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SlowLogResponse {
+    Ok(Vec<SlowLogEntry>),
+    Err(ResponseError),
+}
+
+/// What a running server tells about itself in answer to an `Info` request.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// The version of the server.
+    pub server_version: String,
+    /// The name of the storage engine the server runs.
+    pub engine: String,
+    /// How long the server has been running.
+    pub uptime: Duration,
+    /// The number of connections open, the one asking included.
+    pub connections: u64,
+    /// The number of connections accepted since the server started.
+    pub total_connections: u64,
+}
+
+/// Why a request failed, as sent to the client, which turns it back into the
+/// `KvsError` of the same kind.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ResponseError {
+    /// The key to remove does not exist.
+    KeyNotFound,
+    /// The user of the connection may not make the request.
+    PermissionDenied(String),
+    /// An I/O error of the engine.
+    Io(String),
+    /// Any other error of the engine, such as a corrupted log.
+    Other(String),
+    /// The request waited past its deadline to be processed.
+    Timeout(String),
+    /// The client went over its rate limit, and may retry after this long.
+    Throttled(Duration),
+}
+
+impl From<KvsError> for ResponseError {
+    fn from(err: KvsError) -> ResponseError {
+        match err {
+            KvsError::KeyNotFound => ResponseError::KeyNotFound,
+            KvsError::PermissionDenied(reason) => ResponseError::PermissionDenied(reason),
+            KvsError::Io(e) => ResponseError::Io(e.to_string()),
+            KvsError::Timeout(what) => ResponseError::Timeout(what),
+            KvsError::Throttled(wait) => ResponseError::Throttled(wait),
+            err => ResponseError::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<ResponseError> for KvsError {
+    fn from(err: ResponseError) -> KvsError {
+        match err {
+            ResponseError::KeyNotFound => KvsError::KeyNotFound,
+            ResponseError::PermissionDenied(reason) => KvsError::PermissionDenied(reason),
+            ResponseError::Io(message) => KvsError::Io(io::Error::other(message)),
+            ResponseError::Other(message) => KvsError::StringError(message),
+            ResponseError::Timeout(what) => KvsError::Timeout(what),
+            ResponseError::Throttled(wait) => KvsError::Throttled(wait),
+        }
+    }
+}
+
+/// The error for a response that does not belong to any request sent.
+pub fn unexpected_response() -> KvsError {
+    KvsError::StringError("Unexpected response from server".to_owned())
+}
+
+/// The error for a request that needs a protocol feature the server did not
+/// announce.
+pub fn unsupported(feature: &str) -> KvsError {
+    KvsError::StringError(format!("Server does not support {}", feature))
 }
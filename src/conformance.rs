@@ -0,0 +1,326 @@
+//! A conformance test suite for `KvsEngine` implementations.
+//!
+//! Every check is a function that takes an engine factory: a closure opening
+//! an engine on a given directory. Each check works in a fresh directory of
+//! its own and returns an error, or panics on a failed assertion, if the
+//! engine does not behave like the engines of this crate.
+//!
+//! The `engine_conformance_tests!` macro turns the whole suite into `#[test]`
+//! functions, so any engine can prove it is compatible with a few lines:
+//!
+//! ```rust
+//! mod memory {
+//!     use kvs::MemoryKvsEngine;
+//!     kvs::engine_conformance_tests!(|_| Ok(MemoryKvsEngine::new()));
+//! }
+//!
+//! mod kv_store {
+//!     use kvs::KvStore;
+//!     kvs::engine_conformance_tests!(|path| KvStore::open(path), persistent);
+//! }
+//! # fn main() {}
+//! ```
+
+use crate::{KvsEngine, KvsError, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const LARGE_VALUE_LEN: usize = 1024 * 1024;
+const MANY_KEYS: usize = 10_000;
+const THREADS: usize = 8;
+const KEYS_PER_THREAD: usize = 200;
+
+/// Generates a `#[test]` function for every check of the conformance suite.
+///
+/// The argument is an expression usable as `Fn(&Path) -> Result<E>`. Pass
+/// `persistent` as a second argument to also check that data survives
+/// dropping and reopening the engine on the same directory.
+#[macro_export]
+macro_rules! engine_conformance_tests {
+    ($open:expr) => {
+        $crate::engine_conformance_tests!(@tests $open;
+            set_and_get,
+            overwrite_value,
+            get_missing_key,
+            remove_key,
+            remove_missing_key,
+            list_keys,
+            large_values,
+            many_keys,
+            concurrent_access
+        );
+    };
+    ($open:expr, persistent) => {
+        $crate::engine_conformance_tests!($open);
+        $crate::engine_conformance_tests!(@tests $open;
+            persist_across_reopen,
+            persist_removal_across_reopen
+        );
+    };
+    (@tests $open:expr; $($check:ident),*) => {
+        $(
+            #[test]
+            fn $check() -> $crate::Result<()> {
+                $crate::conformance::$check(&$open)
+            }
+        )*
+    };
+}
+
+/// A uniquely named directory under the system temporary directory, removed
+/// again when dropped.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new() -> Result<TestDir> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "kvs-conformance-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(TestDir(path))
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Checks that a stored value can be read back.
+pub fn set_and_get<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Checks that setting an existing key replaces its value.
+pub fn overwrite_value<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Checks that getting a key that was never set returns `None`.
+pub fn get_missing_key<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+/// Checks that a removed key is gone and can be set again.
+pub fn remove_key<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+/// Checks that removing a missing key fails with `KvsError::KeyNotFound`.
+pub fn remove_missing_key<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+/// Checks that `keys` returns exactly the live keys, in ascending order.
+pub fn list_keys<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    assert!(engine.keys()?.is_empty());
+    for key in ["key3", "key1", "key2"] {
+        engine.set(key.to_owned(), "value".to_owned())?;
+    }
+    engine.remove("key2".to_owned())?;
+    assert_eq!(engine.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+/// Checks that values of a megabyte are stored and overwritten intact.
+pub fn large_values<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    for round in 0..4u8 {
+        for key_id in 0..4 {
+            let value = char::from(b'a' + round + key_id)
+                .to_string()
+                .repeat(LARGE_VALUE_LEN);
+            engine.set(format!("key{}", key_id), value)?;
+        }
+    }
+    for key_id in 0..4u8 {
+        let value = engine
+            .get(format!("key{}", key_id))?
+            .expect("large value is missing");
+        assert_eq!(value.len(), LARGE_VALUE_LEN);
+        assert!(value.bytes().all(|b| b == b'a' + 3 + key_id));
+    }
+    Ok(())
+}
+
+/// Checks that many keys can be stored, read back and listed.
+pub fn many_keys<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    for key_id in 0..MANY_KEYS {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..MANY_KEYS {
+        assert_eq!(
+            engine.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(engine.keys()?.len(), MANY_KEYS);
+    Ok(())
+}
+
+/// Checks that an engine shared between threads behind a `Mutex` keeps every
+/// thread's writes.
+pub fn concurrent_access<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine + Send + 'static,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let engine = Arc::new(Mutex::new(open(&dir.0)?));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..KEYS_PER_THREAD {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    let mut engine = engine.lock().unwrap();
+                    engine.set(key.clone(), format!("value{}", key_id))?;
+                    assert_eq!(engine.get(key)?, Some(format!("value{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("conformance thread panicked")?;
+    }
+
+    let mut engine = engine.lock().unwrap();
+    assert_eq!(engine.keys()?.len(), THREADS * KEYS_PER_THREAD);
+    for thread_id in 0..THREADS {
+        for key_id in 0..KEYS_PER_THREAD {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks that stored values survive dropping and reopening the engine.
+pub fn persist_across_reopen<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(&dir.0)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.keys()?, vec!["key1".to_owned(), "key2".to_owned()]);
+    Ok(())
+}
+
+/// Checks that removed keys stay removed after reopening the engine.
+pub fn persist_removal_across_reopen<E, F>(open: &F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let dir = TestDir::new()?;
+    let mut engine = open(&dir.0)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(&dir.0)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.keys()?, vec!["key2".to_owned()]);
+    Ok(())
+}
@@ -2,45 +2,60 @@ use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use super::KvsEngine;
+use super::vfs::{StdFs, Vfs, VfsFile};
+use super::{EngineStats, KvsEngine};
 use crate::{KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+/// The file a repair writes the new generation to before renaming it.
+const REPAIR_FILE: &str = "repair.tmp";
+
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
+/// The log files are accessed through a `Vfs`, which is the real filesystem
+/// unless the store is opened with `KvStore::open_with`.
+///
 /// ```rust
-/// # use kvs::{KvStore, Result};
+/// # use kvs::{KvStore, KvsEngine, Result};
 /// # fn try_main() -> Result<()> {
-/// use std::env::current_dir;
-/// let mut store = KvStore::open(current_dir()?)?;
+/// # let temp_dir = tempfile::TempDir::new()?;
+/// let mut store = KvStore::open(temp_dir.path())?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
 /// # Ok(())
 /// # }
 /// ```
-pub struct KvStore {
+pub struct KvStore<V: Vfs = StdFs> {
+    // the filesystem holding the logs.
+    vfs: V,
     // directory for the log and other data.
     path: PathBuf,
     // map generation number to the file reader.
-    readers: HashMap<u64, BufReaderWithPos<File>>,
+    readers: HashMap<u64, BufReaderWithPos<V::File>>,
     // writer of the current log.
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<V::File>,
     current_file: u64,
     index: BTreeMap<String, CommandPos>,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     uncompacted: u64,
+    // compact once `uncompacted` exceeds this many bytes.
+    compaction_threshold: u64,
+    // the number of compactions run and the time they took.
+    compactions: u64,
+    compaction_time: Duration,
 }
 
 impl KvStore {
@@ -52,36 +67,172 @@ impl KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(StdFs, path)
+    }
+
+    /// Returns the generation numbers of the log files in `path`, in ascending order.
+    pub fn generations(path: &Path) -> Result<Vec<u64>> {
+        sorted_file_list(&StdFs, path)
+    }
+
+    /// Reads every record of the log file with the given generation number.
+    ///
+    /// Unlike `open`, reading does not stop at the first bad record: each run of
+    /// bytes that cannot be deserialized is reported as `LogRecord::Corrupt` and
+    /// reading resumes at the next position that parses as a command.
+    pub fn read_generation(path: &Path, file_id: u64) -> Result<Vec<LogRecord>> {
+        read_generation(&StdFs, path, file_id)
+    }
+
+    /// Checks the log files in `path` without modifying them.
+    ///
+    /// Every generation is read with `read_generation`, then the index is
+    /// rebuilt the same way `open` does and every indexed value is read back.
+    pub fn verify(path: &Path) -> Result<VerifyReport> {
+        let vfs = StdFs;
+        let generations = sorted_file_list(&vfs, path)?;
+        let mut records = 0;
+        let mut corrupt = Vec::new();
+        for &file_id in &generations {
+            for record in read_generation(&vfs, path, file_id)? {
+                match record {
+                    LogRecord::Valid { .. } => records += 1,
+                    LogRecord::Corrupt { .. } => corrupt.push(record),
+                }
+            }
+        }
+
+        let mut index = BTreeMap::new();
+        let mut readers = HashMap::new();
+        let mut index_error = None;
+        for &file_id in &generations {
+            let mut reader = BufReaderWithPos::new(vfs.open(&log_path(path, file_id))?)?;
+            if let Err(e) = load(file_id, &mut reader, &mut index) {
+                index_error = Some(format!("{}: {}", log_path(path, file_id).display(), e));
+                break;
+            }
+            readers.insert(file_id, reader);
+        }
+        if index_error.is_none() {
+            for (key, cmd_pos) in &index {
+                if let Err(e) = read_value(&mut readers, cmd_pos) {
+                    index_error = Some(format!("value of key '{}': {}", key, e));
+                    break;
+                }
+            }
+        }
+
+        Ok(VerifyReport {
+            generations,
+            records,
+            corrupt,
+            live_keys: index.len(),
+            index_error,
+        })
+    }
+
+    /// Salvages every readable record in `path` into a fresh generation.
+    ///
+    /// See `KvStore::repair_with`.
+    pub fn repair(path: &Path) -> Result<RepairReport> {
+        KvStore::repair_with(StdFs, path)
+    }
+}
+
+impl<V: Vfs> KvStore<V> {
+    /// Opens a `KvStore` with the given path on the given filesystem.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with(vfs: V, path: impl Into<PathBuf>) -> Result<KvStore<V>> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        vfs.create_dir_all(&path)?;
 
         let mut readers = HashMap::new();
         let mut index = BTreeMap::new();
 
-        let file_list = sorted_file_list(&path)?;
+        let file_list = sorted_file_list(&vfs, &path)?;
         let mut uncompacted = 0;
 
         for &file_id in &file_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, file_id))?)?;
+            let mut reader = BufReaderWithPos::new(vfs.open(&log_path(&path, file_id))?)?;
             uncompacted += load(file_id, &mut reader, &mut index)?;
             readers.insert(file_id, reader);
         }
 
         let current_file = file_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_file, &mut readers)?;
+        let writer = new_log_file(&vfs, &path, current_file, &mut readers)?;
 
         Ok(KvStore {
+            vfs,
             path,
             readers,
             writer,
             current_file,
             index,
             uncompacted,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compactions: 0,
+            compaction_time: Duration::ZERO,
         })
     }
 
+    /// Salvages every readable record in `path` on `vfs` into a fresh
+    /// generation.
+    ///
+    /// The readable records are rewritten in their original order to a
+    /// temporary file, `repair.tmp`, which is synced and then renamed to the new generation
+    /// before the old generations are deleted. Until the rename, `open` still
+    /// sees only the old generations; after it, the new one holds every
+    /// record the old ones held. An interrupted repair can therefore simply be
+    /// run again.
+    pub fn repair_with(vfs: V, path: &Path) -> Result<RepairReport> {
+        let generations = sorted_file_list(&vfs, path)?;
+        let generation = generations.last().unwrap_or(&0) + 1;
+        let mut report = RepairReport {
+            generation,
+            salvaged: 0,
+            dropped: 0,
+            dropped_bytes: 0,
+        };
+
+        let tmp_path = path.join(REPAIR_FILE);
+        match vfs.remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut writer = BufWriter::new(vfs.create_new(&tmp_path)?);
+        for &file_id in &generations {
+            for record in read_generation(&vfs, path, file_id)? {
+                match record {
+                    LogRecord::Valid { command, .. } => {
+                        serde_json::to_writer(&mut writer, &command)?;
+                        report.salvaged += 1;
+                    }
+                    LogRecord::Corrupt { len, .. } => {
+                        report.dropped += 1;
+                        report.dropped_bytes += len;
+                    }
+                }
+            }
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync()?;
+        vfs.rename(&tmp_path, &log_path(path, generation))?;
+        vfs.sync_dir(path)?;
+
+        for file_id in generations {
+            vfs.remove_file(&log_path(path, file_id))?;
+        }
+        Ok(report)
+    }
+
     /// Clears stale entries in the log.
     pub fn compact(&mut self) -> Result<()> {
+        let started = Instant::now();
         // increase current gen by 2. current_gen + 1 is for the compaction file.
         let compaction_file = self.current_file + 1;
         self.current_file += 2;
@@ -94,7 +245,7 @@ impl KvStore {
             let reader = self
                 .readers
                 .get_mut(&cmd_pos.file_id)
-                .expect("Cannot find log reader");
+                .ok_or(KvsError::MissingLog(cmd_pos.file_id))?;
             if reader.pos != cmd_pos.pos {
                 reader.seek(SeekFrom::Start(cmd_pos.pos))?;
             }
@@ -105,6 +256,9 @@ impl KvStore {
             new_pos += len;
         }
         compaction_writer.flush()?;
+        // the compacted data must be durable before the only other copy of it
+        // is deleted.
+        compaction_writer.writer.get_ref().sync()?;
 
         // remove stale log files.
         let stale_files: Vec<_> = self
@@ -115,22 +269,50 @@ impl KvStore {
             .collect();
         for stale_file in stale_files {
             self.readers.remove(&stale_file);
-            fs::remove_file(log_path(&self.path, stale_file))?;
+            self.vfs.remove_file(&log_path(&self.path, stale_file))?;
         }
+        // The removals must be durable, otherwise the stale logs may come
+        // back after a crash.
+        self.vfs.sync_dir(&self.path)?;
         self.uncompacted = 0;
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
 
         Ok(())
     }
 
+    /// Sets how many bytes of stale commands may pile up before the log is
+    /// compacted automatically. The default is 1 MiB.
+    pub fn set_compaction_threshold(&mut self, bytes: u64) {
+        self.compaction_threshold = bytes;
+    }
+
     /// Create a new log file with given generation number and add the reader to the readers map.
     ///
     /// Returns the writer to the log.
-    fn new_log_file(&mut self, file_id: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, file_id, &mut self.readers)
+    fn new_log_file(&mut self, file_id: u64) -> Result<BufWriterWithPos<V::File>> {
+        new_log_file(&self.vfs, &self.path, file_id, &mut self.readers)
+    }
+
+    /// Appends `cmd` to the current log.
+    ///
+    /// If that fails, part of the command may have reached the log. The store
+    /// then moves on to a new log file, so the torn command stays at the end of
+    /// the old log, where it is ignored on the next `open`.
+    fn append(&mut self, cmd: &Command) -> Result<()> {
+        let result = serde_json::to_writer(&mut self.writer, cmd)
+            .map_err(KvsError::from)
+            .and_then(|()| Ok(self.writer.flush()?));
+        if result.is_err() {
+            self.current_file += 1;
+            let writer = self.new_log_file(self.current_file)?;
+            mem::replace(&mut self.writer, writer).discard();
+        }
+        result
     }
 }
 
-impl KvsEngine for KvStore {
+impl<V: Vfs> KvsEngine for KvStore<V> {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
@@ -138,12 +320,12 @@ impl KvsEngine for KvStore {
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
+    #[allow(clippy::collapsible_if)]
     fn set(&mut self, key: String, value: String) -> Result<()> {
         let cmd = Command::set(key, value);
         let pos = self.writer.pos;
 
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+        self.append(&cmd)?;
 
         if let Command::Set { key, .. } = cmd {
             if let Some(old_cmd) = self
@@ -153,7 +335,7 @@ impl KvsEngine for KvStore {
                 self.uncompacted += old_cmd.len;
             }
         }
-        if self.uncompacted > COMPACTION_THRESHOLD {
+        if self.uncompacted > self.compaction_threshold {
             self.compact()?;
         }
         Ok(())
@@ -168,17 +350,7 @@ impl KvsEngine for KvStore {
     /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
     fn get(&mut self, key: String) -> Result<Option<String>> {
         if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.file_id)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
+            read_value(&mut self.readers, cmd_pos).map(Some)
         } else {
             Ok(None)
         }
@@ -194,10 +366,10 @@ impl KvsEngine for KvStore {
     fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
             let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
+            self.append(&cmd)?;
+            if let Command::Remove { key } = cmd
+                && let Some(old_cmd) = self.index.remove(&key)
+            {
                 self.uncompacted += old_cmd.len;
             }
             Ok(())
@@ -205,11 +377,55 @@ impl KvsEngine for KvStore {
             Err(KvsError::KeyNotFound)
         }
     }
+
+    /// Returns every key currently stored, in ascending order.
+    ///
+    /// The keys come straight from the in-memory index, so no log is read.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.index.keys().cloned().collect())
+    }
+
+    fn name(&self) -> &str {
+        "kvs"
+    }
+
+    /// Makes every command written so far durable.
+    ///
+    /// Commands are flushed to the filesystem as they are written, but they can
+    /// be lost in a power failure until the log is synced.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync()?;
+        Ok(())
+    }
+
+    /// Reports the live and stale bytes of the log along with the size of its
+    /// files, from the in-memory index and the file lengths, and the
+    /// compactions run.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut disk_bytes = 0;
+        for reader in self.readers.values_mut() {
+            disk_bytes += reader.seek(SeekFrom::End(0))?;
+        }
+        Ok(EngineStats {
+            keys: self.index.len() as u64,
+            live_bytes: self.index.values().map(|cmd_pos| cmd_pos.len).sum(),
+            stale_bytes: self.uncompacted,
+            generations: self.readers.len() as u64,
+            disk_bytes,
+            compactions: self.compactions,
+            compaction_time: self.compaction_time,
+        })
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvStore::compact(self)
+    }
 }
 
-fn load(
+fn load<R: Read + Seek>(
     file_id: u64,
-    reader: &mut BufReaderWithPos<File>,
+    reader: &mut BufReaderWithPos<R>,
     index: &mut BTreeMap<String, CommandPos>,
 ) -> Result<u64> {
     // To make sure we read from the beginning of the file.
@@ -218,7 +434,14 @@ fn load(
     let mut uncompacted = 0;
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // a command cut short by a crash or a failed write; it was never
+            // acknowledged, and nothing is written after it in this log.
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        match cmd {
             Command::Set { key, .. } => {
                 if let Some(old_cmd) = index.insert(key, (file_id, pos..new_pos).into()) {
                     uncompacted += old_cmd.len;
@@ -238,15 +461,76 @@ fn load(
     Ok(uncompacted)
 }
 
+/// Reads the value of the `Set` command at `cmd_pos`.
+fn read_value<R: Read + Seek>(
+    readers: &mut HashMap<u64, BufReaderWithPos<R>>,
+    cmd_pos: &CommandPos,
+) -> Result<String> {
+    let reader = readers
+        .get_mut(&cmd_pos.file_id)
+        .ok_or(KvsError::MissingLog(cmd_pos.file_id))?;
+    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    let cmd_reader = reader.take(cmd_pos.len);
+    if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+        Ok(value)
+    } else {
+        Err(KvsError::UnexpectedCommandType)
+    }
+}
+
+/// Reads every record of a log file, resuming after unreadable bytes.
+fn read_generation<V: Vfs>(vfs: &V, path: &Path, file_id: u64) -> Result<Vec<LogRecord>> {
+    let buf = vfs.read(&log_path(path, file_id))?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let mut stream = Deserializer::from_slice(&buf[pos..]).into_iter::<Command>();
+        match stream.next() {
+            None => break,
+            Some(Ok(command)) => {
+                let len = stream.byte_offset();
+                records.push(LogRecord::Valid {
+                    file_id,
+                    pos: pos as u64,
+                    len: len as u64,
+                    command,
+                });
+                pos += len;
+            }
+            Some(Err(e)) => {
+                let next = next_command_start(&buf, pos + 1);
+                records.push(LogRecord::Corrupt {
+                    file_id,
+                    pos: pos as u64,
+                    len: (next - pos) as u64,
+                    error: e.to_string(),
+                });
+                pos = next;
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Returns the first offset at or after `from` where a serialized command may
+/// start, or the length of `buf` if there is none.
+fn next_command_start(buf: &[u8], from: usize) -> usize {
+    const PREFIXES: [&[u8]; 2] = [b"{\"Set\"", b"{\"Remove\""];
+    (from..buf.len())
+        .find(|&i| PREFIXES.iter().any(|prefix| buf[i..].starts_with(prefix)))
+        .unwrap_or(buf.len())
+}
+
 fn log_path(dir: &Path, file_id: u64) -> PathBuf {
     dir.join(format!("{}.log", file_id))
 }
 
 /// Returns sorted generation numbers in the given directory.
-fn sorted_file_list(path: &Path) -> Result<Vec<u64>> {
-    let mut file_list: Vec<u64> = fs::read_dir(path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn sorted_file_list<V: Vfs>(vfs: &V, path: &Path) -> Result<Vec<u64>> {
+    let mut file_list: Vec<u64> = vfs
+        .list_files(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -262,14 +546,18 @@ fn sorted_file_list(path: &Path) -> Result<Vec<u64>> {
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
-fn new_log_file(
+fn new_log_file<V: Vfs>(
+    vfs: &V,
     path: &Path,
     file_id: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
-) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(path, file_id);
-    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(&path)?)?;
-    readers.insert(file_id, BufReaderWithPos::new(File::open(&path)?)?);
+    readers: &mut HashMap<u64, BufReaderWithPos<V::File>>,
+) -> Result<BufWriterWithPos<V::File>> {
+    let log_path = log_path(path, file_id);
+    let writer = BufWriterWithPos::new(vfs.open_append(&log_path)?)?;
+    // The new entry must be durable: syncing the log later is of no use if
+    // the log itself can vanish.
+    vfs.sync_dir(path)?;
+    readers.insert(file_id, BufReaderWithPos::new(vfs.open(&log_path)?)?);
     Ok(writer)
 }
 
@@ -289,6 +577,7 @@ pub struct BufReaderWithPos<R: Read + Seek> {
 impl<R: Read + Seek> BufReaderWithPos<R> {
     // Constructor that initializes the reader and position
     /// Creates a new `BufReaderWithPos` from a readable and seekable source.
+    #[allow(clippy::seek_from_current)]
     pub fn new(mut inner: R) -> Result<Self> {
         // Get the current position in the stream using SeekFrom::Current(0)
         let pos = inner.seek(SeekFrom::Current(0))?;
@@ -340,6 +629,7 @@ pub struct BufWriterWithPos<W: Write + Seek> {
 impl<W: Write + Seek> BufWriterWithPos<W> {
     // Constructor that initializes the writer and position
     /// Creates a new `BufWriterWithPos` from a writable and seekable source
+    #[allow(clippy::seek_from_current)]
     pub fn new(mut inner: W) -> Result<Self> {
         // Get the current position in the stream
         let pos = inner.seek(SeekFrom::Current(0))?;
@@ -349,6 +639,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+    /// Drops the writer without flushing the data still in its buffer.
+    pub fn discard(self) {
+        let _ = self.writer.into_parts();
+    }
 }
 
 // Implement the Write trait so BufWriterWithPos can be used to write data
@@ -381,6 +675,68 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     }
 }
 
+/// A record read back from a log file by `KvStore::read_generation`.
+#[derive(Debug)]
+pub enum LogRecord {
+    /// A command that was deserialized successfully.
+    Valid {
+        /// The generation number of the log file.
+        file_id: u64,
+        /// The byte offset of the record within the file.
+        pos: u64,
+        /// The length in bytes of the record.
+        len: u64,
+        /// The deserialized command.
+        command: Command,
+    },
+    /// A run of bytes that could not be deserialized as a command.
+    Corrupt {
+        /// The generation number of the log file.
+        file_id: u64,
+        /// The byte offset where the unreadable bytes start.
+        pos: u64,
+        /// The number of unreadable bytes.
+        len: u64,
+        /// The deserialization error.
+        error: String,
+    },
+}
+
+/// The outcome of `KvStore::verify`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// The generation numbers that were checked.
+    pub generations: Vec<u64>,
+    /// The number of readable records.
+    pub records: usize,
+    /// Every `LogRecord::Corrupt` found in the log files.
+    pub corrupt: Vec<LogRecord>,
+    /// The number of live keys in the rebuilt index.
+    pub live_keys: usize,
+    /// Why the index could not be rebuilt, if it could not.
+    pub index_error: Option<String>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.index_error.is_none()
+    }
+}
+
+/// The outcome of `KvStore::repair`.
+#[derive(Debug)]
+pub struct RepairReport {
+    /// The generation number the salvaged records were written to.
+    pub generation: u64,
+    /// The number of records that were salvaged.
+    pub salvaged: usize,
+    /// The number of unreadable byte runs that were dropped.
+    pub dropped: usize,
+    /// The total number of bytes that were dropped.
+    pub dropped_bytes: u64,
+}
+
 /// Represents the position of a command in a log file.
 ///
 /// `CommandPos` stores metadata about where a command is located,
@@ -445,7 +801,7 @@ impl Command {
     ///
     /// # Example
     ///
-    /// `` `
+    /// ```
     /// # use kvs::Command;
     /// let cmd = Command::set("name".to_string(), "Alice".to_string());
     /// ```
@@ -0,0 +1,67 @@
+use super::{EngineStats, KvsEngine};
+use crate::{KvsError, Result};
+use std::collections::BTreeMap;
+
+/// A `KvsEngine` that keeps every key/value pair in memory.
+///
+/// Nothing is written to disk, so the data is lost when the engine is dropped.
+/// This makes it a fast engine for tests and ephemeral caches.
+///
+/// ```rust
+/// # use kvs::{KvsEngine, MemoryKvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = MemoryKvsEngine::new();
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryKvsEngine {
+    map: BTreeMap<String, String>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.map.keys().cloned().collect())
+    }
+
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.map.len() as u64,
+            live_bytes: self
+                .map
+                .iter()
+                .map(|(key, value)| (key.len() + value.len()) as u64)
+                .sum(),
+            ..EngineStats::default()
+        })
+    }
+}
@@ -1,6 +1,8 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine {
@@ -20,10 +22,79 @@ pub trait KvsEngine {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Returns every key currently stored, in ascending order.
+    ///
+    /// Engines that cannot list their keys need not override it; listing
+    /// then fails, and so do the scans, migrations and statistics that rely
+    /// on it.
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Err(KvsError::StringError(format!(
+            "{} cannot list its keys",
+            self.name()
+        )))
+    }
+
+    /// Returns the name of the engine, which servers report to clients.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Makes every change written so far durable.
+    ///
+    /// Servers call this before shutting down. Engines that make every write
+    /// durable right away need not override it.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns statistics about the stored data, for `Stats` requests and
+    /// the metrics of a server.
+    ///
+    /// Only the number of keys is reported by default, from `keys`.
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.keys()?.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    /// Reclaims the space taken by stale data right away.
+    ///
+    /// Engines that keep no stale data, or reclaim it on their own, need not
+    /// override it.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Statistics about the data of an engine, as returned by
+/// `KvsEngine::stats`. Engines leave what they do not track at 0.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of keys stored.
+    pub keys: u64,
+    /// The bytes taken by the current values of the keys.
+    pub live_bytes: u64,
+    /// The bytes taken by overwritten or removed values that a compaction
+    /// would reclaim.
+    pub stale_bytes: u64,
+    /// The number of log files.
+    pub generations: u64,
+    /// The bytes taken on disk.
+    pub disk_bytes: u64,
+    /// The number of compactions run since the engine was opened.
+    pub compactions: u64,
+    /// The time those compactions took altogether.
+    pub compaction_time: Duration,
 }
 
 mod kvs;
+mod memory;
 mod sled;
+mod vfs;
 
-pub use self::kvs::KvStore;
+pub use self::kvs::{Command, KvStore, LogRecord, RepairReport, VerifyReport};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
+pub use self::vfs::{SimFault, SimFile, SimFs, StdFs, Vfs, VfsFile};
@@ -1,4 +1,4 @@
-use super::KvsEngine;
+use super::{EngineStats, KvsEngine};
 use crate::{KvsError, Result};
 use sled::{Db, Tree};
 
@@ -36,4 +36,29 @@ impl KvsEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let tree: &Tree = &self.0;
+        tree.iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn name(&self) -> &str {
+        "sled"
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.0.len() as u64,
+            disk_bytes: self.0.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
 }
@@ -0,0 +1,470 @@
+//! The filesystem operations `KvStore` relies on, behind a trait.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A filesystem that `KvStore` can keep its log files on.
+///
+/// `StdFs` is the real filesystem. `SimFs` keeps files in memory and can inject
+/// errors or simulate a power loss, which makes failure handling testable.
+pub trait Vfs: Clone {
+    /// The handle type for opened files.
+    type File: VfsFile;
+
+    /// Recursively creates a directory and all of its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the regular files directly inside `dir`.
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn open_append(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Creates a new file for writing, failing if it already exists.
+    fn create_new(&self, path: &Path) -> io::Result<Self::File>;
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Renames a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Makes the creation, removal and renaming of files in `dir` durable.
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+
+    /// Reads the whole content of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// A file opened through a `Vfs`.
+pub trait VfsFile: Read + Write + Seek {
+    /// Makes everything written to the file so far durable.
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// The real filesystem, through `std::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdFs;
+
+impl Vfs for StdFs {
+    type File = File;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<File> {
+        OpenOptions::new().create_new(true).write(true).open(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+impl VfsFile for File {
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// A fault that `SimFs` injects until `SimFs::clear_faults` is called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimFault {
+    /// Writes fail with `StorageFull` once `budget` more bytes have been
+    /// written. The write that crosses the limit is cut short, leaving a torn
+    /// write behind.
+    NoSpace {
+        /// The number of bytes that can still be written.
+        budget: u64,
+    },
+    /// Syncing a file or a directory fails.
+    SyncFailure,
+    /// Removing a file fails.
+    RemoveFailure,
+}
+
+/// An in-memory filesystem for testing how `KvStore` copes with failures.
+///
+/// Clones share the same files, so a test can keep one clone to inject faults
+/// and simulate power loss while a `KvStore` works on another.
+///
+/// Written data is visible to readers right away, like in the page cache of a
+/// real filesystem, but only becomes durable when its file is synced.
+/// Likewise, creating, removing and renaming files is visible right away but
+/// only becomes durable when the directory holding them is synced.
+/// `power_loss` throws away everything that is not durable: unsynced data is
+/// cut off, and directory entries that were not synced are rolled back.
+#[derive(Clone, Debug, Default)]
+pub struct SimFs {
+    state: Arc<Mutex<SimState>>,
+}
+
+#[derive(Debug, Default)]
+struct SimState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, SimData>,
+    faults: Vec<SimFault>,
+    /// Directory operations that have not been synced yet, oldest first.
+    unsynced: Vec<DirOp>,
+}
+
+/// A change to a directory, with what it takes to roll it back.
+#[derive(Debug)]
+enum DirOp {
+    Create {
+        path: PathBuf,
+    },
+    Remove {
+        path: PathBuf,
+        data: SimData,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        replaced: Option<SimData>,
+    },
+}
+
+impl DirOp {
+    /// Whether syncing `dir` makes this operation durable.
+    ///
+    /// A rename is durable once the directory it renamed into is synced.
+    fn is_in(&self, dir: &Path) -> bool {
+        let path = match self {
+            DirOp::Create { path } | DirOp::Remove { path, .. } => path,
+            DirOp::Rename { to, .. } => to,
+        };
+        path.parent() == Some(dir)
+    }
+
+    fn undo(self, files: &mut BTreeMap<PathBuf, SimData>) {
+        match self {
+            DirOp::Create { path } => {
+                files.remove(&path);
+            }
+            DirOp::Remove { path, data } => {
+                files.insert(path, data);
+            }
+            DirOp::Rename { from, to, replaced } => {
+                if let Some(data) = files.remove(&to) {
+                    files.insert(from, data);
+                }
+                if let Some(data) = replaced {
+                    files.insert(to, data);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SimData {
+    content: Vec<u8>,
+    durable_len: usize,
+}
+
+impl SimFs {
+    /// Creates an empty `SimFs`.
+    pub fn new() -> SimFs {
+        SimFs::default()
+    }
+
+    /// Injects a fault into all matching operations from now on.
+    pub fn inject(&self, fault: SimFault) {
+        self.lock().faults.push(fault);
+    }
+
+    /// Removes every injected fault.
+    pub fn clear_faults(&self) {
+        self.lock().faults.clear();
+    }
+
+    /// Simulates a power loss by dropping all data that was not synced.
+    ///
+    /// Handles opened before the power loss keep working on the remaining
+    /// data, but a test should reopen everything afterwards, as a restarted
+    /// program would.
+    pub fn power_loss(&self) {
+        let mut state = self.lock();
+        let state = &mut *state;
+        while let Some(op) = state.unsynced.pop() {
+            op.undo(&mut state.files);
+        }
+        for data in state.files.values_mut() {
+            let durable_len = data.durable_len;
+            data.content.truncate(durable_len);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().expect("SimFs state poisoned")
+    }
+
+    fn has_fault(&self, fault: SimFault) -> bool {
+        self.lock().faults.contains(&fault)
+    }
+
+    fn handle(&self, path: &Path, append: bool) -> SimFile {
+        SimFile {
+            fs: self.clone(),
+            path: path.to_path_buf(),
+            pos: 0,
+            append,
+        }
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl SimState {
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) if !self.dirs.contains(dir) => Err(not_found(dir)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Vfs for SimFs {
+    type File = SimFile;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        for dir in path.ancestors() {
+            state.dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<SimFile> {
+        if !self.lock().files.contains_key(path) {
+            return Err(not_found(path));
+        }
+        Ok(self.handle(path, false))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<SimFile> {
+        let mut state = self.lock();
+        state.check_parent(path)?;
+        if !state.files.contains_key(path) {
+            state.files.insert(path.to_path_buf(), SimData::default());
+            state.unsynced.push(DirOp::Create {
+                path: path.to_path_buf(),
+            });
+        }
+        drop(state);
+        Ok(self.handle(path, true))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<SimFile> {
+        let mut state = self.lock();
+        state.check_parent(path)?;
+        if state.files.contains_key(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        state.files.insert(path.to_path_buf(), SimData::default());
+        state.unsynced.push(DirOp::Create {
+            path: path.to_path_buf(),
+        });
+        drop(state);
+        Ok(self.handle(path, true))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        if self.has_fault(SimFault::RemoveFailure) {
+            return Err(io::Error::other("injected remove failure"));
+        }
+        let mut state = self.lock();
+        let data = state.files.remove(path).ok_or_else(|| not_found(path))?;
+        state.unsynced.push(DirOp::Remove {
+            path: path.to_path_buf(),
+            data,
+        });
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check_parent(to)?;
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        let replaced = state.files.insert(to.to_path_buf(), data);
+        state.unsynced.push(DirOp::Rename {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            replaced,
+        });
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        if self.has_fault(SimFault::SyncFailure) {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        let mut state = self.lock();
+        if !state.dirs.contains(dir) {
+            return Err(not_found(dir));
+        }
+        state.unsynced.retain(|op| !op.is_in(dir));
+        Ok(())
+    }
+}
+
+/// A file handle of `SimFs`.
+///
+/// Reads see the current content of the file, including data that has been
+/// written through other handles since this one was opened.
+#[derive(Debug)]
+pub struct SimFile {
+    fs: SimFs,
+    path: PathBuf,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for SimFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.fs.lock();
+        let data = state
+            .files
+            .get(&self.path)
+            .ok_or_else(|| not_found(&self.path))?;
+        let start = (self.pos as usize).min(data.content.len());
+        let len = buf.len().min(data.content.len() - start);
+        buf[..len].copy_from_slice(&data.content[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for SimFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.append {
+            return Err(io::Error::other("file is not opened for writing"));
+        }
+        let mut state = self.fs.lock();
+        let mut len = buf.len();
+        for fault in &mut state.faults {
+            if let SimFault::NoSpace { budget } = fault {
+                if *budget == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::StorageFull,
+                        "injected out of space",
+                    ));
+                }
+                len = len.min(*budget as usize);
+                *budget -= len as u64;
+            }
+        }
+        let data = state
+            .files
+            .get_mut(&self.path)
+            .ok_or_else(|| not_found(&self.path))?;
+        data.content.extend_from_slice(&buf[..len]);
+        self.pos = data.content.len() as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SimFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = {
+            let state = self.fs.lock();
+            let data = state
+                .files
+                .get(&self.path)
+                .ok_or_else(|| not_found(&self.path))?;
+            data.content.len() as u64
+        };
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for SimFile {
+    fn sync(&self) -> io::Result<()> {
+        if self.fs.has_fault(SimFault::SyncFailure) {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        let mut state = self.fs.lock();
+        let data = state
+            .files
+            .get_mut(&self.path)
+            .ok_or_else(|| not_found(&self.path))?;
+        data.durable_len = data.content.len();
+        Ok(())
+    }
+}
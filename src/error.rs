@@ -1,6 +1,10 @@
+// The `Fail` derive expands to impls nested inside anonymous constants.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 /// Error type for kvs.
 #[derive(Fail, Debug)]
@@ -18,12 +22,63 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// The index refers to a log file that is not open.
+    /// It indicates a corrupted log directory or a program bug.
+    #[fail(display = "Log file of generation {} not found", _0)]
+    MissingLog(u64),
+    /// Binary serialization or deserialization error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// A message on the wire is larger than the configured maximum frame size.
+    #[fail(display = "Message exceeds the maximum frame size of {} bytes", _0)]
+    FrameTooLarge(usize),
+    /// The client and the server speak different protocol versions.
+    #[fail(
+        display = "Protocol version mismatch: client speaks version {}, but kvs-server {} speaks version {}",
+        client, server_version, server
+    )]
+    ProtocolMismatch {
+        /// The protocol version of the client.
+        client: u32,
+        /// The protocol version of the server.
+        server: u32,
+        /// The version of the server.
+        server_version: String,
+    },
+    /// A client speaking RESP sent something that is not valid RESP.
+    #[fail(display = "Protocol error: {}", _0)]
+    RespProtocol(String),
+    /// A client of the HTTP gateway sent something that is not valid HTTP.
+    #[fail(display = "Malformed HTTP request: {}", _0)]
+    MalformedHttp(String),
+    /// A TLS error, such as a certificate that does not verify.
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(#[cause] rustls::Error),
+    /// A certificate, key or server name that TLS cannot be set up with.
+    #[fail(display = "Invalid TLS configuration: {}", _0)]
+    TlsConfig(String),
+    /// An ACL file that cannot be parsed.
+    #[fail(display = "Invalid ACL: {}", _0)]
+    InvalidAcl(String),
+    /// The credentials of a client belong to no user.
+    #[fail(display = "Authentication failed: {}", _0)]
+    AuthenticationFailed(String),
+    /// The user of a client may not make a request.
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(String),
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// A connection or a request took longer than allowed.
+    #[fail(display = "Timed out {}", _0)]
+    Timeout(String),
+    /// A client went over its rate limit, and may retry after the given
+    /// time.
+    #[fail(display = "Throttled, retry after {:?}", _0)]
+    Throttled(Duration),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -41,6 +96,18 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(err)
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -0,0 +1,490 @@
+//! An HTTP/JSON gateway to the engine, for tools that only speak HTTP.
+//!
+//! - `GET /keys/{key}` returns `{"key": ..., "value": ...}`.
+//! - `PUT /keys/{key}` with a body of `{"value": ...}` sets the key.
+//! - `DELETE /keys/{key}` removes the key.
+//! - `GET /keys?prefix=&limit=` returns `{"keys": [...]}` in ascending order,
+//!   optionally only those starting with `prefix` and at most `limit` of them.
+//!
+//! A missing key is a 404, and every error comes with a body of
+//! `{"error": ...}`. Connections are kept alive unless the client asks
+//! otherwise.
+//!
+//! On a server with an ACL, requests authenticate with an `Authorization`
+//! header: `Bearer` with a token or `Basic` with a user name and password.
+//!
+//! The metrics endpoint of a server is served the same way, on an address of
+//! its own.
+
+use crate::metrics::Outcome;
+use crate::ratelimit::Access;
+use crate::{Acl, Credentials, KvsEngine, KvsError, Permission, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+/// The largest number of headers of one request.
+const MAX_HEADERS: usize = 64;
+
+const KEYS_PATH: &str = "/keys";
+
+const METRICS_PATH: &str = "/metrics";
+
+/// The content type of the Prometheus text format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// A parsed HTTP request.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    /// The value of the `Authorization` header.
+    authorization: Option<String>,
+    close: bool,
+}
+
+/// An HTTP response, ready to be encoded.
+#[derive(Debug)]
+pub(crate) struct HttpResponse {
+    status: u16,
+    allow: Option<&'static str>,
+    /// How long a throttled client should wait before retrying.
+    retry_after: Option<Duration>,
+    /// The content type and the body.
+    body: Option<(&'static str, String)>,
+    close: bool,
+}
+
+impl HttpResponse {
+    fn new(status: u16, body: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            allow: None,
+            retry_after: None,
+            body: Some(("application/json", body.to_string())),
+            close: false,
+        }
+    }
+
+    fn no_content() -> Self {
+        HttpResponse {
+            status: 204,
+            allow: None,
+            retry_after: None,
+            body: None,
+            close: false,
+        }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Self::new(status, json!({ "error": message.to_string() }))
+    }
+
+    /// The response to a request over the rate limit of its client, which
+    /// keeps the connection open.
+    fn throttled(err: &KvsError) -> Self {
+        let retry_after = match err {
+            KvsError::Throttled(wait) => Some(*wait),
+            _ => None,
+        };
+        HttpResponse {
+            retry_after,
+            ..Self::error(429, err)
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        HttpResponse {
+            allow: Some(allow),
+            ..Self::error(405, "Method not allowed")
+        }
+    }
+
+    /// Returns whether the connection is closed after this response.
+    pub(crate) fn closes(&self) -> bool {
+        self.close
+    }
+
+    /// How the request ended, for metrics.
+    pub(crate) fn outcome(&self) -> Outcome {
+        match self.status {
+            200..=299 => Outcome::Ok,
+            401 | 403 => Outcome::Denied,
+            429 => Outcome::Throttled,
+            _ => Outcome::Error,
+        }
+    }
+
+    /// The message of an error response, for the audit log.
+    pub(crate) fn error_message(&self) -> Option<String> {
+        if self.status < 400 {
+            return None;
+        }
+        let (_, body) = self.body.as_ref()?;
+        let body: serde_json::Value = serde_json::from_str(body).ok()?;
+        body["error"].as_str().map(str::to_owned)
+    }
+
+    /// Appends the encoding of the response to `out`.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            429 => "Too Many Requests",
+            _ => "Internal Server Error",
+        };
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        if let Some(allow) = self.allow {
+            head += &format!("Allow: {}\r\n", allow);
+        }
+        if let Some(retry_after) = self.retry_after {
+            let seconds = retry_after.as_millis().div_ceil(1000);
+            head += &format!("Retry-After: {}\r\n", seconds);
+        }
+        if self.status == 401 {
+            head += "WWW-Authenticate: Basic realm=\"kvs\"\r\n";
+            head += "WWW-Authenticate: Bearer realm=\"kvs\"\r\n";
+        }
+        if self.close {
+            head += "Connection: close\r\n";
+        }
+        if let Some((content_type, body)) = &self.body {
+            head += &format!("Content-Type: {}\r\n", content_type);
+            head += &format!("Content-Length: {}\r\n", body.len());
+        }
+        head += "\r\n";
+        out.extend_from_slice(head.as_bytes());
+        if let Some((_, body)) = &self.body {
+            out.extend_from_slice(body.as_bytes());
+        }
+    }
+}
+
+/// Takes the next request off the front of `buf`.
+///
+/// Returns `None` if `buf` does not hold a complete request yet.
+///
+/// # Errors
+///
+/// It returns `KvsError::MalformedHttp` if `buf` does not hold a valid
+/// request, and `KvsError::FrameTooLarge` as soon as the request is known to
+/// be longer than `max_len` bytes.
+pub(crate) fn decode_request(buf: &mut Vec<u8>, max_len: usize) -> Result<Option<HttpRequest>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let head_len = match parsed.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) if buf.len() > max_len => {
+            return Err(KvsError::FrameTooLarge(max_len));
+        }
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(KvsError::MalformedHttp(e.to_string())),
+    };
+
+    let mut body_len = 0;
+    // HTTP/1.0 closes after every request unless asked to keep alive.
+    let mut close = parsed.version == Some(0);
+    let mut authorization = None;
+    for header in parsed.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("content-length") {
+            body_len = value
+                .trim()
+                .parse()
+                .map_err(|_| KvsError::MalformedHttp("invalid Content-Length".to_owned()))?;
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(KvsError::MalformedHttp(
+                "Transfer-Encoding is not supported".to_owned(),
+            ));
+        } else if header.name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value.into_owned());
+        } else if header.name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                close = true;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                close = false;
+            }
+        }
+    }
+    let len = head_len.saturating_add(body_len);
+    if len > max_len {
+        return Err(KvsError::FrameTooLarge(max_len));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    let request = HttpRequest {
+        method: parsed.method.unwrap_or_default().to_owned(),
+        target: parsed.path.unwrap_or_default().to_owned(),
+        body: buf[head_len..len].to_vec(),
+        authorization,
+        close,
+    };
+    buf.drain(..len);
+    Ok(Some(request))
+}
+
+/// The response to input that is not a valid request, after which the
+/// connection is closed.
+pub(crate) fn error_response(err: &KvsError) -> HttpResponse {
+    let status = match err {
+        KvsError::FrameTooLarge(_) => 413,
+        KvsError::Timeout(_) => 408,
+        _ => 400,
+    };
+    HttpResponse {
+        close: true,
+        ..HttpResponse::error(status, err)
+    }
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+    value: String,
+}
+
+/// Runs a request against the engine, if the ACL of the server allows it
+/// and `throttle` lets the user it authenticated as through, and returns the
+/// response to send back, with that user.
+pub(crate) fn handle<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    acl: Option<&Acl>,
+    request: HttpRequest,
+    throttle: impl FnOnce(Option<&str>) -> Result<()>,
+) -> (HttpResponse, Option<String>) {
+    let (response, user) = match acl.map(|acl| authenticate(acl, &request)).transpose() {
+        Ok(user) => match throttle(user.as_deref()) {
+            Ok(()) => (route(engine, acl.zip(user.as_ref()), &request), user),
+            Err(e) => (HttpResponse::throttled(&e), user),
+        },
+        Err(response) => (response, None),
+    };
+    let response = HttpResponse {
+        close: request.close,
+        ..response
+    };
+    (response, user)
+}
+
+/// The operation and the key of a `PUT` or a `DELETE` of a key, for the
+/// audit log.
+pub(crate) fn audited(request: &HttpRequest) -> Option<(&'static str, String)> {
+    let operation = match request.method.as_str() {
+        "PUT" => "set",
+        "DELETE" => "remove",
+        _ => return None,
+    };
+    let path = request.target.split('?').next().unwrap_or_default();
+    let key = path
+        .strip_prefix(KEYS_PATH)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|key| !key.is_empty())?;
+    let key = percent_decode_str(key).decode_utf8().ok()?;
+    Some((operation, key.into_owned()))
+}
+
+/// The type of `request`, for metrics: its method.
+pub(crate) fn request_kind(request: &HttpRequest) -> &'static str {
+    match request.method.as_str() {
+        "GET" => "get",
+        "PUT" => "put",
+        "DELETE" => "delete",
+        _ => "other",
+    }
+}
+
+/// Whether `request` reads or writes, for rate limits.
+pub(crate) fn request_access(request: &HttpRequest) -> Access {
+    match request.method.as_str() {
+        "PUT" | "DELETE" => Access::Write,
+        _ => Access::Read,
+    }
+}
+
+/// The lengths of the key and of the body of `request`, for the slow log.
+pub(crate) fn request_sizes(request: &HttpRequest) -> (usize, usize) {
+    let path = request.target.split('?').next().unwrap_or_default();
+    let key_len = path
+        .strip_prefix(KEYS_PATH)
+        .and_then(|key| key.strip_prefix('/'))
+        .map_or(0, str::len);
+    (key_len, request.body.len())
+}
+
+/// Answers a request to the metrics endpoint with the metrics `encode`
+/// renders.
+pub(crate) fn handle_metrics(
+    request: HttpRequest,
+    encode: impl FnOnce() -> String,
+) -> HttpResponse {
+    let response = if request.target != METRICS_PATH {
+        HttpResponse::error(404, "No such resource")
+    } else if request.method != "GET" {
+        HttpResponse::method_not_allowed("GET")
+    } else {
+        HttpResponse {
+            status: 200,
+            allow: None,
+            retry_after: None,
+            body: Some((METRICS_CONTENT_TYPE, encode())),
+            close: false,
+        }
+    };
+    HttpResponse {
+        close: request.close,
+        ..response
+    }
+}
+
+/// Returns the user the `Authorization` header of `request` belongs to.
+fn authenticate(acl: &Acl, request: &HttpRequest) -> std::result::Result<String, HttpResponse> {
+    let Some(authorization) = &request.authorization else {
+        return Err(HttpResponse::error(401, "Authentication required"));
+    };
+    let credentials = match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(Credentials::Token(token.trim().to_owned()))
+        }
+        Some((scheme, encoded)) if scheme.eq_ignore_ascii_case("basic") => BASE64
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (user, password) = decoded.split_once(':')?;
+                Some(Credentials::Password {
+                    user: user.to_owned(),
+                    password: password.to_owned(),
+                })
+            }),
+        _ => None,
+    };
+    credentials
+        .and_then(|credentials| acl.authenticate(&credentials).ok())
+        .ok_or_else(|| HttpResponse::error(401, "Authentication failed"))
+}
+
+/// Checks that `user` has `permission` on `key`, if the server has an ACL.
+fn authorize(
+    user: Option<(&Acl, &String)>,
+    permission: Permission,
+    key: &str,
+) -> std::result::Result<(), HttpResponse> {
+    match user {
+        Some((acl, user)) => acl
+            .check(Some(user), permission, key)
+            .map_err(|e| HttpResponse::error(403, e)),
+        None => Ok(()),
+    }
+}
+
+fn route<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    user: Option<(&Acl, &String)>,
+    request: &HttpRequest,
+) -> HttpResponse {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+
+    if path == KEYS_PATH {
+        return match request.method.as_str() {
+            "GET" => list_keys(engine, user, query),
+            _ => HttpResponse::method_not_allowed("GET"),
+        };
+    }
+    let Some(key) = path
+        .strip_prefix(KEYS_PATH)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|key| !key.is_empty())
+    else {
+        return HttpResponse::error(404, "No such resource");
+    };
+    let Ok(key) = percent_decode_str(key).decode_utf8() else {
+        return HttpResponse::error(400, "Keys must be valid UTF-8");
+    };
+    let key = key.into_owned();
+
+    let permission = match request.method.as_str() {
+        "GET" => Permission::Read,
+        _ => Permission::Write,
+    };
+    if let Err(response) = authorize(user, permission, &key) {
+        return response;
+    }
+    match request.method.as_str() {
+        "GET" => match engine.get(key.clone()) {
+            Ok(Some(value)) => HttpResponse::new(200, json!({ "key": key, "value": value })),
+            Ok(None) => HttpResponse::error(404, KvsError::KeyNotFound),
+            Err(e) => engine_error(e),
+        },
+        "PUT" => {
+            let body: SetBody = match serde_json::from_slice(&request.body) {
+                Ok(body) => body,
+                Err(e) => return HttpResponse::error(400, e),
+            };
+            match engine.set(key, body.value) {
+                Ok(()) => HttpResponse::no_content(),
+                Err(e) => engine_error(e),
+            }
+        }
+        "DELETE" => match engine.remove(key) {
+            Ok(()) => HttpResponse::no_content(),
+            Err(e) => engine_error(e),
+        },
+        _ => HttpResponse::method_not_allowed("GET, PUT, DELETE"),
+    }
+}
+
+/// `GET /keys?prefix=&limit=`
+fn list_keys<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    user: Option<(&Acl, &String)>,
+    query: &str,
+) -> HttpResponse {
+    let mut prefix = String::new();
+    let mut limit = usize::MAX;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match &*name {
+            "prefix" => prefix = value.into_owned(),
+            "limit" => match value.parse() {
+                Ok(value) => limit = value,
+                Err(_) => return HttpResponse::error(400, "limit must be a number"),
+            },
+            _ => {}
+        }
+    }
+    if let Err(response) = authorize(user, Permission::Read, &prefix) {
+        return response;
+    }
+    match engine.keys() {
+        Ok(keys) => {
+            let keys: Vec<String> = keys
+                .into_iter()
+                .filter(|key| key.starts_with(&prefix))
+                .take(limit)
+                .collect();
+            HttpResponse::new(200, json!({ "keys": keys }))
+        }
+        Err(e) => engine_error(e),
+    }
+}
+
+fn engine_error(err: KvsError) -> HttpResponse {
+    match err {
+        KvsError::KeyNotFound => HttpResponse::error(404, err),
+        _ => HttpResponse::error(500, err),
+    }
+}
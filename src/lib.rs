@@ -1,13 +1,42 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use async_client::AsyncKvsClient;
+pub use async_server::AsyncKvsServer;
+pub use audit::{AuditConfig, AuditEntry};
+pub use auth::{Acl, Credentials, Permission, hash_password, hash_token};
+pub use client::{ClientConfig, KvsClient, Pipeline};
+pub use codec::{Codec, DEFAULT_MAX_FRAME_LEN, PROTOCOL_VERSION, ServerInfo};
+pub use common::ServerStatus;
+pub use engines::{
+    Command, EngineStats, KvStore, KvsEngine, LogRecord, MemoryKvsEngine, RepairReport, SimFault,
+    SimFile, SimFs, SledKvsEngine, StdFs, VerifyReport, Vfs, VfsFile,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use ratelimit::{RateLimit, RateLimitConfig};
+pub use server::{KvsServer, Protocol, ServerConfig};
+pub use shutdown::ShutdownHandle;
+pub use slowlog::{SlowLogConfig, SlowLogEntry};
+pub use tls::{ClientTlsConfig, ServerTlsConfig};
+pub use transport::Address;
 
+pub mod conformance;
+
+mod async_client;
+mod async_server;
+mod audit;
+mod auth;
 mod client;
+mod codec;
 mod common;
 mod engines;
 mod error;
+mod http;
+mod metrics;
+mod ratelimit;
+mod resp;
 mod server;
+mod shutdown;
+mod slowlog;
+mod tls;
+mod transport;
@@ -0,0 +1,239 @@
+//! Metrics of a server, in the Prometheus text format.
+//!
+//! A server given `ServerConfig::metrics_addr` serves them at `GET /metrics`
+//! on that address. Requests are counted by protocol, type and result, and
+//! timed in a histogram by protocol and type. The numbers of the engine come
+//! from `KvsEngine::stats` at every scrape.
+
+use crate::EngineStats;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the buckets of the request duration histogram, in
+/// seconds.
+const DURATION_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// How a request ended, for the `result` label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Outcome {
+    Ok,
+    Error,
+    /// The ACL turned the request down.
+    Denied,
+    /// The client went over its rate limit.
+    Throttled,
+}
+
+impl Outcome {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Error => "error",
+            Outcome::Denied => "denied",
+            Outcome::Throttled => "throttled",
+        }
+    }
+}
+
+/// A histogram of durations.
+#[derive(Debug, Default)]
+struct Histogram {
+    /// The number of durations in each bucket, not cumulated.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// The request metrics of one protocol and request type.
+#[derive(Debug, Default)]
+struct RequestMetrics {
+    results: BTreeMap<Outcome, u64>,
+    duration: Histogram,
+}
+
+/// The counters and histograms a server updates as it serves.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// By protocol and request type.
+    requests: Mutex<BTreeMap<(&'static str, &'static str), RequestMetrics>>,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a request of `protocol` of the given type, answered with
+    /// `outcome` after `elapsed`.
+    pub(crate) fn request(
+        &self,
+        protocol: &'static str,
+        kind: &'static str,
+        outcome: Outcome,
+        elapsed: Duration,
+    ) {
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        let metrics = requests.entry((protocol, kind)).or_default();
+        *metrics.results.entry(outcome).or_default() += 1;
+        metrics.duration.observe(elapsed);
+    }
+
+    /// Counts bytes read from clients.
+    pub(crate) fn received(&self, len: usize) {
+        self.received_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes written to clients.
+    pub(crate) fn sent(&self, len: usize) {
+        self.sent_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Appends the metrics to `out` in the Prometheus text format.
+    pub(crate) fn encode(&self, out: &mut String) {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        header(
+            out,
+            "kvs_requests_total",
+            "counter",
+            "Requests answered, by protocol, type and result.",
+        );
+        for ((protocol, kind), metrics) in requests.iter() {
+            for (outcome, count) in &metrics.results {
+                let _ = writeln!(
+                    out,
+                    "kvs_requests_total{{protocol=\"{}\",type=\"{}\",result=\"{}\"}} {}",
+                    protocol,
+                    kind,
+                    outcome.label(),
+                    count
+                );
+            }
+        }
+        header(
+            out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "How long requests took to answer, by protocol and type.",
+        );
+        for ((protocol, kind), metrics) in requests.iter() {
+            let labels = format!("protocol=\"{}\",type=\"{}\"", protocol, kind);
+            let histogram = &metrics.duration;
+            let mut cumulated = 0;
+            for (le, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulated += count;
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, cumulated
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+        drop(requests);
+
+        sample(
+            out,
+            "kvs_received_bytes_total",
+            "counter",
+            "Bytes read from clients.",
+            self.received_bytes.load(Ordering::Relaxed),
+        );
+        sample(
+            out,
+            "kvs_sent_bytes_total",
+            "counter",
+            "Bytes written to clients.",
+            self.sent_bytes.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// Appends the gauges and counters of the engine to `out`.
+pub(crate) fn encode_engine_stats(stats: &EngineStats, out: &mut String) {
+    let samples = [
+        ("kvs_engine_keys", "gauge", "Keys stored.", stats.keys),
+        (
+            "kvs_engine_live_bytes",
+            "gauge",
+            "Bytes taken by the current values of the keys.",
+            stats.live_bytes,
+        ),
+        (
+            "kvs_engine_stale_bytes",
+            "gauge",
+            "Bytes a compaction would reclaim.",
+            stats.stale_bytes,
+        ),
+        (
+            "kvs_engine_log_files",
+            "gauge",
+            "Log files of the engine.",
+            stats.generations,
+        ),
+        (
+            "kvs_engine_disk_bytes",
+            "gauge",
+            "Bytes taken on disk.",
+            stats.disk_bytes,
+        ),
+        (
+            "kvs_engine_compactions_total",
+            "counter",
+            "Compactions run.",
+            stats.compactions,
+        ),
+    ];
+    for (name, kind, help, value) in samples {
+        sample(out, name, kind, help, value);
+    }
+    header(
+        out,
+        "kvs_engine_compaction_seconds_total",
+        "counter",
+        "Time spent compacting.",
+    );
+    let _ = writeln!(
+        out,
+        "kvs_engine_compaction_seconds_total {}",
+        stats.compaction_time.as_secs_f64()
+    );
+}
+
+/// Appends a metric with a single sample to `out`.
+pub(crate) fn sample(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
@@ -0,0 +1,170 @@
+//! Rate limits: token buckets that requests of a connection, and of the
+//! user it authenticated as, draw from.
+//!
+//! Reads and writes have budgets of their own, so that a client flooding the
+//! server with writes still leaves reads alone. A request over budget is
+//! answered with `KvsError::Throttled` and the time until it would fit.
+
+use crate::{KvsError, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many requests of a kind may be made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// The sustained rate, in requests per second.
+    pub per_second: f64,
+    /// How many requests may be made at once, after a quiet period. At
+    /// least 1.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// A limit of `per_second` requests per second, at most one second's
+    /// worth at once.
+    pub fn per_second(per_second: u32) -> Self {
+        RateLimit {
+            per_second: per_second.into(),
+            burst: per_second.max(1),
+        }
+    }
+}
+
+/// Settings of the rate limits of a server. Any limit may be left out.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateLimitConfig {
+    /// The reads each connection may make.
+    pub connection_reads: Option<RateLimit>,
+    /// The writes each connection may make.
+    pub connection_writes: Option<RateLimit>,
+    /// The reads each user may make, over all their connections.
+    pub user_reads: Option<RateLimit>,
+    /// The writes each user may make, over all their connections.
+    pub user_writes: Option<RateLimit>,
+}
+
+/// Whether a request reads or writes, which decides the budget it draws
+/// from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Access {
+    /// Any request that changes no key, admin requests included.
+    Read,
+    /// A request setting or removing keys.
+    Write,
+}
+
+/// The budgets of a connection or of a user.
+#[derive(Debug, Default)]
+pub(crate) struct Budgets {
+    reads: Option<TokenBucket>,
+    writes: Option<TokenBucket>,
+}
+
+impl Budgets {
+    fn bucket(&mut self, access: Access) -> &mut Option<TokenBucket> {
+        match access {
+            Access::Read => &mut self.reads,
+            Access::Write => &mut self.writes,
+        }
+    }
+}
+
+/// The rate limits of a running server, and the budgets of its users.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    users: Mutex<HashMap<String, Budgets>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a request of `access` out of the budgets of `connection` and of
+    /// `user`, if any.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Throttled` with the time until the request would
+    /// fit if either budget is spent, and then takes it out of neither.
+    pub(crate) fn check(
+        &self,
+        connection: &mut Budgets,
+        user: Option<&str>,
+        access: Access,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let (connection_limit, user_limit) = match access {
+            Access::Read => (self.config.connection_reads, self.config.user_reads),
+            Access::Write => (self.config.connection_writes, self.config.user_writes),
+        };
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let user_bucket = match (user, user_limit) {
+            (Some(user), Some(limit)) => Some((
+                users.entry(user.to_owned()).or_default().bucket(access),
+                limit,
+            )),
+            _ => None,
+        };
+        let connection_bucket = connection_limit.map(|limit| (connection.bucket(access), limit));
+        let mut buckets: Vec<_> = [connection_bucket, user_bucket]
+            .into_iter()
+            .flatten()
+            .map(|(bucket, limit)| {
+                let bucket = bucket.get_or_insert_with(|| TokenBucket::full(limit, now));
+                bucket.refill(limit, now);
+                (bucket, limit)
+            })
+            .collect();
+        let wait = buckets
+            .iter()
+            .map(|(bucket, limit)| bucket.wait(*limit))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(KvsError::Throttled(wait));
+        }
+        for (bucket, _) in &mut buckets {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+/// The requests left in a budget, refilled at the rate of its limit.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            tokens: limit.burst.into(),
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst.into());
+        self.refilled = now;
+    }
+
+    /// How long until a request fits, zero if it does now.
+    fn wait(&self, limit: RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else if limit.per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - self.tokens) / limit.per_second)
+        } else {
+            Duration::MAX
+        }
+    }
+}
@@ -0,0 +1,690 @@
+//! The Redis serialization protocol (RESP2), so that `redis-cli` and Redis
+//! client libraries can talk to a server run with `Protocol::Resp`.
+//!
+//! Commands are mapped onto `KvsEngine`. Key expiry is not something engines
+//! know about, so the server keeps the deadlines set by `EXPIRE` in memory and
+//! removes a key from the engine once it is found expired.
+
+use crate::metrics::Outcome;
+use crate::ratelimit::Access;
+use crate::{Acl, Credentials, KvsEngine, KvsError, Permission, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The largest number of arguments of one command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// How many keys `SCAN` returns when not given a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 value, as sent in replies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<RespValue>),
+}
+
+impl RespValue {
+    fn ok() -> Self {
+        RespValue::Simple("OK".to_owned())
+    }
+
+    fn bulk(s: impl Into<Vec<u8>>) -> Self {
+        RespValue::Bulk(Some(s.into()))
+    }
+
+    /// Appends the encoding of the value to `out`.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RespValue::Simple(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+            }
+            RespValue::Error(s) => {
+                out.push(b'-');
+                // A line break would end the error early.
+                out.extend(
+                    s.bytes()
+                        .map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }),
+                );
+            }
+            RespValue::Integer(n) => out.extend_from_slice(format!(":{}", n).as_bytes()),
+            RespValue::Bulk(None) => out.extend_from_slice(b"$-1"),
+            RespValue::Bulk(Some(bytes)) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            RespValue::Array(values) => {
+                out.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(out);
+                }
+                return;
+            }
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Takes the next command off the front of `buf`.
+///
+/// Commands are arrays of bulk strings, as sent by client libraries, or
+/// inline commands: a line of words separated by spaces, as typed into a
+/// telnet session. Returns `None` if `buf` does not hold a complete command
+/// yet.
+///
+/// # Errors
+///
+/// It returns `KvsError::RespProtocol` if `buf` does not hold valid RESP, and
+/// `KvsError::FrameTooLarge` as soon as the command is known to be longer
+/// than `max_len` bytes.
+pub(crate) fn decode_command(buf: &mut Vec<u8>, max_len: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some((command, len)) = parse_command(buf, max_len)? else {
+            return Ok(None);
+        };
+        buf.drain(..len);
+        // Empty commands are skipped, like Redis does.
+        if !command.is_empty() {
+            return Ok(Some(command));
+        }
+    }
+}
+
+/// Parses the command at the front of `buf` and returns it along with its
+/// length in bytes.
+fn parse_command(buf: &[u8], max_len: usize) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let Some(&first) = buf.first() else {
+        return Ok(None);
+    };
+    if first != b'*' {
+        let Some((line, len)) = parse_line(buf, max_len)? else {
+            return Ok(None);
+        };
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some((args, len)));
+    }
+
+    let Some((line, mut pos)) = parse_line(buf, max_len)? else {
+        return Ok(None);
+    };
+    let count = parse_len(&line[1..])?;
+    if count > MAX_ARGS as i64 {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count.clamp(0, 16) as usize);
+    for _ in 0..count {
+        let Some((line, len)) = parse_line(&buf[pos..], max_len)? else {
+            return Ok(None);
+        };
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error(&format!(
+                "expected '$', got '{}'",
+                line.first().map_or(' ', |&b| b as char)
+            )));
+        }
+        let bulk_len = parse_len(&line[1..])?;
+        if bulk_len < 0 {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let bulk_len = bulk_len as usize;
+        let start = pos + len;
+        if start.saturating_add(bulk_len) > max_len {
+            return Err(KvsError::FrameTooLarge(max_len));
+        }
+        if buf.len() < start + bulk_len + 2 {
+            return Ok(None);
+        }
+        if &buf[start + bulk_len..start + bulk_len + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string not followed by CRLF"));
+        }
+        args.push(buf[start..start + bulk_len].to_vec());
+        pos = start + bulk_len + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Parses the line at the front of `buf` and returns it without its line
+/// break, along with its length in bytes including the line break.
+fn parse_line(buf: &[u8], max_len: usize) -> Result<Option<(&[u8], usize)>> {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) if end >= max_len => Err(KvsError::FrameTooLarge(max_len)),
+        Some(end) => {
+            let line = &buf[..end];
+            Ok(Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1)))
+        }
+        None if buf.len() >= max_len => Err(KvsError::FrameTooLarge(max_len)),
+        None => Ok(None),
+    }
+}
+
+fn parse_len(digits: &[u8]) -> Result<i64> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::RespProtocol(message.to_owned())
+}
+
+/// The deadlines of keys given one with `EXPIRE`, shared by the connections
+/// of a server.
+///
+/// Deadlines only live as long as the server: after a restart, keys that
+/// were set to expire are kept.
+#[derive(Debug, Default)]
+pub(crate) struct Expiries(Mutex<HashMap<String, Instant>>);
+
+impl Expiries {
+    fn deadlines(&self) -> std::sync::MutexGuard<'_, HashMap<String, Instant>> {
+        // A panic cannot leave the map inconsistent, so a poisoned lock is
+        // still good to use.
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Removes `key` from `engine` if it has expired.
+    fn purge<E: KvsEngine + ?Sized>(&self, engine: &mut E, key: &str) -> Result<()> {
+        let mut deadlines = self.deadlines();
+        if deadlines.get(key).is_some_and(|&d| d <= Instant::now()) {
+            deadlines.remove(key);
+            remove_if_present(engine, key.to_owned())?;
+        }
+        Ok(())
+    }
+
+    /// Removes every expired key from `engine`.
+    fn purge_all<E: KvsEngine + ?Sized>(&self, engine: &mut E) -> Result<()> {
+        let now = Instant::now();
+        let mut deadlines = self.deadlines();
+        let expired: Vec<String> = deadlines
+            .iter()
+            .filter(|&(_, &d)| d <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            deadlines.remove(&key);
+            remove_if_present(engine, key)?;
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: String, deadline: Instant) {
+        self.deadlines().insert(key, deadline);
+    }
+
+    fn clear(&self, key: &str) {
+        self.deadlines().remove(key);
+    }
+
+    fn len(&self) -> usize {
+        self.deadlines().len()
+    }
+}
+
+/// Removes `key` from `engine`, returning whether it was there.
+fn remove_if_present<E: KvsEngine + ?Sized>(engine: &mut E, key: String) -> Result<bool> {
+    match engine.remove(key) {
+        Ok(()) => Ok(true),
+        Err(KvsError::KeyNotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The type of a command, for metrics: its name if it is supported.
+pub(crate) fn command_kind(args: &[Vec<u8>]) -> &'static str {
+    const KINDS: [&str; 9] = [
+        "get", "set", "del", "exists", "scan", "expire", "ping", "info", "auth",
+    ];
+    KINDS
+        .into_iter()
+        .find(|kind| args[0].eq_ignore_ascii_case(kind.as_bytes()))
+        .unwrap_or("other")
+}
+
+/// The lengths of the key and of the value of a command, for the slow log.
+pub(crate) fn command_sizes(args: &[Vec<u8>]) -> (usize, usize) {
+    let key_len = args.get(1).map_or(0, Vec::len);
+    let value_len = match args.get(2) {
+        Some(value) if args[0].eq_ignore_ascii_case(b"set") => value.len(),
+        _ => 0,
+    };
+    (key_len, value_len)
+}
+
+/// Whether a command reads or writes, for rate limits.
+pub(crate) fn command_access(args: &[Vec<u8>]) -> Access {
+    let writes = [&b"set"[..], b"del", b"expire"];
+    if writes.iter().any(|name| args[0].eq_ignore_ascii_case(name)) {
+        Access::Write
+    } else {
+        Access::Read
+    }
+}
+
+/// The operations and the keys of a `SET` or a `DEL`, for the audit log.
+pub(crate) fn audited(args: &[Vec<u8>]) -> Vec<(&'static str, String)> {
+    let (operation, keys) = if args[0].eq_ignore_ascii_case(b"set") {
+        ("set", args.get(1..2).unwrap_or_default())
+    } else if args[0].eq_ignore_ascii_case(b"del") {
+        ("remove", &args[1..])
+    } else {
+        return Vec::new();
+    };
+    keys.iter()
+        .map(|key| (operation, String::from_utf8_lossy(key).into_owned()))
+        .collect()
+}
+
+/// The message of an error reply, for the audit log.
+pub(crate) fn reply_error(reply: &RespValue) -> Option<String> {
+    match reply {
+        RespValue::Error(message) => Some(message.clone()),
+        _ => None,
+    }
+}
+
+/// The length of the value a reply returns, if any, for the slow log.
+pub(crate) fn reply_value_len(reply: &RespValue) -> Option<usize> {
+    match reply {
+        RespValue::Bulk(Some(value)) => Some(value.len()),
+        _ => None,
+    }
+}
+
+/// How a command ended, for metrics.
+pub(crate) fn outcome(reply: &RespValue) -> Outcome {
+    match reply {
+        RespValue::Error(message)
+            if ["NOAUTH", "NOPERM", "WRONGPASS"]
+                .iter()
+                .any(|code| message.starts_with(code)) =>
+        {
+            Outcome::Denied
+        }
+        RespValue::Error(message) if message.starts_with("THROTTLED") => Outcome::Throttled,
+        RespValue::Error(_) => Outcome::Error,
+        _ => Outcome::Ok,
+    }
+}
+
+/// The reply to input that is not a valid command.
+pub(crate) fn error_reply(err: &KvsError) -> RespValue {
+    RespValue::Error(format!("ERR {}", err))
+}
+
+/// The reply to a command over the rate limit of its client.
+pub(crate) fn throttled_reply(err: &KvsError) -> RespValue {
+    match err {
+        KvsError::Throttled(wait) => RespValue::Error(format!(
+            "THROTTLED retry after {} ms",
+            wait.as_micros().div_ceil(1000)
+        )),
+        err => error_reply(err),
+    }
+}
+
+/// Returns whether `args` is an `AUTH` command.
+pub(crate) fn is_auth(args: &[Vec<u8>]) -> bool {
+    args[0].eq_ignore_ascii_case(b"auth")
+}
+
+/// Checks a command against the ACL of the server, if it has one, and runs
+/// `AUTH`, which logs the connection in as `user`.
+///
+/// `AUTH token` authenticates with a token, and `AUTH user password` with a
+/// password. Returns the reply to send instead of running the command, if
+/// any.
+pub(crate) fn authorize(
+    acl: Option<&Acl>,
+    user: &mut Option<String>,
+    args: &[Vec<u8>],
+) -> Option<RespValue> {
+    if is_auth(args) {
+        return Some(auth(acl, user, &args[1..]));
+    }
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let acl = acl?;
+    let checks: Vec<(Permission, &[u8])> = match (name.as_str(), &args[1..]) {
+        ("get" | "exists", keys) => keys
+            .iter()
+            .map(|key| (Permission::Read, &key[..]))
+            .collect(),
+        ("set" | "expire", [key, ..]) => vec![(Permission::Write, key)],
+        ("del", keys) => keys
+            .iter()
+            .map(|key| (Permission::Write, &key[..]))
+            .collect(),
+        ("scan", [_, options @ ..]) => vec![(Permission::Read, scan_prefix(options))],
+        ("info", _) => vec![(Permission::Admin, b"")],
+        // PING, unknown commands and wrong numbers of arguments
+        _ => Vec::new(),
+    };
+    for (permission, key) in checks {
+        let checked = acl.check(user.as_deref(), permission, &String::from_utf8_lossy(key));
+        match (checked, &user) {
+            (Ok(()), _) => {}
+            (Err(_), None) => {
+                return Some(RespValue::Error(
+                    "NOAUTH Authentication required.".to_owned(),
+                ));
+            }
+            (Err(KvsError::PermissionDenied(reason)), Some(_)) => {
+                return Some(RespValue::Error(format!("NOPERM {}", reason)));
+            }
+            (Err(e), Some(_)) => return Some(RespValue::Error(format!("NOPERM {}", e))),
+        }
+    }
+    None
+}
+
+/// `AUTH [user] secret`
+fn auth(acl: Option<&Acl>, user: &mut Option<String>, args: &[Vec<u8>]) -> RespValue {
+    let Some(acl) = acl else {
+        return RespValue::Error(
+            "ERR AUTH called without any password configured for the default user".to_owned(),
+        );
+    };
+    let credentials = match args {
+        [token] => Credentials::Token(String::from_utf8_lossy(token).into_owned()),
+        [name, password] => Credentials::Password {
+            user: String::from_utf8_lossy(name).into_owned(),
+            password: String::from_utf8_lossy(password).into_owned(),
+        },
+        _ => {
+            return RespValue::Error("ERR wrong number of arguments for 'auth' command".to_owned());
+        }
+    };
+    match acl.authenticate(&credentials) {
+        Ok(name) => {
+            *user = Some(name);
+            RespValue::ok()
+        }
+        Err(_) => RespValue::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_owned(),
+        ),
+    }
+}
+
+/// The part of the `MATCH` pattern of `SCAN` options before its first
+/// wildcard, which every key returned starts with.
+fn scan_prefix(mut options: &[Vec<u8>]) -> &[u8] {
+    let mut prefix: &[u8] = b"";
+    while let [option, value, rest @ ..] = options {
+        if option.eq_ignore_ascii_case(b"match") {
+            let end = value
+                .iter()
+                .position(|b| matches!(b, b'*' | b'?' | b'\\'))
+                .unwrap_or(value.len());
+            prefix = &value[..end];
+        }
+        options = rest;
+    }
+    prefix
+}
+
+/// Runs a command against the engine and returns the reply to send back.
+pub(crate) fn execute<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    expiries: &Expiries,
+    args: Vec<Vec<u8>>,
+) -> RespValue {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    match run_command(engine, expiries, &name, &args[1..]) {
+        Ok(reply) => reply,
+        Err(CommandError::Arity) => RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        )),
+        Err(CommandError::Reply(message)) => RespValue::Error(format!("ERR {}", message)),
+        Err(CommandError::Engine(e)) => RespValue::Error(format!("ERR {}", e)),
+    }
+}
+
+enum CommandError {
+    Arity,
+    Reply(String),
+    Engine(KvsError),
+}
+
+impl From<KvsError> for CommandError {
+    fn from(err: KvsError) -> CommandError {
+        CommandError::Engine(err)
+    }
+}
+
+fn run_command<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    expiries: &Expiries,
+    name: &str,
+    args: &[Vec<u8>],
+) -> std::result::Result<RespValue, CommandError> {
+    match (name, args) {
+        ("ping", []) => Ok(RespValue::Simple("PONG".to_owned())),
+        ("ping", [message]) => Ok(RespValue::bulk(message.clone())),
+        ("get", [key]) => {
+            let key = utf8(key)?;
+            expiries.purge(engine, &key)?;
+            Ok(RespValue::Bulk(engine.get(key)?.map(String::into_bytes)))
+        }
+        ("set", [key, value, options @ ..]) => {
+            let deadline = match options {
+                [] => None,
+                [unit, amount] => {
+                    let amount = integer(amount)?;
+                    if amount <= 0 {
+                        return Err(invalid_expire_time("set"));
+                    }
+                    let amount = amount as u64;
+                    let ttl = match utf8(unit)?.to_ascii_lowercase().as_str() {
+                        "ex" => Duration::from_secs(amount),
+                        "px" => Duration::from_millis(amount),
+                        _ => return Err(syntax_error()),
+                    };
+                    Some(expire_after("set", ttl)?)
+                }
+                _ => return Err(syntax_error()),
+            };
+            let key = utf8(key)?;
+            engine.set(key.clone(), utf8(value)?)?;
+            match deadline {
+                Some(deadline) => expiries.set(key, deadline),
+                None => expiries.clear(&key),
+            }
+            Ok(RespValue::ok())
+        }
+        ("del", keys) if !keys.is_empty() => {
+            let mut removed = 0;
+            for key in keys {
+                let key = utf8(key)?;
+                expiries.purge(engine, &key)?;
+                expiries.clear(&key);
+                if remove_if_present(engine, key)? {
+                    removed += 1;
+                }
+            }
+            Ok(RespValue::Integer(removed))
+        }
+        ("exists", keys) if !keys.is_empty() => {
+            let mut found = 0;
+            for key in keys {
+                let key = utf8(key)?;
+                expiries.purge(engine, &key)?;
+                if engine.get(key)?.is_some() {
+                    found += 1;
+                }
+            }
+            Ok(RespValue::Integer(found))
+        }
+        ("expire", [key, seconds]) => {
+            let seconds = integer(seconds)?;
+            let key = utf8(key)?;
+            expiries.purge(engine, &key)?;
+            if engine.get(key.clone())?.is_none() {
+                return Ok(RespValue::Integer(0));
+            }
+            if seconds <= 0 {
+                expiries.clear(&key);
+                remove_if_present(engine, key)?;
+            } else {
+                let deadline = expire_after("expire", Duration::from_secs(seconds as u64))?;
+                expiries.set(key, deadline);
+            }
+            Ok(RespValue::Integer(1))
+        }
+        ("scan", [cursor, options @ ..]) => scan(engine, expiries, cursor, options),
+        ("info", [] | [_]) => info(engine, expiries),
+        ("ping" | "get" | "set" | "del" | "exists" | "expire" | "scan" | "info", _) => {
+            Err(CommandError::Arity)
+        }
+        _ => Err(CommandError::Reply(format!(
+            "unknown command '{}'",
+            name.escape_debug()
+        ))),
+    }
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor is the position in the sorted list of keys. Keys present for
+/// the whole scan are returned at least once; keys removed during the scan
+/// may make it skip keys that sort after them.
+fn scan<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    expiries: &Expiries,
+    cursor: &[u8],
+    mut options: &[Vec<u8>],
+) -> std::result::Result<RespValue, CommandError> {
+    let cursor = usize::try_from(integer(cursor)?)
+        .map_err(|_| CommandError::Reply("invalid cursor".to_owned()))?;
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let [option, value, rest @ ..] = options {
+        match utf8(option)?.to_ascii_lowercase().as_str() {
+            "match" => pattern = Some(value.as_slice()),
+            "count" => {
+                count = usize::try_from(integer(value)?)
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(syntax_error)?
+            }
+            _ => return Err(syntax_error()),
+        }
+        options = rest;
+    }
+    if !options.is_empty() {
+        return Err(syntax_error());
+    }
+
+    expiries.purge_all(engine)?;
+    let keys = engine.keys()?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let next = if end == keys.len() { 0 } else { end };
+    let batch = keys
+        .get(cursor..end)
+        .unwrap_or_default()
+        .iter()
+        .filter(|key| pattern.is_none_or(|p| glob_match(p, key.as_bytes())))
+        .map(|key| RespValue::bulk(key.as_str()))
+        .collect();
+    Ok(RespValue::Array(vec![
+        RespValue::bulk(next.to_string()),
+        RespValue::Array(batch),
+    ]))
+}
+
+fn info<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    expiries: &Expiries,
+) -> std::result::Result<RespValue, CommandError> {
+    expiries.purge_all(engine)?;
+    let keys = engine.keys()?.len();
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\nengine:{}\r\nprotocol:resp2\r\n\r\n\
+         # Keyspace\r\ndb0:keys={},expires={}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        engine.name(),
+        keys,
+        expiries.len()
+    );
+    Ok(RespValue::bulk(info))
+}
+
+/// Matches `s` against a glob `pattern` with `*`, `?` and `\` escapes, as
+/// `SCAN ... MATCH` does.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where to resume after the last `*`: the pattern after it and the next
+    // position in `s` for it to swallow.
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, i + 1));
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&s[i]) => {
+                p += 2;
+                i += 1;
+                continue;
+            }
+            Some(&c) if c != b'\\' && c == s[i] => {
+                p += 1;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((star_p, star_i)) => {
+                p = star_p;
+                i = star_i;
+                backtrack = Some((star_p, star_i + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn utf8(bytes: &[u8]) -> std::result::Result<String, CommandError> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| CommandError::Reply("keys and values must be valid UTF-8".to_owned()))
+}
+
+fn integer(bytes: &[u8]) -> std::result::Result<i64, CommandError> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| CommandError::Reply("value is not an integer or out of range".to_owned()))
+}
+
+fn syntax_error() -> CommandError {
+    CommandError::Reply("syntax error".to_owned())
+}
+
+fn invalid_expire_time(command: &str) -> CommandError {
+    CommandError::Reply(format!("invalid expire time in '{}' command", command))
+}
+
+/// When a key set by `command` to expire in `ttl` expires.
+fn expire_after(command: &str, ttl: Duration) -> std::result::Result<Instant, CommandError> {
+    Instant::now()
+        .checked_add(ttl)
+        .ok_or_else(|| invalid_expire_time(command))
+}
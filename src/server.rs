@@ -1,10 +1,139 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsEngine, Result};
+use crate::audit::AuditLog;
+use crate::codec::{CRATE_VERSION, DEFAULT_MAX_FRAME_LEN, ServerFraming, unexpected_eof};
+use crate::common::{
+    CompactResponse, GetResponse, InfoResponse, PingResponse, RemoveResponse, Request,
+    RequestFrame, Response, ResponseFrame, ServerStatus, SetResponse, SlowLogResponse,
+    StatsResponse,
+};
+use crate::http;
+use crate::metrics::{self, Metrics, Outcome};
+use crate::ratelimit::{Access, Budgets, RateLimiter};
+use crate::resp::{self, Expiries};
+use crate::slowlog::SlowLog;
+use crate::tls::SharedStream;
+use crate::transport::{Listener, Stream, read_len, timed_out, wait_readable};
+use crate::{
+    Acl, Address, AuditConfig, AuditEntry, KvsEngine, KvsError, RateLimitConfig, Result,
+    ServerTlsConfig, ShutdownHandle, SlowLogConfig, SlowLogEntry,
+};
 
-use log::{debug, error};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use log::{debug, error, warn};
+use rustls::{ServerConnection, StreamOwned};
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::debug_span;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
+/// The protocol a server speaks to its clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// The protocol of `KvsClient` and `AsyncKvsClient`.
+    #[default]
+    Kvs,
+    /// RESP2, the protocol of Redis, for `redis-cli` and Redis client
+    /// libraries. `GET`, `SET`, `DEL`, `EXISTS`, `SCAN`, `EXPIRE`, `PING` and
+    /// `INFO` are supported; other commands get an error reply.
+    Resp,
+}
+
+/// Settings of `KvsServer` and `AsyncKvsServer`.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// How long a shutdown waits for requests that are still being received
+    /// or processed before their connections are closed anyway.
+    pub shutdown_timeout: Duration,
+    /// The largest message a client may send, in bytes. A connection sending
+    /// a larger one is closed.
+    pub max_frame_len: usize,
+    /// The protocol spoken on every connection.
+    pub protocol: Protocol,
+    /// Where to serve an HTTP/JSON gateway to the same engine, if anywhere.
+    ///
+    /// It serves `GET`, `PUT` and `DELETE` on `/keys/{key}`, and lists keys
+    /// with `GET /keys?prefix=&limit=`.
+    pub http_addr: Option<SocketAddr>,
+    /// The TLS settings, if connections are to be encrypted. Plaintext is
+    /// the default, for local use.
+    ///
+    /// They apply to every listener, the HTTP gateway included.
+    pub tls: Option<ServerTlsConfig>,
+    /// The users allowed in and what they may do, if access is restricted.
+    /// Anyone who can connect may do anything by default.
+    ///
+    /// It applies to every protocol, the HTTP gateway included.
+    pub acl: Option<Acl>,
+    /// Where to serve metrics in the Prometheus text format, at
+    /// `GET /metrics`, if anywhere.
+    ///
+    /// The TLS settings apply to it, but not the ACL: the metrics hold no
+    /// keys or values.
+    pub metrics_addr: Option<SocketAddr>,
+    /// The settings of the slow log, if requests slower than a threshold
+    /// are to be logged.
+    pub slow_log: Option<SlowLogConfig>,
+    /// The settings of the audit log, if every set and remove is to be
+    /// logged, with who asked for it and how it ended.
+    pub audit_log: Option<AuditConfig>,
+    /// How many connections may be open at once, if there is a limit.
+    /// Connections past it are closed right away.
+    ///
+    /// `KvsServer` serves one connection at a time on each listener anyway;
+    /// the others wait to be accepted.
+    pub max_connections: Option<usize>,
+    /// How long a connection may go without a request before it is closed.
+    /// The default is 5 minutes.
+    pub idle_timeout: Option<Duration>,
+    /// How long a partly received request may go without more of it
+    /// arriving before its connection is closed. The default is 30 seconds.
+    pub read_timeout: Option<Duration>,
+    /// How long sending a response may take before its connection is
+    /// closed. The default is 30 seconds.
+    pub write_timeout: Option<Duration>,
+    /// How long a request may wait to be processed once received, if there
+    /// is a limit. Requests still waiting past it, behind other requests or
+    /// for the engine, are answered with `KvsError::Timeout` instead.
+    /// Requests being processed are not interrupted.
+    pub request_timeout: Option<Duration>,
+    /// The rate limits of connections and users, if any. Requests over them
+    /// are answered with `KvsError::Throttled` instead of being run.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            protocol: Protocol::default(),
+            http_addr: None,
+            tls: None,
+            acl: None,
+            metrics_addr: None,
+            slow_log: None,
+            audit_log: None,
+            max_connections: None,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+            request_timeout: None,
+            rate_limit: None,
+        }
+    }
+}
 
 /// A key-value store server that handles TCP connections and processes requests
 /// using a given storage engine implementing the `KvsEngine` trait.
@@ -12,68 +141,890 @@ use std::net::{TcpListener, TcpStream, ToSocketAddrs};
 /// The `KvsServer` listens for incoming client connections, deserializes
 /// requests, processes them through the engine, and serializes responses back
 /// to the client. It supports `GET`, `SET`, and `REMOVE` operations.
+///
+/// Each listener is served on a thread of its own, one connection at a time,
+/// so a connection to the HTTP gateway or the metrics endpoint is not held up
+/// by a client of the main protocol or the other way round. The threads share
+/// the engine.
+///
+/// The server runs until it is asked to stop through its `ShutdownHandle`.
 pub struct KvsServer<E: KvsEngine> {
-    engine: E,
+    engine: Mutex<E>,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
+    expiries: Expiries,
+    state: Arc<ServerState>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Creates a new `KvsServer` instance with the provided key-value storage engine.
     pub fn new(engine: E) -> Self {
-        Self { engine }
+        Self::with_config(engine, ServerConfig::default())
+    }
+
+    /// Creates a new `KvsServer` instance with the given settings.
+    pub fn with_config(engine: E, config: ServerConfig) -> Self {
+        Self {
+            engine: Mutex::new(engine),
+            state: Arc::new(ServerState::new(&config)),
+            config,
+            shutdown: ShutdownHandle::new(),
+            expiries: Expiries::default(),
+        }
     }
+
+    /// Returns a handle that stops the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     /// Starts the key-value server, binds to the given address, and handles incoming
-    /// client connections.
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            let stream = match stream {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Connection failed: {}", e);
+    /// client connections. The address is a TCP one or, written `unix:PATH`,
+    /// a Unix domain socket, which is removed again when the server returns.
+    ///
+    /// Once a shutdown is requested, the server stops accepting connections,
+    /// answers the requests it has received, syncs the engine and returns.
+    pub fn run<A: Into<Address>>(self, addr: A) -> Result<()>
+    where
+        E: Send,
+    {
+        // Each listener is paired with what it serves.
+        let main = (Listener::bind(&addr.into())?, Endpoint::Main);
+        let mut others = Vec::new();
+        if let Some(http_addr) = self.config.http_addr {
+            others.push((Listener::bind(&http_addr.into())?, Endpoint::Http));
+        }
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            others.push((Listener::bind(&metrics_addr.into())?, Endpoint::Metrics));
+        }
+        let server = &self;
+        thread::scope(|scope| {
+            let threads: Vec<_> = others
+                .into_iter()
+                .map(|listener| scope.spawn(move || server.accept(vec![listener])))
+                .collect();
+            let mut served = self.accept(vec![main]);
+            for thread in threads {
+                let thread_served = thread
+                    .join()
+                    .map_err(|_| KvsError::StringError("Listener thread panicked".to_owned()))?;
+                served = served.and(thread_served);
+            }
+            served
+        })?;
+        self.engine().sync()
+    }
+
+    /// Accepts connections on `listeners` and serves them one at a time,
+    /// until a shutdown is requested. If this fails, it requests a shutdown,
+    /// so that the other listeners stop too.
+    fn accept(&self, listeners: Vec<(Listener, Endpoint)>) -> Result<()> {
+        let accepted = self.accept_until_shutdown(listeners);
+        if accepted.is_err() {
+            self.shutdown.shutdown();
+        }
+        accepted
+    }
+
+    fn accept_until_shutdown(&self, listeners: Vec<(Listener, Endpoint)>) -> Result<()> {
+        // Waiting on the listeners and on the waker lets the loop notice a
+        // shutdown request.
+        let waker = self.shutdown.waker()?;
+        for (listener, _) in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        while !self.shutdown.is_shutdown() {
+            let fds: Vec<_> = listeners
+                .iter()
+                .map(|(listener, _)| listener.as_fd())
+                .chain([waker.as_fd()])
+                .collect();
+            let ready = wait_readable(&fds, None)?;
+            for ((listener, endpoint), ready) in listeners.iter().zip(ready) {
+                if !ready {
+                    continue;
+                }
+                let (stream, peer_addr) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        continue;
+                    }
+                };
+                let Some(_connection) = self.state.try_connect(&peer_addr) else {
                     continue;
+                };
+                let session = match endpoint {
+                    Endpoint::Main => self.session(),
+                    Endpoint::Http => Session::Http {
+                        closing: false,
+                        metrics: false,
+                    },
+                    Endpoint::Metrics => Session::Http {
+                        closing: false,
+                        metrics: true,
+                    },
+                };
+                let _span = debug_span!("connection", peer = %peer_addr).entered();
+                if let Err(e) = self.serve(stream, &peer_addr, session, &waker) {
+                    error!("Error serving client: {}", e);
                 }
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks the engine, which the threads serving the listeners share.
+    fn engine(&self) -> MutexGuard<'_, E> {
+        // A panic in the middle of a request leaves the engine as consistent
+        // as an error would, so a poisoned lock is still good to use.
+        self.engine.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Handles a single client connection over the given `Stream`.
+    fn serve(
+        &self,
+        stream: Stream,
+        peer_addr: &str,
+        session: Session,
+        waker: &UnixStream,
+    ) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        // Reads only block once the connection has something to read, until
+        // the rest of a TLS record arrives.
+        stream.set_read_timeout(Some(self.config.shutdown_timeout).filter(|t| !t.is_zero()))?;
+        stream.set_write_timeout(self.state.timeouts.write)?;
+        let mut waiter = Waiter {
+            stream: stream.try_clone()?,
+            waker,
+            tls: None,
+        };
+        match &self.config.tls {
+            Some(tls) => {
+                let stream = SharedStream::new(tls.accept(stream)?);
+                waiter.tls = Some(stream.clone());
+                self.serve_from(&peer_addr, stream.clone(), stream, session, Some(waiter))
+            }
+            None => {
+                let reader = stream.try_clone()?;
+                self.serve_from(&peer_addr, reader, stream, session, Some(waiter))
+            }
+        }
+    }
+
+    /// The session of a new connection speaking the configured protocol.
+    fn session(&self) -> Session {
+        match self.config.protocol {
+            Protocol::Kvs => Session::Kvs(ServerFraming::new(
+                self.config.max_frame_len,
+                self.engine().name(),
+                self.config.acl.clone(),
+            )),
+            Protocol::Resp => Session::Resp { user: None },
+        }
+    }
+
+    /// Handles the requests read from `reader` until it is exhausted, writing
+    /// the responses to `writer`.
+    ///
+    /// `run` does this for every accepted connection. Any byte stream will do
+    /// though, such as an in-memory pipe, which lets tests and fuzz targets
+    /// exercise the protocol handling without sockets.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Serde`, or `KvsError::RespProtocol` when speaking
+    /// RESP, if the stream does not hold valid requests,
+    /// `KvsError::Io` if it ends in the middle of a request, and propagates
+    /// I/O errors of `reader` and `writer`.
+    pub fn serve_stream<R: Read, W: Write>(&mut self, reader: R, writer: W) -> Result<()> {
+        let session = self.session();
+        let _connection = self.state.connect();
+        self.serve_from(&"stream", reader, writer, session, None)
+    }
+
+    fn serve_from<R: Read, W: Write>(
+        &self,
+        peer_addr: &dyn Display,
+        mut reader: R,
+        mut writer: W,
+        mut session: Session,
+        waiter: Option<Waiter<'_>>,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut replies = Replies {
+            received: Instant::now(),
+            out: Vec::new(),
+            answered: Vec::new(),
+        };
+        let mut budgets = Budgets::default();
+        let mut chunk = vec![0; READ_CHUNK_LEN];
+        let mut deadline = None;
+        // When bytes last arrived or a response was last sent.
+        let mut last_active = Instant::now();
+
+        loop {
+            let handled = match &mut session {
+                Session::Kvs(framing) => {
+                    self.handle_frames(framing, &mut buf, &mut replies, &mut budgets, peer_addr)
+                }
+                Session::Resp { user } => {
+                    self.handle_commands(&mut buf, &mut replies, &mut budgets, user, peer_addr)
+                }
+                Session::Http { closing, metrics } => self.handle_http(
+                    &mut buf,
+                    &mut replies,
+                    &mut budgets,
+                    closing,
+                    *metrics,
+                    peer_addr,
+                ),
             };
+            // Requests that arrived together are answered together.
+            let out = &mut replies.out;
+            if !out.is_empty() {
+                self.state.metrics.sent(out.len());
+                writer
+                    .write_all(out)
+                    .and_then(|()| writer.flush())
+                    .map_err(|e| timed_out(e.into(), "sending a response"))?;
+                out.clear();
+                last_active = Instant::now();
+            }
+            for trace in replies.answered.drain(..) {
+                self.state.finish(trace, peer_addr);
+            }
+            handled?;
+            if let Session::Http { closing: true, .. } = session {
+                return Ok(());
+            }
 
-            if let Err(e) = self.serve(stream) {
-                error!("Error serving client: {}", e);
+            if self.shutdown.is_shutdown() {
+                // An idle connection is closed right away. A partly received
+                // request gets until the shutdown deadline to arrive.
+                let deadline =
+                    *deadline.get_or_insert_with(|| Instant::now() + self.config.shutdown_timeout);
+                if session.is_idle(&buf) || Instant::now() >= deadline {
+                    debug!("Closing connection to {} for shutdown", peer_addr);
+                    return Ok(());
+                }
+            }
+
+            // Without a waiter, reading blocks until the stream has bytes.
+            let idle = session.is_idle(&buf);
+            let wake_at = [self.state.timeouts.deadline(idle, last_active), deadline]
+                .into_iter()
+                .flatten()
+                .min();
+            let ready = match &waiter {
+                Some(waiter) => waiter.wait(wake_at, self.shutdown.is_shutdown())?,
+                None => true,
+            };
+            let read = if ready {
+                read_len(reader.read(&mut chunk))
+            } else {
+                Err(io::ErrorKind::TimedOut.into())
+            };
+            match read {
+                Ok(0) => {
+                    return if session.is_idle(&buf) {
+                        Ok(())
+                    } else {
+                        Err(unexpected_eof())
+                    };
+                }
+                Ok(len) => {
+                    self.state.metrics.received(len);
+                    buf.extend_from_slice(&chunk[..len]);
+                    last_active = Instant::now();
+                    replies.received = last_active;
+                }
+                // The wait or the read timed out, so check for a shutdown
+                // request and for the timeouts of the connection again.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    if let Some(deadline) = self.state.timeouts.deadline(idle, last_active)
+                        && Instant::now() >= deadline
+                    {
+                        if !idle {
+                            return Err(KvsError::Timeout("receiving a request".to_owned()));
+                        }
+                        debug!("Closing idle connection to {}", peer_addr);
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Err(e.into()),
             }
         }
-        Ok(())
     }
-    /// Handles a single client connection over the given `TcpStream`.
-    fn serve(&mut self, tcp: TcpStream) -> Result<()> {
-        let peer_addr = tcp.peer_addr()?;
-        let reader = BufReader::new(tcp.try_clone()?);
-        let mut writer = BufWriter::new(tcp);
-        let reqs = Deserializer::from_reader(reader).into_iter::<Request>();
-
-        macro_rules! send_resp {
-            ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                debug!("Response sent to {}: {:?}", peer_addr, resp);
-            }};
-        }
-
-        for req in reqs {
-            let req = req?;
-            debug!("Receive request from {}: {:?}", peer_addr, req);
-            match req {
-                Request::Get { key } => send_resp!(match self.engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                }),
-                Request::Set { key, value } => send_resp!(match self.engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
-                }),
-                Request::Remove { key } => send_resp!(match self.engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(format!("{}", e)),
-                }),
+
+    /// Answers the requests in `buf`, appending the responses to `out`.
+    fn handle_frames(
+        &self,
+        framing: &mut ServerFraming,
+        buf: &mut Vec<u8>,
+        replies: &mut Replies,
+        budgets: &mut Budgets,
+        peer_addr: &dyn Display,
+    ) -> Result<()> {
+        let Replies {
+            received,
+            out,
+            answered,
+        } = replies;
+        while let Some(RequestFrame { id, request }) = framing.decode(buf, out)? {
+            let kind = request.kind();
+            let _span = debug_span!("request", id, operation = kind).entered();
+            debug!("Receive request {} from {}: {:?}", id, peer_addr, request);
+            let mut trace = RequestTrace::new("kvs", kind, request.sizes(), *received);
+            trace.audit(request.audited(), framing.user());
+            let access = request.access();
+            let allowed = framing
+                .authorize(&request)
+                .and_then(|()| self.state.throttle(budgets, framing.user(), access));
+            let response = match allowed {
+                Ok(()) if self.state.timeouts.expired(*received) => {
+                    Response::error(&request, request_timed_out())
+                }
+                Ok(()) => {
+                    trace.engine(|| handle_request(&mut *self.engine(), &self.state, request))
+                }
+                Err(e) => Response::error(&request, e),
             };
+            trace.answered(
+                response.outcome(),
+                response.value_len(),
+                response.error_message(),
+            );
+            self.state.audit(&mut trace, peer_addr);
+            answered.push(trace);
+            let frame = ResponseFrame { id, response };
+            framing.encode(&frame, out)?;
+            debug!("Response to {}: {:?}", peer_addr, frame);
+        }
+        framing.check_open()
+    }
+
+    /// Answers the RESP commands in `buf`, appending the replies to `out`.
+    fn handle_commands(
+        &self,
+        buf: &mut Vec<u8>,
+        replies: &mut Replies,
+        budgets: &mut Budgets,
+        user: &mut Option<String>,
+        peer_addr: &dyn Display,
+    ) -> Result<()> {
+        let Replies {
+            received,
+            out,
+            answered,
+        } = replies;
+        loop {
+            let args = match resp::decode_command(buf, self.config.max_frame_len) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    // Invalid input is answered like Redis does before the
+                    // connection is closed.
+                    resp::error_reply(&e).encode(out);
+                    return Err(e);
+                }
+            };
+            let kind = resp::command_kind(&args);
+            let _span = debug_span!("request", operation = kind).entered();
+            debug!("Receive command from {}: {:?}", peer_addr, args);
+            let mut trace = RequestTrace::new("resp", kind, resp::command_sizes(&args), *received);
+            trace.audit(resp::audited(&args), user.as_deref());
+            let access = resp::command_access(&args);
+            let refused = resp::authorize(self.config.acl.as_ref(), user, &args).or_else(|| {
+                let throttled = self.state.throttle(budgets, user.as_deref(), access);
+                throttled.err().map(|e| resp::throttled_reply(&e))
+            });
+            let reply = match refused {
+                Some(reply) => reply,
+                None if self.state.timeouts.expired(*received) => {
+                    resp::error_reply(&request_timed_out())
+                }
+                None => trace.engine(|| resp::execute(&mut *self.engine(), &self.expiries, args)),
+            };
+            trace.answered(
+                resp::outcome(&reply),
+                resp::reply_value_len(&reply),
+                resp::reply_error(&reply),
+            );
+            self.state.audit(&mut trace, peer_addr);
+            answered.push(trace);
+            debug!("Reply to {}: {:?}", peer_addr, reply);
+            reply.encode(out);
+        }
+    }
+
+    /// Answers the HTTP requests in `buf`, appending the responses to `out`,
+    /// up to one that closes the connection. They are requests to the
+    /// metrics endpoint if `metrics` is set, and to the gateway otherwise.
+    fn handle_http(
+        &self,
+        buf: &mut Vec<u8>,
+        replies: &mut Replies,
+        budgets: &mut Budgets,
+        closing: &mut bool,
+        metrics: bool,
+        peer_addr: &dyn Display,
+    ) -> Result<()> {
+        let Replies {
+            received,
+            out,
+            answered,
+        } = replies;
+        while !*closing {
+            let request = match http::decode_request(buf, self.config.max_frame_len) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    http::error_response(&e).encode(out);
+                    return Err(e);
+                }
+            };
+            debug!("Receive HTTP request from {}: {:?}", peer_addr, request);
+            let response = if metrics {
+                http::handle_metrics(request, || self.state.encode_metrics(&mut *self.engine()))
+            } else {
+                let kind = http::request_kind(&request);
+                let _span = debug_span!("request", operation = kind).entered();
+                let sizes = http::request_sizes(&request);
+                let mut trace = RequestTrace::new("http", kind, sizes, *received);
+                let audited = http::audited(&request);
+                let access = http::request_access(&request);
+                let acl = self.config.acl.as_ref();
+                let state = &self.state;
+                let (response, user) = if state.timeouts.expired(*received) {
+                    (http::error_response(&request_timed_out()), None)
+                } else {
+                    trace.engine(|| {
+                        http::handle(&mut *self.engine(), acl, request, |user| {
+                            state.throttle(budgets, user, access)
+                        })
+                    })
+                };
+                trace.audit(audited, user.as_deref());
+                trace.answered(response.outcome(), None, response.error_message());
+                state.audit(&mut trace, peer_addr);
+                answered.push(trace);
+                response
+            };
+            debug!("HTTP response to {}: {:?}", peer_addr, response);
+            response.encode(out);
+            *closing = response.closes();
         }
         Ok(())
     }
 }
+
+/// What a listener serves.
+enum Endpoint {
+    /// The configured protocol.
+    Main,
+    /// The HTTP gateway.
+    Http,
+    /// The metrics.
+    Metrics,
+}
+
+/// The answers to the requests of a connection received together.
+struct Replies {
+    /// When the requests were received.
+    received: Instant,
+    /// The responses.
+    out: Vec<u8>,
+    /// The requests answered in `out`, finished once it is written.
+    answered: Vec<RequestTrace>,
+}
+
+/// How the requests of one connection are read and answered.
+enum Session {
+    Kvs(ServerFraming),
+    /// `user` is who the client authenticated as with `AUTH`.
+    Resp {
+        user: Option<String>,
+    },
+    /// `closing` is set once a response closes the connection, and
+    /// `metrics` on connections to the metrics endpoint.
+    Http {
+        closing: bool,
+        metrics: bool,
+    },
+}
+
+impl Session {
+    /// Returns whether `buf` holds no part of a request.
+    fn is_idle(&self, buf: &[u8]) -> bool {
+        match self {
+            Session::Kvs(framing) => framing.is_idle(buf),
+            Session::Resp { .. } | Session::Http { .. } => buf.is_empty(),
+        }
+    }
+}
+
+/// Waits for the next bytes of a connection of `run`, or for a shutdown
+/// request.
+struct Waiter<'a> {
+    stream: Stream,
+    waker: &'a UnixStream,
+    /// The TLS session over `stream`, if any.
+    tls: Option<SharedStream<StreamOwned<ServerConnection, Stream>>>,
+}
+
+impl Waiter<'_> {
+    /// Waits until the connection has bytes to read, until `deadline` or,
+    /// unless `shutdown` is set already, until a shutdown is requested.
+    /// Returns whether there are bytes to read.
+    fn wait(&self, deadline: Option<Instant>, shutdown: bool) -> io::Result<bool> {
+        if self.tls.as_ref().is_some_and(SharedStream::has_buffered) {
+            return Ok(true);
+        }
+        let mut fds = vec![self.stream.as_fd()];
+        if !shutdown {
+            fds.push(self.waker.as_fd());
+        }
+        Ok(wait_readable(&fds, deadline)?[0])
+    }
+}
+
+/// What a server keeps track of about itself, for `Info` requests and its
+/// metrics.
+#[derive(Debug)]
+pub(crate) struct ServerState {
+    started: Instant,
+    connections: AtomicU64,
+    total_connections: AtomicU64,
+    pub(crate) metrics: Metrics,
+    slow_log: Option<SlowLog>,
+    audit_log: Option<AuditLog>,
+    rate_limiter: Option<RateLimiter>,
+    max_connections: Option<usize>,
+    pub(crate) timeouts: Timeouts,
+}
+
+impl ServerState {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        ServerState {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            metrics: Metrics::default(),
+            slow_log: config.slow_log.clone().map(SlowLog::new),
+            audit_log: config.audit_log.clone().map(AuditLog::new),
+            rate_limiter: config.rate_limit.clone().map(RateLimiter::new),
+            max_connections: config.max_connections,
+            timeouts: Timeouts {
+                idle: config.idle_timeout,
+                read: config.read_timeout,
+                write: config.write_timeout,
+                request: config.request_timeout,
+            },
+        }
+    }
+
+    /// Counts a new connection, until the returned guard is dropped.
+    pub(crate) fn connect(self: &Arc<Self>) -> Connection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        Connection(Arc::clone(self))
+    }
+
+    /// Counts a new connection from `peer_addr` like `connect`, unless as
+    /// many as allowed are open already.
+    pub(crate) fn try_connect(self: &Arc<Self>, peer_addr: &str) -> Option<Connection> {
+        if let Some(max_connections) = self.max_connections {
+            let admitted =
+                self.connections
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| {
+                        (open < max_connections as u64).then_some(open + 1)
+                    });
+            if admitted.is_err() {
+                warn!(
+                    "Refusing connection from {}: {} connections open already",
+                    peer_addr, max_connections
+                );
+                return None;
+            }
+        } else {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        Some(Connection(Arc::clone(self)))
+    }
+
+    /// Takes a request of `access` out of the budgets of the connection and
+    /// of `user`, if the server has rate limits.
+    pub(crate) fn throttle(
+        &self,
+        connection: &mut Budgets,
+        user: Option<&str>,
+        access: Access,
+    ) -> Result<()> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.check(connection, user, access),
+            None => Ok(()),
+        }
+    }
+
+    fn status(&self, engine: &str) -> ServerStatus {
+        ServerStatus {
+            server_version: CRATE_VERSION.to_owned(),
+            engine: engine.to_owned(),
+            uptime: self.started.elapsed(),
+            connections: self.connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Audits the keys a request from `peer_addr` set or removed. It is
+    /// called as soon as the request has run, before its response is sent,
+    /// so that no entry is lost to a connection that fails.
+    pub(crate) fn audit(&self, trace: &mut RequestTrace, peer_addr: &dyn Display) {
+        let audited = mem::take(&mut trace.audited);
+        let Some(audit_log) = &self.audit_log else {
+            return;
+        };
+        let now = SystemTime::now();
+        let received = now.checked_sub(trace.received.elapsed()).unwrap_or(now);
+        for (operation, key) in audited {
+            audit_log.record(AuditEntry {
+                time: received,
+                peer: peer_addr.to_string(),
+                user: trace.user.clone(),
+                protocol: trace.protocol.to_owned(),
+                operation: operation.to_owned(),
+                key,
+                result: trace.outcome.label().to_owned(),
+                error: trace.error.clone(),
+            });
+        }
+    }
+
+    /// Counts a request answered to `peer_addr` in the metrics, and logs it
+    /// if it was slow.
+    pub(crate) fn finish(&self, trace: RequestTrace, peer_addr: &dyn Display) {
+        let elapsed = trace.received.elapsed();
+        self.metrics
+            .request(trace.protocol, trace.operation, trace.outcome, elapsed);
+        let now = SystemTime::now();
+        let received = now.checked_sub(elapsed).unwrap_or(now);
+        let Some(slow_log) = &self.slow_log else {
+            return;
+        };
+        if !slow_log.is_slow(elapsed) {
+            return;
+        }
+        slow_log.record(SlowLogEntry {
+            id: 0,
+            time: received,
+            peer: peer_addr.to_string(),
+            protocol: trace.protocol.to_owned(),
+            operation: trace.operation.to_owned(),
+            key_len: trace.key_len,
+            value_len: trace.value_len,
+            engine_time: trace.engine_time,
+            network_time: elapsed.saturating_sub(trace.engine_time),
+        });
+    }
+
+    /// Renders the metrics of the server and of `engine` in the Prometheus
+    /// text format.
+    pub(crate) fn encode_metrics<E: KvsEngine + ?Sized>(&self, engine: &mut E) -> String {
+        let mut out = String::new();
+        metrics::sample(
+            &mut out,
+            "kvs_uptime_seconds",
+            "gauge",
+            "Seconds since the server started.",
+            self.started.elapsed().as_secs(),
+        );
+        metrics::sample(
+            &mut out,
+            "kvs_connections",
+            "gauge",
+            "Connections open.",
+            self.connections.load(Ordering::Relaxed),
+        );
+        metrics::sample(
+            &mut out,
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted.",
+            self.total_connections.load(Ordering::Relaxed),
+        );
+        self.metrics.encode(&mut out);
+        match engine.stats() {
+            Ok(stats) => metrics::encode_engine_stats(&stats, &mut out),
+            Err(e) => error!("Cannot get the statistics of the engine: {}", e),
+        }
+        out
+    }
+}
+
+/// The timeouts of the connections and requests of a server.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    idle: Option<Duration>,
+    read: Option<Duration>,
+    pub(crate) write: Option<Duration>,
+    request: Option<Duration>,
+}
+
+impl Timeouts {
+    /// When a connection last active at `last_active` times out, if ever:
+    /// after the idle timeout if it is `idle`, and after the read timeout in
+    /// the middle of a request.
+    pub(crate) fn deadline(&self, idle: bool, last_active: Instant) -> Option<Instant> {
+        let timeout = if idle { self.idle } else { self.read };
+        timeout.map(|timeout| last_active + timeout)
+    }
+
+    /// Returns whether a request received at `received` waited too long to
+    /// be processed.
+    pub(crate) fn expired(&self, received: Instant) -> bool {
+        self.request
+            .is_some_and(|timeout| received.elapsed() > timeout)
+    }
+}
+
+/// The error answering a request that waited too long to be processed.
+pub(crate) fn request_timed_out() -> KvsError {
+    KvsError::Timeout("waiting to be processed".to_owned())
+}
+
+/// What is measured of a request while it is answered, for the metrics and
+/// the slow log.
+#[derive(Debug)]
+pub(crate) struct RequestTrace {
+    protocol: &'static str,
+    operation: &'static str,
+    key_len: usize,
+    value_len: usize,
+    received: Instant,
+    engine_time: Duration,
+    outcome: Outcome,
+    /// The operations and the keys to audit.
+    audited: Vec<(&'static str, String)>,
+    user: Option<String>,
+    error: Option<String>,
+}
+
+impl RequestTrace {
+    /// Starts measuring a request received at `received`, with a key and a
+    /// value of the given lengths.
+    pub(crate) fn new(
+        protocol: &'static str,
+        operation: &'static str,
+        (key_len, value_len): (usize, usize),
+        received: Instant,
+    ) -> Self {
+        RequestTrace {
+            protocol,
+            operation,
+            key_len,
+            value_len,
+            received,
+            engine_time: Duration::ZERO,
+            outcome: Outcome::Ok,
+            audited: Vec::new(),
+            user: None,
+            error: None,
+        }
+    }
+
+    /// Runs `f`, counting the time it takes as time spent in the engine.
+    pub(crate) fn engine<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.engine_time += started.elapsed();
+        result
+    }
+
+    /// Records the keys the request sets or removes, and who asked for it,
+    /// for the audit log.
+    pub(crate) fn audit<K: ToString>(
+        &mut self,
+        audited: impl IntoIterator<Item = (&'static str, K)>,
+        user: Option<&str>,
+    ) {
+        self.audited.extend(
+            audited
+                .into_iter()
+                .map(|(operation, key)| (operation, key.to_string())),
+        );
+        self.user = user.map(str::to_owned);
+    }
+
+    /// Records how the request ended, the length of the value returned and
+    /// the error, if any.
+    pub(crate) fn answered(
+        &mut self,
+        outcome: Outcome,
+        value_len: Option<usize>,
+        error: Option<String>,
+    ) {
+        self.outcome = outcome;
+        if let Some(value_len) = value_len {
+            self.value_len = value_len;
+        }
+        self.error = error;
+    }
+}
+
+/// An open connection, counted by `ServerState` while it lives.
+pub(crate) struct Connection(Arc<ServerState>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs a request against the engine and returns the response to send back.
+pub(crate) fn handle_request<E: KvsEngine + ?Sized>(
+    engine: &mut E,
+    state: &ServerState,
+    req: Request,
+) -> Response {
+    match req {
+        Request::Get { key } => Response::Get(match engine.get(key) {
+            Ok(value) => GetResponse::Ok(value),
+            Err(e) => GetResponse::Err(e.into()),
+        }),
+        Request::Set { key, value } => Response::Set(match engine.set(key, value) {
+            Ok(_) => SetResponse::Ok(()),
+            Err(e) => SetResponse::Err(e.into()),
+        }),
+        Request::Remove { key } => Response::Remove(match engine.remove(key) {
+            Ok(_) => RemoveResponse::Ok(()),
+            Err(e) => RemoveResponse::Err(e.into()),
+        }),
+        Request::Ping => Response::Ping(PingResponse::Ok(())),
+        Request::Info => Response::Info(InfoResponse::Ok(state.status(engine.name()))),
+        Request::Stats => Response::Stats(match engine.stats() {
+            Ok(stats) => StatsResponse::Ok(stats),
+            Err(e) => StatsResponse::Err(e.into()),
+        }),
+        Request::Compact => Response::Compact(match engine.compact() {
+            Ok(()) => CompactResponse::Ok(()),
+            Err(e) => CompactResponse::Err(e.into()),
+        }),
+        Request::SlowLog { count } => Response::SlowLog(SlowLogResponse::Ok(
+            state
+                .slow_log
+                .as_ref()
+                .map(|slow_log| slow_log.latest(count))
+                .unwrap_or_default(),
+        )),
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// A handle that asks a running `KvsServer` or `AsyncKvsServer` to shut down.
+///
+/// Handles are cheap to clone and can be used from any thread, for example
+/// from a signal handler or from a test that started a server.
+///
+/// Once a shutdown is requested, the server stops accepting connections,
+/// finishes the requests it is working on, syncs its engine and returns from
+/// `run`.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+    /// The writing ends of the sockets returned by `waker`.
+    wakers: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        ShutdownHandle {
+            sender: Arc::new(watch::Sender::new(false)),
+            wakers: Arc::default(),
+        }
+    }
+
+    /// Asks the server to shut down.
+    ///
+    /// Calling this more than once, or before the server runs, is fine.
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+        for waker in self.wakers.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            wake(waker);
+        }
+    }
+
+    /// Returns whether a shutdown has been requested.
+    pub fn is_shutdown(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Waits until a shutdown is requested.
+    pub(crate) async fn wait(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so the channel cannot close while waiting.
+        let _ = receiver.wait_for(|&shutdown| shutdown).await;
+    }
+
+    /// Returns a socket that becomes readable once a shutdown is requested,
+    /// for `KvsServer` to wait on along with its connections.
+    pub(crate) fn waker(&self) -> io::Result<UnixStream> {
+        let (reader, writer) = UnixStream::pair()?;
+        writer.set_nonblocking(true)?;
+        let mut wakers = self.wakers.lock().unwrap_or_else(|e| e.into_inner());
+        // A shutdown requested before the lock was taken has written to the
+        // other wakers only.
+        if self.is_shutdown() {
+            wake(&writer);
+        }
+        wakers.push(writer);
+        Ok(reader)
+    }
+}
+
+fn wake(mut waker: &UnixStream) {
+    // A full socket is readable already, and a closed one has no server
+    // waiting on it.
+    let _ = waker.write(&[0]);
+}
@@ -0,0 +1,184 @@
+//! The slow log: the requests a server took longer than a threshold to
+//! answer.
+//!
+//! The latest entries are kept in memory, for `KvsClient::slow_log`, and
+//! every entry can also be appended to a file as a line of JSON, by a thread
+//! of the log, so that slow requests are not made slower by the file.
+
+use crate::Result;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_CAPACITY: usize = 128;
+
+/// Settings of the slow log of a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowLogConfig {
+    /// Requests taking at least this long to answer are logged. The default
+    /// is 10 ms.
+    pub threshold: Duration,
+    /// How many entries are kept in memory. Older ones are dropped. The
+    /// default is 128.
+    pub capacity: usize,
+    /// The file every entry is appended to, if any.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for SlowLogConfig {
+    fn default() -> Self {
+        SlowLogConfig {
+            threshold: DEFAULT_THRESHOLD,
+            capacity: DEFAULT_CAPACITY,
+            path: None,
+        }
+    }
+}
+
+/// A request that was slow to answer.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlowLogEntry {
+    /// The number of the entry, counting from 0 since the server started.
+    pub id: u64,
+    /// When the request was received.
+    pub time: SystemTime,
+    /// The address of the client.
+    pub peer: String,
+    /// The protocol of the request: `kvs`, `resp` or `http`.
+    pub protocol: String,
+    /// The type of the request, such as `get`.
+    pub operation: String,
+    /// The length of the key, in bytes.
+    pub key_len: usize,
+    /// The length of the value set or returned, in bytes.
+    pub value_len: usize,
+    /// The time spent in the engine.
+    pub engine_time: Duration,
+    /// The rest of the time it took to answer: waiting for the engine and
+    /// writing the response to the client.
+    pub network_time: Duration,
+}
+
+/// The slow log of a running server.
+#[derive(Debug)]
+pub(crate) struct SlowLog {
+    config: SlowLogConfig,
+    entries: Mutex<(VecDeque<SlowLogEntry>, u64)>,
+    /// Feeds the thread writing the file, if the log has one.
+    sender: Option<Sender<SlowLogEntry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SlowLog {
+    /// Starts the thread writing the file of the log, if it has one.
+    pub(crate) fn new(config: SlowLogConfig) -> Self {
+        let (sender, writer) = match config.path.clone() {
+            Some(path) => {
+                let (sender, receiver) = mpsc::channel();
+                let writer = thread::Builder::new()
+                    .name("kvs-slowlog".to_owned())
+                    .spawn(move || write_entries(&path, receiver));
+                let writer = match writer {
+                    Ok(writer) => Some(writer),
+                    Err(e) => {
+                        error!("Cannot start writing the slow log: {}", e);
+                        None
+                    }
+                };
+                (Some(sender), writer)
+            }
+            None => (None, None),
+        };
+        SlowLog {
+            config,
+            entries: Mutex::new((VecDeque::new(), 0)),
+            sender,
+            writer,
+        }
+    }
+
+    /// Returns whether a request taking `elapsed` to answer is logged.
+    pub(crate) fn is_slow(&self, elapsed: Duration) -> bool {
+        elapsed >= self.config.threshold
+    }
+
+    /// Logs `entry`, numbering it, and queues it to be written to the file.
+    pub(crate) fn record(&self, mut entry: SlowLogEntry) {
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+            let (entries, next_id) = &mut *entries;
+            entry.id = *next_id;
+            *next_id += 1;
+            if entries.len() == self.config.capacity {
+                entries.pop_front();
+            }
+            if self.config.capacity > 0 {
+                entries.push_back(entry.clone());
+            }
+        }
+        if let Some(sender) = &self.sender
+            && sender.send(entry).is_err()
+        {
+            error!("The slow log file is no longer written");
+        }
+    }
+
+    /// Returns the latest `count` entries, the newest first.
+    pub(crate) fn latest(&self, count: usize) -> Vec<SlowLogEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.0.iter().rev().take(count).cloned().collect()
+    }
+}
+
+impl Drop for SlowLog {
+    /// Waits for the queued entries to be written.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Appends the entries received to the file at `path` until the log is
+/// dropped.
+fn write_entries(path: &Path, receiver: Receiver<SlowLogEntry>) {
+    let mut file = None;
+    while let Ok(entry) = receiver.recv() {
+        // Entries queued together are written together.
+        let written = std::iter::once(entry)
+            .chain(receiver.try_iter())
+            .try_for_each(|entry| append(path, &mut file, &entry));
+        let flushed = written.and_then(|()| match &mut file {
+            Some(file) => Ok(file.flush()?),
+            None => Ok(()),
+        });
+        if let Err(e) = flushed {
+            error!("Cannot write to the slow log: {}", e);
+            // Reopen the file for the next entries.
+            file = None;
+        }
+    }
+}
+
+/// Appends `entry` to the file at `path`, opening it first if need be.
+fn append(path: &Path, file: &mut Option<BufWriter<File>>, entry: &SlowLogEntry) -> Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let file = match file {
+        Some(file) => file,
+        None => file.insert(BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+    };
+    file.write_all(&line)?;
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! TLS for the connections between clients and servers, on rustls.
+
+use crate::transport::{AsyncStream, Stream};
+use crate::{Address, KvsError, Result};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| KvsError::TlsConfig(format!("invalid certificate: {}", e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::TlsConfig("no certificate found".to_owned()));
+    }
+    Ok(certs)
+}
+
+fn private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_slice(pem)
+        .map_err(|e| KvsError::TlsConfig(format!("invalid private key: {}", e)))
+}
+
+fn root_store(ca_pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certificates(ca_pem)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Turns an I/O error of a handshake caused by TLS into `KvsError::Tls`.
+fn handshake_error(err: io::Error) -> KvsError {
+    match err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<rustls::Error>())
+    {
+        Some(e) => KvsError::Tls(e.clone()),
+        None => KvsError::Io(err),
+    }
+}
+
+/// Reads a PEM file, naming it in the error.
+fn read_pem(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| KvsError::TlsConfig(format!("{}: {}", path.display(), e)))
+}
+
+/// The TLS settings of a server: its certificate and, for mutual TLS, the
+/// certificate authorities that client certificates must be signed by.
+#[derive(Clone, Debug)]
+pub struct ServerTlsConfig {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Serves the PEM-encoded certificate chain `cert_pem` with the private
+    /// key `key_pem`.
+    ///
+    /// Given `client_ca_pem`, clients must present a certificate signed by
+    /// one of the certificate authorities in it.
+    pub fn new(cert_pem: &[u8], key_pem: &[u8], client_ca_pem: Option<&[u8]>) -> Result<Self> {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca_pem {
+            Some(ca_pem) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(root_store(ca_pem)?),
+                    provider(),
+                )
+                .build()
+                .map_err(|e| KvsError::TlsConfig(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(certificates(cert_pem)?, private_key(key_pem)?)?;
+        Ok(ServerTlsConfig {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Like `new`, reading the PEM files at the given paths.
+    pub fn from_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<Self> {
+        let client_ca = client_ca.map(read_pem).transpose()?;
+        Self::new(&read_pem(cert)?, &read_pem(key)?, client_ca.as_deref())
+    }
+
+    /// Wraps `stream`, whose handshake then runs as it is first read.
+    pub(crate) fn accept(&self, stream: Stream) -> Result<StreamOwned<ServerConnection, Stream>> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    pub(crate) async fn accept_async(
+        &self,
+        stream: Box<dyn AsyncStream>,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let acceptor = TlsAcceptor::from(Arc::clone(&self.config));
+        let stream = acceptor.accept(stream).await.map_err(handshake_error)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// The TLS settings of a client: the certificate authorities that the server
+/// certificate must be signed by and, for mutual TLS, a client certificate.
+#[derive(Clone, Debug)]
+pub struct ClientTlsConfig {
+    config: Arc<rustls::ClientConfig>,
+    server_name: Option<String>,
+}
+
+impl ClientTlsConfig {
+    /// Trusts the certificate authorities in the PEM-encoded `ca_pem`.
+    ///
+    /// Given `identity`, a PEM-encoded certificate chain and private key,
+    /// the client presents it to servers that ask for one.
+    pub fn new(ca_pem: &[u8], identity: Option<(&[u8], &[u8])>) -> Result<Self> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(ca_pem)?);
+        let config = match identity {
+            Some((cert_pem, key_pem)) => {
+                builder.with_client_auth_cert(certificates(cert_pem)?, private_key(key_pem)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTlsConfig {
+            config: Arc::new(config),
+            server_name: None,
+        })
+    }
+
+    /// Like `new`, reading the PEM files at the given paths.
+    pub fn from_files(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Self> {
+        let identity = match identity {
+            Some((cert, key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            None => None,
+        };
+        let identity = identity
+            .as_ref()
+            .map(|(cert, key)| (cert.as_slice(), key.as_slice()));
+        Self::new(&read_pem(ca)?, identity)
+    }
+
+    /// Sets the name the server certificate must be valid for.
+    ///
+    /// It defaults to the host of the TCP address connected to, and must be
+    /// set for Unix domain sockets.
+    pub fn with_server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    fn server_name(&self, addr: &Address) -> Result<ServerName<'static>> {
+        let name = match (&self.server_name, addr) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Tcp(addr)) => {
+                let host = addr
+                    .rsplit_once(':')
+                    .map_or(addr.as_str(), |(host, _)| host);
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_owned()
+            }
+            (None, Address::Unix(_)) => {
+                return Err(KvsError::TlsConfig(
+                    "a server name is needed over a Unix domain socket".to_owned(),
+                ));
+            }
+        };
+        ServerName::try_from(name)
+            .map_err(|e| KvsError::TlsConfig(format!("invalid server name: {}", e)))
+    }
+
+    pub(crate) fn connect(
+        &self,
+        addr: &Address,
+        mut stream: Stream,
+    ) -> Result<StreamOwned<ClientConnection, Stream>> {
+        let mut connection =
+            ClientConnection::new(Arc::clone(&self.config), self.server_name(addr)?)?;
+        while connection.is_handshaking() {
+            connection
+                .complete_io(&mut stream)
+                .map_err(handshake_error)?;
+        }
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    pub(crate) async fn connect_async(
+        &self,
+        addr: &Address,
+        stream: Box<dyn AsyncStream>,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let connector = TlsConnector::from(Arc::clone(&self.config));
+        let server_name = self.server_name(addr)?;
+        let stream = connector
+            .connect(server_name, stream)
+            .await
+            .map_err(handshake_error)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// A blocking stream shared by the reading and the writing side of a
+/// connection, for TLS streams that cannot be cloned.
+pub(crate) struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S> SharedStream<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, S>> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("stream lock poisoned"))
+    }
+}
+
+impl SharedStream<StreamOwned<ServerConnection, Stream>> {
+    /// Returns whether bytes received already wait to be read, which a wait
+    /// on the socket would not notice.
+    pub(crate) fn has_buffered(&self) -> bool {
+        // Errors are left for the next read to report.
+        self.lock().map_or(true, |mut stream| {
+            stream.conn.process_new_packets().map_or(true, |state| {
+                state.plaintext_bytes_to_read() > 0 || state.peer_has_closed()
+            })
+        })
+    }
+}
+
+impl<S> Clone for SharedStream<S> {
+    fn clone(&self) -> Self {
+        SharedStream(Arc::clone(&self.0))
+    }
+}
+
+impl<S: Read> Read for SharedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
@@ -0,0 +1,375 @@
+//! The transports servers listen on and clients connect over.
+//!
+//! The serve loops and the clients only need a byte stream, so the same code
+//! runs over TCP, Unix domain sockets and, through `KvsClient::from_stream`,
+//! `AsyncKvsClient::from_stream` and the servers' `serve_stream`, over
+//! in-memory pipes.
+
+use crate::KvsError;
+use rustix::event::{PollFd, PollFlags, Timespec, poll};
+use rustix::io::Errno;
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Where a server listens or a client connects.
+///
+/// Written as `IP:PORT` or `HOST:PORT` for TCP, and as `unix:PATH` for a Unix
+/// domain socket. Strings and socket addresses convert into it, so
+/// `KvsClient::connect("127.0.0.1:4000")` and
+/// `KvsClient::connect("unix:/run/kvs.sock")` both work.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// A TCP address, resolved when binding or connecting.
+    Tcp(String),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl From<&str> for Address {
+    fn from(addr: &str) -> Address {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(addr.to_owned()),
+        }
+    }
+}
+
+impl From<String> for Address {
+    fn from(addr: String) -> Address {
+        Address::from(addr.as_str())
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr.to_string())
+    }
+}
+
+impl From<&Address> for Address {
+    fn from(addr: &Address) -> Address {
+        addr.clone()
+    }
+}
+
+impl FromStr for Address {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Address::from(s))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Binds a Unix domain socket at `path`.
+///
+/// A socket file left behind by a server that is gone is replaced; one that
+/// a live server listens on is not, and neither is anything but a socket.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            // Connecting to a regular file is refused just like connecting to
+            // a stale socket.
+            if !fs::symlink_metadata(path)?.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            match UnixStream::connect(path) {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(path)?;
+                    UnixListener::bind(path)
+                }
+                _ => Err(e),
+            }
+        }
+        bound => bound,
+    }
+}
+
+/// A listener of `KvsServer`.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed again when the listener is dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub(crate) fn bind(addr: &Address) -> io::Result<Self> {
+        Ok(match addr {
+            Address::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            Address::Unix(path) => Listener::Unix(bind_unix(path)?, path.clone()),
+        })
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Accepts a connection and describes its peer, for logging.
+    pub(crate) fn accept(&self) -> io::Result<(Stream, String)> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                // Responses are sent as soon as they are ready.
+                stream.set_nodelay(true)?;
+                (Stream::Tcp(stream), peer_addr.to_string())
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept()?;
+                (
+                    Stream::Unix(stream),
+                    format!("{}{}", UNIX_PREFIX, path.display()),
+                )
+            }
+        })
+    }
+}
+
+impl AsFd for Listener {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix(listener, _) => listener.as_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection of `KvsServer` or `KvsClient`.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to `addr`, giving up on a TCP address after `timeout`, if
+    /// there is one.
+    pub(crate) fn connect(addr: &Address, timeout: Option<Duration>) -> io::Result<Self> {
+        Ok(match (addr, timeout) {
+            (Address::Tcp(addr), None) => Stream::Tcp(TcpStream::connect(addr)?),
+            (Address::Tcp(addr), Some(timeout)) => Stream::Tcp(connect_timeout(addr, timeout)?),
+            (Address::Unix(path), _) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(stream) => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl AsFd for Stream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Stream::Tcp(stream) => stream.as_fd(),
+            Stream::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
+/// Waits until any of `fds` has something to read, or is closed, or until
+/// `deadline`, and returns which of them do.
+///
+/// A signal interrupting the wait leaves all of them unready.
+pub(crate) fn wait_readable(
+    fds: &[BorrowedFd<'_>],
+    deadline: Option<Instant>,
+) -> io::Result<Vec<bool>> {
+    let mut poll_fds: Vec<_> = fds
+        .iter()
+        .map(|&fd| PollFd::from_borrowed_fd(fd, PollFlags::IN))
+        .collect();
+    // A deadline too far away to express is no deadline.
+    let timeout = deadline.and_then(|deadline| {
+        Timespec::try_from(deadline.saturating_duration_since(Instant::now())).ok()
+    });
+    match poll(&mut poll_fds, timeout.as_ref()) {
+        Ok(_) => Ok(poll_fds.iter().map(|fd| !fd.revents().is_empty()).collect()),
+        Err(Errno::INTR) => Ok(vec![false; fds.len()]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Connects to the first address `addr` resolves to that answers within
+/// `timeout`.
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Address resolves to nothing")
+    }))
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A byte stream that async servers and clients can run over.
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+/// A listener of `AsyncKvsServer`.
+pub(crate) enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    /// The path is removed again when the listener is dropped.
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl AsyncListener {
+    /// Binds to `addr`. This must be called inside a tokio runtime.
+    pub(crate) async fn bind(addr: &Address) -> io::Result<Self> {
+        Ok(match addr {
+            Address::Tcp(addr) => AsyncListener::Tcp(tokio::net::TcpListener::bind(addr).await?),
+            Address::Unix(path) => {
+                let listener = bind_unix(path)?;
+                listener.set_nonblocking(true)?;
+                AsyncListener::Unix(tokio::net::UnixListener::from_std(listener)?, path.clone())
+            }
+        })
+    }
+
+    /// Accepts a connection and describes its peer, for logging.
+    pub(crate) async fn accept(&self) -> io::Result<(Box<dyn AsyncStream>, String)> {
+        Ok(match self {
+            AsyncListener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                // Responses are sent as soon as they are ready.
+                stream.set_nodelay(true)?;
+                (Box::new(stream), peer_addr.to_string())
+            }
+            AsyncListener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                (
+                    Box::new(stream),
+                    format!("{}{}", UNIX_PREFIX, path.display()),
+                )
+            }
+        })
+    }
+}
+
+impl Drop for AsyncListener {
+    fn drop(&mut self) {
+        if let AsyncListener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Connects to `addr`. This must be called inside a tokio runtime.
+pub(crate) async fn connect_async(addr: &Address) -> io::Result<Box<dyn AsyncStream>> {
+    Ok(match addr {
+        Address::Tcp(addr) => Box::new(tokio::net::TcpStream::connect(addr).await?),
+        Address::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+    })
+}
+
+/// Turns the error of a read or a write that timed out into
+/// `KvsError::Timeout`, leaving other errors alone.
+pub(crate) fn timed_out(err: KvsError, what: &str) -> KvsError {
+    match err {
+        KvsError::Io(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            KvsError::Timeout(what.to_owned())
+        }
+        err => err,
+    }
+}
+
+/// The result of a read, with a TLS connection closed without a
+/// `close_notify` taken to be closed cleanly.
+///
+/// Messages are framed, so one cut short by a truncated connection is still
+/// noticed.
+pub(crate) fn read_len(read: io::Result<usize>) -> io::Result<usize> {
+    match read {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+        read => read,
+    }
+}
@@ -0,0 +1,502 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use kvs::{
+    Acl, AsyncKvsClient, AsyncKvsServer, AuditConfig, AuditEntry, ClientConfig, Credentials,
+    KvsClient, KvsError, KvsServer, MemoryKvsEngine, Permission, Protocol, RateLimit,
+    RateLimitConfig, Result, ServerConfig, hash_password, hash_token,
+};
+use serde_json::json;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const TOKEN: &str = "8f14e45fceea167a5a36dedd4bea2543";
+
+/// alice may read and write keys starting with `app/`, and the holder of
+/// `TOKEN` may read every key.
+fn acl() -> Acl {
+    let acl = json!({
+        "users": {
+            "alice": {
+                "password": hash_password("secret").unwrap(),
+                "grants": { "app/": ["read", "write"] }
+            },
+            "reader": {
+                "tokens": [hash_token(TOKEN)],
+                "grants": { "": ["read"] }
+            }
+        }
+    });
+    Acl::from_json(&acl.to_string()).unwrap()
+}
+
+fn alice() -> Credentials {
+    Credentials::Password {
+        user: "alice".to_owned(),
+        password: "secret".to_owned(),
+    }
+}
+
+fn client_config(credentials: Option<Credentials>) -> ClientConfig {
+    ClientConfig {
+        credentials,
+        ..ClientConfig::default()
+    }
+}
+
+fn spawn_server(config: ServerConfig, addr: &'static str, blocking: bool) {
+    if blocking {
+        let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+        thread::spawn(move || server.run(addr).unwrap());
+    } else {
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+            runtime.block_on(server.run(addr)).unwrap();
+        });
+    }
+}
+
+fn assert_denied<T>(result: Result<T>) {
+    match result {
+        Err(KvsError::PermissionDenied(_)) => {}
+        Err(e) => panic!("Expected a permission error, got {}", e),
+        Ok(_) => panic!("Expected a permission error"),
+    }
+}
+
+fn assert_unauthenticated<T>(result: Result<T>) {
+    match result {
+        Err(KvsError::AuthenticationFailed(_)) => {}
+        Err(e) => panic!("Expected an authentication error, got {}", e),
+        Ok(_) => panic!("Expected an authentication error"),
+    }
+}
+
+// Clients should authenticate in the handshake and only be allowed what
+// their grants allow.
+#[test]
+fn acl_on_kvs_protocol() -> Result<()> {
+    let config = ServerConfig {
+        acl: Some(acl()),
+        ..ServerConfig::default()
+    };
+    let addrs = ["127.0.0.1:4210", "127.0.0.1:4211"];
+    spawn_server(config.clone(), addrs[0], true);
+    spawn_server(config, addrs[1], false);
+    thread::sleep(Duration::from_millis(500));
+
+    let wrong_password = Credentials::Password {
+        user: "alice".to_owned(),
+        password: "guess".to_owned(),
+    };
+    for addr in addrs {
+        assert_unauthenticated(KvsClient::connect(addr));
+        assert_unauthenticated(KvsClient::connect_with_config(
+            addr,
+            &client_config(Some(wrong_password.clone())),
+        ));
+        assert_unauthenticated(KvsClient::connect_with_config(
+            addr,
+            &client_config(Some(Credentials::Token("forged".to_owned()))),
+        ));
+
+        let mut client = KvsClient::connect_with_config(addr, &client_config(Some(alice())))?;
+        client.set("app/key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(
+            client.get("app/key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_denied(client.set("key1".to_owned(), "value1".to_owned()));
+        assert_denied(client.get("key1".to_owned()));
+        assert_denied(client.remove("key1".to_owned()));
+        let results = client
+            .pipeline()
+            .get("app/key1".to_owned())
+            .get("key1".to_owned())
+            .execute()?;
+        assert_eq!(results[0].as_ref().unwrap(), &Some("value1".to_owned()));
+        assert!(matches!(results[1], Err(KvsError::PermissionDenied(_))));
+        client.ping()?;
+        assert_denied(client.stats());
+        assert_denied(client.compact());
+        drop(client);
+
+        let token = client_config(Some(Credentials::Token(TOKEN.to_owned())));
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let client = AsyncKvsClient::connect_with_config(addr, &token).await?;
+            assert_eq!(
+                client.get("app/key1".to_owned()).await?,
+                Some("value1".to_owned())
+            );
+            assert_eq!(client.get("key1".to_owned()).await?, None);
+            assert_denied(client.remove("app/key1".to_owned()).await);
+            Ok::<_, KvsError>(())
+        })?;
+    }
+    Ok(())
+}
+
+/// Sends an inline RESP command and returns the raw reply.
+fn resp_call(stream: &mut BufReader<TcpStream>, command: &str) -> Result<String> {
+    stream
+        .get_mut()
+        .write_all(format!("{}\r\n", command).as_bytes())?;
+    resp_reply(stream)
+}
+
+/// Reads one RESP reply and returns it raw.
+fn resp_reply(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut reply = String::new();
+    stream.read_line(&mut reply)?;
+    let len: i64 = reply[1..reply.len() - 2].parse().unwrap_or(0);
+    match reply.as_bytes()[0] {
+        b'$' if len >= 0 => stream.read_line(&mut reply).map(drop)?,
+        b'*' => {
+            for _ in 0..len {
+                reply += &resp_reply(stream)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(reply)
+}
+
+// RESP clients should authenticate with AUTH, like they do with Redis.
+#[test]
+fn acl_on_resp() -> Result<()> {
+    let config = ServerConfig {
+        protocol: Protocol::Resp,
+        acl: Some(acl()),
+        ..ServerConfig::default()
+    };
+    let addrs = ["127.0.0.1:4212", "127.0.0.1:4213"];
+    spawn_server(config.clone(), addrs[0], true);
+    spawn_server(config, addrs[1], false);
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in addrs {
+        let mut stream = BufReader::new(TcpStream::connect(addr)?);
+        let mut call = |command: &str| resp_call(&mut stream, command).unwrap();
+        assert_eq!(call("PING"), "+PONG\r\n");
+        assert_eq!(call("GET app/key1"), "-NOAUTH Authentication required.\r\n");
+        assert!(call("AUTH alice guess").starts_with("-WRONGPASS"));
+        assert_eq!(call("AUTH alice secret"), "+OK\r\n");
+        assert_eq!(call("SET app/key1 value1"), "+OK\r\n");
+        assert_eq!(call("GET app/key1"), "$6\r\nvalue1\r\n");
+        assert!(call("SET key1 value1").starts_with("-NOPERM"));
+        assert!(call("DEL app/key1 key1").starts_with("-NOPERM"));
+        assert_eq!(
+            call("SCAN 0 MATCH app/*"),
+            "*2\r\n$1\r\n0\r\n*1\r\n$8\r\napp/key1\r\n"
+        );
+        assert!(call("SCAN 0").starts_with("-NOPERM"));
+        assert!(call("INFO").starts_with("-NOPERM"));
+        assert_eq!(call(&format!("AUTH {}", TOKEN)), "+OK\r\n");
+        assert_eq!(call("GET app/key1"), "$6\r\nvalue1\r\n");
+        assert!(call("SET app/key1 value2").starts_with("-NOPERM"));
+    }
+    Ok(())
+}
+
+/// Sends an HTTP request on a connection of its own and returns the status
+/// and the whole response.
+fn http_call(addr: &str, request: &str, authorization: Option<&str>) -> Result<(u16, String)> {
+    let (method, target, body) = match request.split_once(' ') {
+        Some((method, rest)) => match rest.split_once(' ') {
+            Some((target, body)) => (method, target, body),
+            None => (method, rest, ""),
+        },
+        None => panic!("No method in {:?}", request),
+    };
+    let mut stream = TcpStream::connect(addr)?;
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        target,
+        body.len()
+    );
+    if let Some(authorization) = authorization {
+        head += &format!("Authorization: {}\r\n", authorization);
+    }
+    stream.write_all(format!("{}\r\n{}", head, body).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    Ok((status, response))
+}
+
+// HTTP clients should authenticate with an Authorization header.
+#[test]
+fn acl_on_http() -> Result<()> {
+    let addrs = [
+        ("127.0.0.1:4214", "127.0.0.1:4215"),
+        ("127.0.0.1:4216", "127.0.0.1:4217"),
+    ];
+    for (i, (addr, http_addr)) in addrs.into_iter().enumerate() {
+        let config = ServerConfig {
+            http_addr: Some(http_addr.parse().unwrap()),
+            acl: Some(acl()),
+            ..ServerConfig::default()
+        };
+        spawn_server(config, addr, i == 0);
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let basic = format!("Basic {}", BASE64.encode("alice:secret"));
+    let bearer = format!("Bearer {}", TOKEN);
+    let wrong = format!("Basic {}", BASE64.encode("alice:guess"));
+    for (_, addr) in addrs {
+        let (status, response) = http_call(addr, "GET /keys/app%2Fkey1", None)?;
+        assert_eq!(status, 401);
+        assert!(response.contains("WWW-Authenticate: Basic realm=\"kvs\"\r\n"));
+        assert_eq!(
+            http_call(addr, "GET /keys/app%2Fkey1", Some(&wrong))?.0,
+            401
+        );
+        assert_eq!(
+            http_call(
+                addr,
+                r#"PUT /keys/app%2Fkey1 {"value":"value1"}"#,
+                Some(&basic)
+            )?
+            .0,
+            204
+        );
+        assert_eq!(
+            http_call(addr, r#"PUT /keys/key1 {"value":"value1"}"#, Some(&basic))?.0,
+            403
+        );
+        assert_eq!(
+            http_call(addr, "GET /keys?prefix=app/", Some(&basic))?.0,
+            200
+        );
+        assert_eq!(http_call(addr, "GET /keys", Some(&basic))?.0, 403);
+        assert_eq!(http_call(addr, "GET /keys", Some(&bearer))?.0, 200);
+        assert_eq!(
+            http_call(addr, "GET /keys/app%2Fkey1", Some(&bearer))?.0,
+            200
+        );
+        assert_eq!(
+            http_call(addr, "DELETE /keys/app%2Fkey1", Some(&bearer))?.0,
+            403
+        );
+    }
+    Ok(())
+}
+
+// ACL files should hold hashed secrets only, and grants should match keys by
+// prefix.
+#[test]
+fn acl_files() -> Result<()> {
+    let acl = acl();
+    assert_eq!(acl.authenticate(&alice())?, "alice");
+    assert_eq!(
+        acl.authenticate(&Credentials::Token(TOKEN.to_owned()))?,
+        "reader"
+    );
+    acl.check(Some("alice"), Permission::Write, "app/key1")?;
+    assert!(acl.check(Some("alice"), Permission::Write, "ap").is_err());
+    assert!(acl.check(Some("alice"), Permission::Admin, "").is_err());
+    assert!(
+        acl.check(Some("mallory"), Permission::Read, "app/key1")
+            .is_err()
+    );
+    assert!(acl.check(None, Permission::Read, "app/key1").is_err());
+    acl.check(Some("reader"), Permission::Read, "")?;
+
+    let plaintext = r#"{"users": {"bob": {"password": "hunter2"}}}"#;
+    assert!(matches!(
+        Acl::from_json(plaintext),
+        Err(KvsError::InvalidAcl(_))
+    ));
+    let unknown = r#"{"users": {"bob": {"grants": {"": ["delete"]}}}}"#;
+    assert!(matches!(
+        Acl::from_json(unknown),
+        Err(KvsError::InvalidAcl(_))
+    ));
+    Ok(())
+}
+
+// A wrong password should take about as long to refuse for an unknown user as
+// for a known one, so that the time does not give away who exists.
+#[test]
+fn unknown_users_take_as_long() {
+    let acl = acl();
+    let elapsed = |user: &str| {
+        let credentials = Credentials::Password {
+            user: user.to_owned(),
+            password: "wrong".to_owned(),
+        };
+        let started = Instant::now();
+        assert!(acl.authenticate(&credentials).is_err());
+        started.elapsed()
+    };
+    let known = elapsed("alice");
+    // the token user has no password, which is checked like an unknown user
+    for user in ["mallory", "reader"] {
+        assert!(elapsed(user) > known / 4, "{} was refused early", user);
+    }
+}
+
+/// Waits for the set of `app/key1` to be written to the audit log at `path`,
+/// and returns the entries of the log, the oldest first.
+fn read_audit_log(path: &Path) -> Result<Vec<AuditEntry>> {
+    let read = |path: &Path| -> Result<Vec<AuditEntry>> {
+        let entries = fs::read_to_string(path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?;
+        Ok(entries)
+    };
+    for _ in 0..100 {
+        if let Ok(entries) = read(path)
+            && entries.iter().any(|entry| entry.key == "app/key1")
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut entries = Vec::new();
+    for rotated in [".2", ".1", ""] {
+        let mut rotated_path = path.as_os_str().to_owned();
+        rotated_path.push(rotated);
+        entries.extend(read(Path::new(&rotated_path))?);
+    }
+    Ok(entries)
+}
+
+// Every set and remove should be audited with its user and result, and the
+// log rotated as it grows.
+#[test]
+fn audit_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for (addr, blocking) in [("127.0.0.1:4218", true), ("127.0.0.1:4219", false)] {
+        let path = temp_dir.path().join(format!("audit-{}.log", blocking));
+        // Every entry starts a new file, and two old ones are kept.
+        let config = ServerConfig {
+            acl: Some(acl()),
+            audit_log: Some(AuditConfig {
+                max_file_len: 1,
+                max_files: 2,
+                ..AuditConfig::new(&path)
+            }),
+            ..ServerConfig::default()
+        };
+        spawn_server(config, addr, blocking);
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect_with_config(addr, &client_config(Some(alice())))?;
+        client.set("app/key0".to_owned(), "value".to_owned())?;
+        assert_eq!(client.get("app/key0".to_owned())?, Some("value".to_owned()));
+        assert!(matches!(
+            client.remove("app/missing".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_denied(client.set("other".to_owned(), "value".to_owned()));
+        client.set("app/key1".to_owned(), "value".to_owned())?;
+
+        // The set of `app/key0` was rotated out, and gets are not audited.
+        let entries = read_audit_log(&path)?;
+        assert_eq!(entries.len(), 3);
+        let missing = &entries[0];
+        assert_eq!(missing.user.as_deref(), Some("alice"));
+        assert_eq!(missing.protocol, "kvs");
+        assert_eq!(missing.operation, "remove");
+        assert_eq!(missing.key, "app/missing");
+        assert_eq!(missing.result, "error");
+        assert_eq!(missing.error.as_deref(), Some("Key not found"));
+        assert!(missing.peer.starts_with("127.0.0.1:"));
+        let denied = &entries[1];
+        assert_eq!(denied.operation, "set");
+        assert_eq!(denied.key, "other");
+        assert_eq!(denied.result, "denied");
+        assert!(denied.error.is_some());
+        let set = &entries[2];
+        assert_eq!(set.key, "app/key1");
+        assert_eq!(set.result, "ok");
+        assert_eq!(set.error, None);
+    }
+    Ok(())
+}
+
+// A set should be audited even if its response cannot be sent, because the
+// client went away without reading it.
+#[test]
+fn audit_log_without_reply() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let request = br#"{"id":1,"request":{"Set":{"key":"key1","value":"value"}}}"#;
+    for blocking in [true, false] {
+        let path = temp_dir.path().join(format!("audit-{}.log", blocking));
+        let config = ServerConfig {
+            audit_log: Some(AuditConfig::new(&path)),
+            ..ServerConfig::default()
+        };
+        // The server is dropped once the connection fails, which waits for
+        // the log to be written.
+        if blocking {
+            let (server_reader, mut client_writer) = io::pipe()?;
+            let (client_reader, server_writer) = io::pipe()?;
+            client_writer.write_all(request)?;
+            drop((client_writer, client_reader));
+            let mut server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+            assert!(server.serve_stream(server_reader, server_writer).is_err());
+        } else {
+            let runtime = tokio::runtime::Runtime::new()?;
+            runtime.block_on(async {
+                use tokio::io::AsyncWriteExt;
+
+                let (mut client_end, server_end) = tokio::io::duplex(1024);
+                client_end.write_all(request).await?;
+                drop(client_end);
+                let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+                assert!(server.serve_stream(server_end).await.is_err());
+                Ok::<_, KvsError>(())
+            })?;
+        }
+
+        let entries: Vec<AuditEntry> = fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<_>>()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, "set");
+        assert_eq!(entries[0].key, "key1");
+        assert_eq!(entries[0].result, "ok");
+    }
+    Ok(())
+}
+
+// The budget of a user is shared by all their connections.
+#[test]
+fn user_rate_limits() -> Result<()> {
+    for (addr, blocking) in [("127.0.0.1:4220", true), ("127.0.0.1:4221", false)] {
+        let config = ServerConfig {
+            acl: Some(acl()),
+            rate_limit: Some(RateLimitConfig {
+                user_writes: Some(RateLimit::per_second(1)),
+                ..RateLimitConfig::default()
+            }),
+            ..ServerConfig::default()
+        };
+        spawn_server(config, addr, blocking);
+        thread::sleep(Duration::from_millis(500));
+
+        let config = client_config(Some(alice()));
+        let mut client = KvsClient::connect_with_config(addr, &config)?;
+        client.set("app/key1".to_owned(), "value".to_owned())?;
+        drop(client);
+        let mut client = KvsClient::connect_with_config(addr, &config)?;
+        assert!(matches!(
+            client.set("app/key2".to_owned(), "value".to_owned()),
+            Err(KvsError::Throttled(_))
+        ));
+        assert_eq!(client.get("app/key1".to_owned())?, Some("value".to_owned()));
+    }
+    Ok(())
+}
@@ -1,6 +1,11 @@
+// These lints fire on the original tests, which are left as they are.
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -164,6 +169,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -335,3 +341,480 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_start_server(
+    engine: &str,
+    addr: &str,
+    dir: &TempDir,
+) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+
+    let (sender, handle) = cli_start_server("kvs", addr, &temp_dir);
+    for (key, value) in [("key1", "value1"), ("key2", "value2"), ("key3", "value3")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    for (to, from) in [("sled", "kvs"), ("kvs", "sled")] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["migrate", "--to", to])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+            to
+        );
+        assert!(!temp_dir.path().join("migration").exists());
+
+        // The old engine is no longer accepted for this directory.
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", from, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+
+        let (sender, handle) = cli_start_server(to, addr, &temp_dir);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key2", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("Key not found"));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key3", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value3\n");
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+/// Checks that the engine the marker of `dir` names holds the `count` keys
+/// written before a migration.
+fn assert_migrated_keys(dir: &Path, count: usize) {
+    let marker = fs::read_to_string(dir.join("engine")).unwrap_or_else(|_| "kvs".to_owned());
+    let mut engine: Box<dyn KvsEngine> = match marker.as_str() {
+        "kvs" => Box::new(KvStore::open(dir).unwrap()),
+        "sled" => Box::new(SledKvsEngine::new(sled::open(dir).unwrap())),
+        other => panic!("Unexpected engine marker: {}", other),
+    };
+    assert_eq!(engine.keys().unwrap().len(), count);
+    for i in [0, count / 2, count - 1] {
+        assert_eq!(
+            engine.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+// A migration killed at any point should leave either engine live with every
+// key, and running it again should finish it.
+#[test]
+fn cli_migrate_interrupted() {
+    const KEYS: usize = 2000;
+    for delay in [0, 10, 25, 50, 75, 100, 125, 150, 175, 200, 400] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..KEYS {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        drop(store);
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["migrate", "--to", "sled"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_millis(delay));
+        let _ = child.kill();
+        child.wait().expect("failed to wait on migration");
+        assert_migrated_keys(temp_dir.path(), KEYS);
+
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["migrate", "--to", "sled"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+            "sled"
+        );
+        assert_migrated_keys(temp_dir.path(), KEYS);
+    }
+}
+
+#[test]
+fn admin_cli_verify_repair_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = kvs::KvStore::open(temp_dir.path()).unwrap();
+    kvs::KvsEngine::set(&mut store, "key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("0 corrupt, 1 live keys"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.log@0\tset \"key1\" \"value1\""));
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))
+        .unwrap();
+    std::io::Write::write_all(&mut file, b"{\"Remove\":").unwrap();
+    drop(file);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("corrupt (10 bytes)"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("salvaged 1 records"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+
+    // The memory engine ignores, and leaves alone, the engine marker.
+    let (sender, handle) = cli_start_server("memory", addr, &temp_dir);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["--version", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!(
+            "kvs-client {}",
+            env!("CARGO_PKG_VERSION")
+        )))
+        .stdout(contains(format!(
+            "kvs-server {}",
+            env!("CARGO_PKG_VERSION")
+        )))
+        .stdout(contains("engine memory"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("PONG\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: memory\n"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // The server runs without a slow log.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["slowlog", "--count", "5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["migrate", "--to", "memory"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_access_async_server() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--codec", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// SIGTERM should shut the server down cleanly: exit status 0, a clean-shutdown
+// marker, and the data written before the signal still there.
+#[test]
+fn cli_graceful_shutdown() {
+    for (addr, extra_args) in [
+        ("127.0.0.1:4009", vec![]),
+        ("127.0.0.1:4010", vec!["--async"]),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(&extra_args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        assert!(!temp_dir.path().join("clean-shutdown").exists());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::new("kill")
+            .args(&["-TERM", &child.id().to_string()])
+            .assert()
+            .success();
+        let status = child.wait().expect("failed to wait on server");
+        assert!(status.success(), "server exited with {}", status);
+        assert!(temp_dir.path().join("clean-shutdown").exists());
+
+        let (sender, handle) = cli_start_server("kvs", addr, &temp_dir);
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+        // Killed, so the marker of the previous run is gone.
+        assert!(!temp_dir.path().join("clean-shutdown").exists());
+    }
+}
+
+#[test]
+fn cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", path.display());
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--unix"])
+        .arg(&path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    assert!(!path.exists());
+}
+
+// `kvs-client --tls-ca` should talk to `kvs-server --tls-cert --tls-key`.
+#[test]
+fn cli_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = params.self_signed(&ca_key).unwrap();
+    let key = rcgen::KeyPair::generate().unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["127.0.0.1".to_owned()])
+        .unwrap()
+        .signed_by(&key, &ca, &ca_key)
+        .unwrap();
+    fs::write(temp_dir.path().join("ca.pem"), ca.pem()).unwrap();
+    fs::write(temp_dir.path().join("cert.pem"), cert.pem()).unwrap();
+    fs::write(temp_dir.path().join("key.pem"), key.serialize_pem()).unwrap();
+
+    let addr = "127.0.0.1:4011";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "memory", "--addr", addr])
+        .args(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set", "key1", "value1", "--addr", addr, "--tls-ca", "ca.pem",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--tls-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,25 @@
+//! Helpers shared by the integration tests.
+
+// Each test crate uses only some of the helpers.
+#![allow(dead_code)]
+
+use kvs::Result;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// sled releases its file lock from a background thread shortly after the
+// database is dropped, so reopening right away may need a few attempts.
+pub fn open_sled(path: &Path) -> Result<sled::Db> {
+    let mut attempts = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if e.kind() == io::ErrorKind::Other && attempts < 100 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => return Ok(result?),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+mod common;
+
+mod kv_store {
+    use kvs::KvStore;
+    kvs::engine_conformance_tests!(|path| KvStore::open(path), persistent);
+}
+
+mod sled_engine {
+    use crate::common::open_sled;
+    use kvs::{Result, SledKvsEngine};
+    use std::path::Path;
+
+    fn open(path: &Path) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine::new(open_sled(path)?))
+    }
+
+    kvs::engine_conformance_tests!(open, persistent);
+}
+
+mod memory_engine {
+    use kvs::MemoryKvsEngine;
+    kvs::engine_conformance_tests!(|_| Ok(MemoryKvsEngine::new()));
+}
+
+mod kv_store_on_sim_fs {
+    use kvs::{KvStore, SimFs};
+    kvs::engine_conformance_tests!(
+        {
+            let fs = SimFs::new();
+            move |path| KvStore::open_with(fs.clone(), path)
+        },
+        persistent
+    );
+}
@@ -0,0 +1,256 @@
+use kvs::{KvStore, KvsEngine, Result, SimFault, SimFs, Vfs};
+use std::path::{Path, PathBuf};
+
+const DIR: &str = "/data";
+
+fn open(fs: &SimFs) -> Result<KvStore<SimFs>> {
+    KvStore::open_with(fs.clone(), DIR)
+}
+
+// Overwrites `keys` keys until a compaction has replaced every log file.
+fn overwrite_until_compacted(store: &mut KvStore<SimFs>, fs: &SimFs, keys: usize) -> Result<()> {
+    let stale = log_files(fs);
+    let value = "x".repeat(1024);
+    for iter in 0.. {
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+        if log_files(fs).iter().all(|file| !stale.contains(file)) {
+            return Ok(());
+        }
+    }
+    unreachable!()
+}
+
+fn log_files(fs: &SimFs) -> Vec<PathBuf> {
+    fs.list_files(Path::new(DIR)).unwrap()
+}
+
+// Synced commands should survive a power loss, unsynced ones may be lost.
+#[test]
+fn power_loss_keeps_synced_data() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.sync()?;
+    for key_id in 100..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    fs.power_loss();
+    drop(store);
+
+    let mut store = open(&fs)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("key100".to_owned())?, None);
+    Ok(())
+}
+
+// A power loss right after a compaction should not lose the compacted data.
+#[test]
+fn power_loss_after_compaction() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    overwrite_until_compacted(&mut store, &fs, 500)?;
+    fs.power_loss();
+    drop(store);
+
+    let mut store = open(&fs)?;
+    for key_id in 0..500 {
+        assert!(store.get(format!("key{}", key_id))?.is_some());
+    }
+    Ok(())
+}
+
+// Neither the stale logs removed by a compaction nor the log it moved on to
+// should be rolled back by a power loss.
+#[test]
+fn power_loss_keeps_compacted_directory() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    let stale = log_files(&fs);
+    overwrite_until_compacted(&mut store, &fs, 500)?;
+    store.set("last".to_owned(), "value".to_owned())?;
+    store.sync()?;
+    let compacted = log_files(&fs);
+    fs.power_loss();
+    drop(store);
+
+    assert!(log_files(&fs).iter().all(|file| !stale.contains(file)));
+    assert_eq!(log_files(&fs), compacted);
+    let mut store = open(&fs)?;
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A crash between writing the compaction output and deleting the stale logs
+// should leave every synced value readable.
+#[test]
+fn crash_before_stale_logs_are_removed() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    store.sync()?;
+
+    fs.inject(SimFault::RemoveFailure);
+    assert!(overwrite_until_compacted(&mut store, &fs, 500).is_err());
+    fs.power_loss();
+    drop(store);
+    fs.clear_faults();
+
+    let mut store = open(&fs)?;
+    for key_id in 0..500 {
+        assert!(store.get(format!("key{}", key_id))?.is_some());
+    }
+    overwrite_until_compacted(&mut store, &fs, 500)?;
+    Ok(())
+}
+
+// A failed sync of the compaction output should keep the stale logs.
+#[test]
+fn failed_sync_keeps_stale_logs() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    store.sync()?;
+
+    fs.inject(SimFault::SyncFailure);
+    assert!(store.sync().is_err());
+    assert!(overwrite_until_compacted(&mut store, &fs, 500).is_err());
+    fs.power_loss();
+    drop(store);
+    fs.clear_faults();
+
+    let mut store = open(&fs)?;
+    for key_id in 0..500 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("first".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Running out of space in the middle of a command should fail that command
+// only, and leave a log that can still be opened.
+#[test]
+fn no_space_tears_a_single_write() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.inject(SimFault::NoSpace { budget: 10 });
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    fs.clear_faults();
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let mut store = open(&fs)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A torn write at the end of a log should be ignored on open.
+#[test]
+fn power_loss_during_write() -> Result<()> {
+    let fs = SimFs::new();
+    let mut store = open(&fs)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.sync()?;
+
+    fs.inject(SimFault::NoSpace { budget: 15 });
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    fs.clear_faults();
+    // Everything but the torn write becomes durable.
+    store.sync()?;
+    drop(store);
+    fs.power_loss();
+
+    let mut store = open(&fs)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Sets every key to "first", then to "second", in a closed store.
+fn set_twice(fs: &SimFs, keys: usize) -> Result<()> {
+    let mut store = open(fs)?;
+    for value in ["first", "second"] {
+        for key_id in 0..keys {
+            store.set(format!("key{}", key_id), value.to_owned())?;
+        }
+    }
+    store.sync()
+}
+
+fn assert_second(fs: &SimFs, keys: usize) -> Result<()> {
+    let mut store = open(fs)?;
+    for key_id in 0..keys {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("second".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// A repair that stops halfway through writing the new generation should leave
+// the old generations as they were, and finish when run again.
+#[test]
+fn interrupted_repair() -> Result<()> {
+    let fs = SimFs::new();
+    set_twice(&fs, 100)?;
+
+    // The new generation gets about the first half of the records, all of
+    // them "first".
+    fs.inject(SimFault::NoSpace { budget: 3000 });
+    assert!(KvStore::repair_with(fs.clone(), Path::new(DIR)).is_err());
+    fs.clear_faults();
+    assert_second(&fs, 100)?;
+    fs.power_loss();
+    assert_second(&fs, 100)?;
+
+    KvStore::repair_with(fs.clone(), Path::new(DIR))?;
+    assert_second(&fs, 100)?;
+    assert!(
+        log_files(&fs)
+            .iter()
+            .all(|file| file.extension() == Some("log".as_ref()))
+    );
+    Ok(())
+}
+
+// A crash between writing the new generation and deleting the old ones should
+// leave every value readable.
+#[test]
+fn crash_before_repaired_logs_are_removed() -> Result<()> {
+    let fs = SimFs::new();
+    set_twice(&fs, 100)?;
+
+    fs.inject(SimFault::RemoveFailure);
+    assert!(KvStore::repair_with(fs.clone(), Path::new(DIR)).is_err());
+    fs.power_loss();
+    fs.clear_faults();
+    assert_second(&fs, 100)?;
+
+    KvStore::repair_with(fs.clone(), Path::new(DIR))?;
+    assert_second(&fs, 100)?;
+    Ok(())
+}
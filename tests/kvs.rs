@@ -1,4 +1,6 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{KvStore, KvsEngine, KvsError, LogRecord, MemoryKvsEngine, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +126,93 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should list the live keys in ascending order
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+// Should report corrupt records and salvage the readable ones
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    // Garble the first record and append a torn write.
+    let generation = KvStore::generations(temp_dir.path())?[0];
+    let log = temp_dir.path().join(format!("{}.log", generation));
+    let mut content = fs::read(&log)?;
+    content[2] = b'#';
+    fs::write(&log, &content)?;
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(b"{\"Set\":{\"key\":\"key4\",\"val")?;
+    drop(file);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert!(report.index_error.is_some());
+    assert_eq!(report.records, 2);
+    let corrupt: Vec<_> = report
+        .corrupt
+        .iter()
+        .map(|record| match record {
+            LogRecord::Corrupt { file_id, pos, .. } => (*file_id, *pos),
+            LogRecord::Valid { .. } => panic!("valid record reported as corrupt"),
+        })
+        .collect();
+    assert_eq!(
+        corrupt,
+        vec![(generation, 0), (generation, content.len() as u64)]
+    );
+
+    let report = KvStore::repair(temp_dir.path())?;
+    assert_eq!(report.salvaged, 2);
+    assert_eq!(report.dropped, 2);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// The in-memory engine should follow the same semantics as the on-disk ones
+#[test]
+fn memory_engine() -> Result<()> {
+    let mut store = MemoryKvsEngine::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(matches!(
+        store.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.keys()?, vec!["key1".to_owned()]);
+    Ok(())
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use common::open_sled;
+use kvs::{KvStore, KvsEngine, KvsError, Result, SimFs, SledKvsEngine};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+// Small enough that a handful of overwrites triggers a compaction.
+const COMPACTION_THRESHOLD: u64 = 256;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Set(String, String),
+    Get(String),
+    Remove(String),
+    Compact,
+    Reopen,
+}
+
+// Few distinct keys, so that sequences overwrite and remove existing keys.
+fn key() -> impl Strategy<Value = String> {
+    "key[0-7]"
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (key(), "[a-z]{0,40}").prop_map(|(key, value)| Op::Set(key, value)),
+        4 => key().prop_map(Op::Get),
+        3 => key().prop_map(Op::Remove),
+        1 => Just(Op::Compact),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+fn ops() -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(op(), 1..200)
+}
+
+/// Runs `ops` against the engine returned by `open` and against a `BTreeMap`,
+/// and checks that every result and the final content agree.
+fn check_against_model<E, O, C>(ops: &[Op], open: O, compact: C) -> Result<()>
+where
+    E: KvsEngine,
+    O: Fn() -> Result<E>,
+    C: Fn(&mut E) -> Result<()>,
+{
+    let mut model = BTreeMap::new();
+    let mut engine = open()?;
+    for op in ops {
+        match op.clone() {
+            Op::Set(key, value) => {
+                engine.set(key.clone(), value.clone())?;
+                model.insert(key, value);
+            }
+            Op::Get(key) => {
+                assert_eq!(
+                    engine.get(key.clone())?,
+                    model.get(&key).cloned(),
+                    "{:?}",
+                    op
+                );
+            }
+            Op::Remove(key) => match engine.remove(key.clone()) {
+                Ok(()) => assert!(model.remove(&key).is_some(), "{:?} succeeded", op),
+                Err(KvsError::KeyNotFound) => {
+                    assert!(!model.contains_key(&key), "{:?} found no key", op)
+                }
+                Err(e) => return Err(e),
+            },
+            Op::Compact => compact(&mut engine)?,
+            Op::Reopen => {
+                drop(engine);
+                engine = open()?;
+            }
+        }
+    }
+
+    assert_eq!(engine.keys()?, model.keys().cloned().collect::<Vec<_>>());
+    for (key, value) in &model {
+        assert_eq!(engine.get(key.clone())?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn kv_store_matches_model(ops in ops()) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            let mut store = KvStore::open(temp_dir.path())?;
+            store.set_compaction_threshold(COMPACTION_THRESHOLD);
+            Ok(store)
+        };
+        check_against_model(&ops, open, KvStore::compact).unwrap();
+    }
+
+    #[test]
+    fn kv_store_on_sim_fs_matches_model(ops in ops()) {
+        let fs = SimFs::new();
+        let open = || {
+            let mut store = KvStore::open_with(fs.clone(), "/data")?;
+            store.set_compaction_threshold(COMPACTION_THRESHOLD);
+            Ok(store)
+        };
+        check_against_model(&ops, open, KvStore::compact).unwrap();
+    }
+
+    #[test]
+    fn sled_matches_model(ops in ops()) {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || Ok(SledKvsEngine::new(open_sled(temp_dir.path())?));
+        // sled compacts on its own.
+        check_against_model(&ops, open, |_| Ok(())).unwrap();
+    }
+}
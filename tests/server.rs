@@ -0,0 +1,1324 @@
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, ClientConfig, Codec, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, MemoryKvsEngine, PROTOCOL_VERSION, Protocol, RateLimit, RateLimitConfig, Result,
+    ServerConfig, SlowLogConfig, SlowLogEntry,
+};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn spawn_async_server(addr: &'static str) {
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(AsyncKvsServer::new(MemoryKvsEngine::new()).run(addr))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+}
+
+// The blocking server should serve clients one after another.
+#[test]
+fn blocking_server_access() -> Result<()> {
+    let addr = "127.0.0.1:4100";
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// The async server should keep serving while many idle connections are open.
+#[test]
+fn async_server_with_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    spawn_async_server(addr);
+
+    let idle: Vec<_> = (0..500)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<_>>()?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+    drop(idle);
+    Ok(())
+}
+
+// Clients on many threads should see each other's writes.
+#[test]
+fn async_server_concurrent_clients() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    spawn_async_server(addr);
+
+    let handles: Vec<_> = (0..16)
+        .map(|thread_id| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    client.set(key.clone(), format!("value{}", key_id))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", key_id)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    for thread_id in 0..16 {
+        assert_eq!(
+            client.get(format!("key{}-49", thread_id))?,
+            Some("value49".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Tasks sharing one async client should each get their own responses.
+async fn multiplexed_access(addr: &str) -> Result<()> {
+    let client = AsyncKvsClient::connect(addr).await?;
+    let tasks: Vec<_> = (0..32)
+        .map(|task_id| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                for key_id in 0..20 {
+                    let key = format!("key{}-{}", task_id, key_id);
+                    client.set(key.clone(), format!("value{}", key_id)).await?;
+                    assert_eq!(client.get(key).await?, Some(format!("value{}", key_id)));
+                }
+                client.remove(format!("key{}-0", task_id)).await?;
+                assert!(matches!(
+                    client.remove(format!("key{}-0", task_id)).await,
+                    Err(KvsError::KeyNotFound)
+                ));
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    assert_eq!(
+        client.get("key7-19".to_owned()).await?,
+        Some("value19".to_owned())
+    );
+    assert_eq!(client.get("key7-0".to_owned()).await?, None);
+    Ok(())
+}
+
+#[test]
+fn async_client_with_async_server() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    spawn_async_server(addr);
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(multiplexed_access(addr))
+}
+
+#[test]
+fn async_client_with_blocking_server() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(multiplexed_access(addr))
+}
+
+// Requests on a client whose server went away should fail, not hang.
+#[test]
+fn async_client_after_server_closed() -> Result<()> {
+    let addr = "127.0.0.1:4112";
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let server = AsyncKvsServer::new(MemoryKvsEngine::new());
+        let shutdown = server.shutdown_handle();
+        let server = tokio::spawn(server.run(addr));
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let client = AsyncKvsClient::connect(addr).await?;
+        shutdown.shutdown();
+        server.await.unwrap()?;
+        assert!(client.get("key1".to_owned()).await.is_err());
+        assert!(client.get("key1".to_owned()).await.is_err());
+        Ok(())
+    })
+}
+
+// A shutdown should close idle connections, return from `run` and leave the
+// written data in the store.
+#[test]
+fn blocking_server_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect(addr).is_err());
+    drop(client);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn async_server_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = AsyncKvsServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(server.run(addr))
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let idle: Vec<_> = (0..10)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<std::io::Result<_>>()?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect(addr).is_err());
+    drop(idle);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A request that never finishes arriving must not hold up a shutdown for
+// longer than the deadline.
+#[test]
+fn shutdown_deadline_with_partial_request() -> Result<()> {
+    let config = ServerConfig {
+        shutdown_timeout: Duration::from_millis(300),
+        ..ServerConfig::default()
+    };
+    for (addr, use_async) in [("127.0.0.1:4107", false), ("127.0.0.1:4108", true)] {
+        let (shutdown, handle) = if use_async {
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+            let shutdown = server.shutdown_handle();
+            let handle = thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new()?;
+                runtime.block_on(server.run(addr))
+            });
+            (shutdown, handle)
+        } else {
+            let server = KvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+            let shutdown = server.shutdown_handle();
+            (shutdown, thread::spawn(move || server.run(addr)))
+        };
+        thread::sleep(Duration::from_millis(500));
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(br#"{"Set":{"key":"key1","#)?;
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        shutdown.shutdown();
+        handle.join().unwrap()?;
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+    Ok(())
+}
+
+// A pipeline sends requests without waiting for each response in turn and
+// returns the results in request order.
+fn pipelined_access(addr: &str) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for key_id in 0..100 {
+        pipeline.set(format!("key{}", key_id), format!("value{}", key_id));
+    }
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 100);
+    assert!(results.iter().all(|result| matches!(result, Ok(None))));
+
+    let mut pipeline = client.pipeline();
+    for key_id in 0..99 {
+        pipeline.get(format!("key{}", key_id));
+    }
+    let results = pipeline
+        .remove("key99".to_owned())
+        .remove("missing".to_owned())
+        .execute()?;
+    for (key_id, result) in results[..99].iter().enumerate() {
+        assert_eq!(result.as_ref().unwrap(), &Some(format!("value{}", key_id)));
+    }
+    assert!(matches!(results[99], Ok(None)));
+    assert!(matches!(results[100], Err(KvsError::KeyNotFound)));
+    assert_eq!(client.get("key99".to_owned())?, None);
+
+    // The requests of a batch run in order, so each sees those before it.
+    let mut pipeline = client.pipeline();
+    for key_id in 0..100 {
+        let key = format!("ordered{}", key_id);
+        pipeline
+            .set(key.clone(), "first".to_owned())
+            .set(key.clone(), "second".to_owned())
+            .get(key.clone())
+            .remove(key.clone())
+            .get(key);
+    }
+    for results in pipeline.execute()?.chunks(5) {
+        assert_eq!(results[2].as_ref().unwrap(), &Some("second".to_owned()));
+        assert!(matches!(results[3], Ok(None)));
+        assert!(matches!(results[4], Ok(None)));
+    }
+
+    // Single requests still work on the same connection afterwards.
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+
+    // A batch larger than the socket buffers must not leave the client and
+    // the server both waiting for the other to read.
+    let config = ClientConfig {
+        timeout: Some(Duration::from_secs(10)),
+        ..ClientConfig::default()
+    };
+    let mut client = KvsClient::connect_with_config(addr, &config)?;
+    let mut pipeline = client.pipeline();
+    let value = "x".repeat(1024);
+    for key_id in 0..100_000 {
+        let key = format!("large{}", key_id);
+        pipeline.set(key.clone(), value.clone()).get(key);
+    }
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 200_000);
+    for results in results.chunks(2) {
+        assert!(matches!(results[0], Ok(None)));
+        assert_eq!(results[1].as_ref().unwrap(), &Some(value.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn pipelined_blocking_server() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    pipelined_access(addr)
+}
+
+#[test]
+fn pipelined_async_server() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    spawn_async_server(addr);
+    pipelined_access(addr)
+}
+
+// Every response should echo the ID of its request, however the IDs are
+// chosen and in whatever order the responses come back.
+#[test]
+fn responses_echo_request_ids() -> Result<()> {
+    let addr = "127.0.0.1:4111";
+    spawn_async_server(addr);
+
+    let ids = [42u64, 7, u64::MAX, 0, 7_000_000];
+    let mut stream = TcpStream::connect(addr)?;
+    for id in ids {
+        let frame = serde_json::json!({
+            "id": id,
+            "request": { "Set": { "key": format!("key{}", id), "value": "value" } },
+        });
+        serde_json::to_writer(&mut stream, &frame)?;
+    }
+    stream.flush()?;
+
+    let mut echoed: Vec<u64> = serde_json::Deserializer::from_reader(&stream)
+        .into_iter::<serde_json::Value>()
+        .take(ids.len())
+        .map(|frame| {
+            let frame = frame.unwrap();
+            assert_eq!(
+                frame["response"],
+                serde_json::json!({ "Set": { "Ok": null } })
+            );
+            frame["id"].as_u64().unwrap()
+        })
+        .collect();
+    echoed.sort_unstable();
+    let mut expected = ids.to_vec();
+    expected.sort_unstable();
+    assert_eq!(echoed, expected);
+    Ok(())
+}
+
+// Clients asking for JSON in the handshake should be served in JSON, next to
+// clients using the binary codec.
+#[test]
+fn json_codec() -> Result<()> {
+    let json = ClientConfig {
+        codec: Codec::Json,
+        ..ClientConfig::default()
+    };
+    let addr = "127.0.0.1:4113";
+    spawn_async_server(addr);
+    let blocking_addr = "127.0.0.1:4114";
+    thread::spawn(move || {
+        KvsServer::new(MemoryKvsEngine::new())
+            .run(blocking_addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [addr, blocking_addr] {
+        // One connection at a time, for the blocking server.
+        let mut client = KvsClient::connect_with_config(addr, &json)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        drop(client);
+        let mut client = KvsClient::connect(addr)?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.remove("key1".to_owned())?;
+        drop(client);
+        let mut client = KvsClient::connect_with_config(addr, &json)?;
+        assert!(matches!(
+            client.remove("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, &json).await?;
+        client.set("key2".to_owned(), "value2".to_owned()).await?;
+        assert_eq!(
+            client.get("key2".to_owned()).await?,
+            Some("value2".to_owned())
+        );
+        Ok(())
+    })
+}
+
+// A message over the frame size limit should close the connection before it
+// is buffered in full, in either codec.
+#[test]
+fn oversized_frames_are_rejected() -> Result<()> {
+    let config = ServerConfig {
+        max_frame_len: 1024,
+        ..ServerConfig::default()
+    };
+    let addr = "127.0.0.1:4115";
+    let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.run(addr)).unwrap();
+    });
+    let blocking_addr = "127.0.0.1:4116";
+    let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+    thread::spawn(move || server.run(blocking_addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [addr, blocking_addr] {
+        for codec in [Codec::Binary, Codec::Json] {
+            let config = ClientConfig {
+                codec,
+                ..ClientConfig::default()
+            };
+            let mut client = KvsClient::connect_with_config(addr, &config)?;
+            assert!(client.set("key1".to_owned(), "x".repeat(4096)).is_err());
+            let mut client = KvsClient::connect_with_config(addr, &config)?;
+            client.set("key1".to_owned(), "x".repeat(512))?;
+            assert_eq!(client.get("key1".to_owned())?, Some("x".repeat(512)));
+        }
+    }
+    Ok(())
+}
+
+// The handshake should tell clients about the server, and turn away clients
+// speaking another protocol version with a reply saying why.
+#[test]
+fn protocol_version_handshake() -> Result<()> {
+    let addr = "127.0.0.1:4117";
+    spawn_async_server(addr);
+    let blocking_addr = "127.0.0.1:4118";
+    thread::spawn(move || {
+        KvsServer::new(MemoryKvsEngine::new())
+            .run(blocking_addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [addr, blocking_addr] {
+        let client = KvsClient::connect(addr)?;
+        let info = client.server_info();
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(info.server_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.engine, "memory");
+        assert!(info.supports("pipelining"));
+        assert!(info.supports("slowlog"));
+        drop(client);
+
+        let mut stream = TcpStream::connect(addr)?;
+        let hello = serde_json::json!({
+            "Hello": { "codecs": ["Json"], "protocol_version": PROTOCOL_VERSION + 1 },
+        });
+        serde_json::to_writer(&mut stream, &hello)?;
+        stream.flush()?;
+        let reply: serde_json::Value = serde_json::Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .unwrap()?;
+        assert_eq!(
+            reply["Rejected"]["protocol_version"],
+            serde_json::json!(PROTOCOL_VERSION)
+        );
+        // The server closes the connection after turning the client away.
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest)?, 0);
+    }
+    Ok(())
+}
+
+/// Sends `args` as a RESP command and returns the raw reply.
+fn resp_call(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<String> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.get_mut().write_all(command.as_bytes())?;
+    resp_reply(stream)
+}
+
+/// Reads one RESP reply and returns it raw.
+fn resp_reply(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut reply = String::new();
+    stream.read_line(&mut reply)?;
+    let len: i64 = reply[1..reply.len() - 2].parse().unwrap_or(0);
+    match reply.as_bytes()[0] {
+        b'$' if len >= 0 => {
+            let mut bulk = vec![0; len as usize + 2];
+            stream.read_exact(&mut bulk)?;
+            reply += &String::from_utf8(bulk).unwrap();
+        }
+        b'*' => {
+            for _ in 0..len {
+                reply += &resp_reply(stream)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(reply)
+}
+
+// A server speaking RESP should serve Redis clients, mapping their commands
+// onto the engine.
+#[test]
+fn resp_protocol() -> Result<()> {
+    let config = ServerConfig {
+        protocol: Protocol::Resp,
+        ..ServerConfig::default()
+    };
+    let addr = "127.0.0.1:4119";
+    let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.run(addr)).unwrap();
+    });
+    let blocking_addr = "127.0.0.1:4120";
+    let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+    thread::spawn(move || server.run(blocking_addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [addr, blocking_addr] {
+        let mut stream = BufReader::new(TcpStream::connect(addr)?);
+        let mut call = |args: &[&str]| resp_call(&mut stream, args).unwrap();
+        assert_eq!(call(&["PING"]), "+PONG\r\n");
+        assert_eq!(call(&["ping", "hello"]), "$5\r\nhello\r\n");
+        assert_eq!(call(&["SET", "key1", "value1"]), "+OK\r\n");
+        assert_eq!(call(&["SET", "key2", "value2"]), "+OK\r\n");
+        assert_eq!(call(&["SET", "other", "value3"]), "+OK\r\n");
+        assert_eq!(call(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+        assert_eq!(call(&["GET", "missing"]), "$-1\r\n");
+        assert_eq!(call(&["EXISTS", "key1", "key2", "missing"]), ":2\r\n");
+        assert_eq!(
+            call(&["SCAN", "0", "MATCH", "key*"]),
+            "*2\r\n$1\r\n0\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+        );
+        assert_eq!(
+            call(&["SCAN", "0", "COUNT", "2"]),
+            "*2\r\n$1\r\n2\r\n*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+        );
+        assert_eq!(
+            call(&["SCAN", "2", "COUNT", "2"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$5\r\nother\r\n"
+        );
+        assert_eq!(call(&["DEL", "key2", "missing"]), ":1\r\n");
+        assert_eq!(call(&["EXPIRE", "key1", "1"]), ":1\r\n");
+        assert_eq!(call(&["EXPIRE", "missing", "1"]), ":0\r\n");
+        assert_eq!(call(&["SET", "other", "value4", "PX", "100"]), "+OK\r\n");
+        assert!(call(&["INFO"]).contains("engine:memory\r\n"));
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(call(&["GET", "key1"]), "$-1\r\n");
+        assert_eq!(call(&["EXISTS", "other"]), ":0\r\n");
+
+        assert_eq!(call(&["FLUSHALL"]), "-ERR unknown command 'flushall'\r\n");
+        assert_eq!(
+            call(&["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            call(&["EXPIRE", "key1", "soon"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        let too_late = i64::MAX.to_string();
+        assert_eq!(
+            call(&["SET", "key4", "value4", "EX", &too_late]),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(call(&["SET", "key4", "value4"]), "+OK\r\n");
+        assert_eq!(
+            call(&["EXPIRE", "key4", &too_late]),
+            "-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(call(&["GET", "key4"]), "$6\r\nvalue4\r\n");
+        // Inline commands, as typed into telnet.
+        stream
+            .get_mut()
+            .write_all(b"SET key3 value3\r\nGET key3\r\n")?;
+        assert_eq!(resp_reply(&mut stream)?, "+OK\r\n");
+        assert_eq!(resp_reply(&mut stream)?, "$6\r\nvalue3\r\n");
+        drop(stream);
+
+        // Invalid RESP gets an error reply, then the connection is closed.
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(b"*1\r\n+PING\r\n")?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        assert_eq!(reply, "-ERR Protocol error: expected '$', got '+'\r\n");
+    }
+    Ok(())
+}
+
+/// Sends an HTTP request on a kept-alive connection and returns the status
+/// and body of the response.
+fn http_call(
+    stream: &mut BufReader<TcpStream>,
+    method: &str,
+    target: &str,
+    body: &str,
+) -> Result<(u16, String)> {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(request.as_bytes())?;
+
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut len = 0;
+    loop {
+        line.clear();
+        stream.read_line(&mut line)?;
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+            len = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body)?;
+    Ok((status, String::from_utf8(body).unwrap()))
+}
+
+// The HTTP gateway should serve the engine of the server it sits next to.
+#[test]
+fn http_gateway() -> Result<()> {
+    let addrs = [
+        ("127.0.0.1:4121", "127.0.0.1:4122"),
+        ("127.0.0.1:4123", "127.0.0.1:4124"),
+    ];
+    for (i, (addr, http_addr)) in addrs.into_iter().enumerate() {
+        let config = ServerConfig {
+            http_addr: Some(http_addr.parse().unwrap()),
+            ..ServerConfig::default()
+        };
+        if i == 0 {
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(server.run(addr)).unwrap();
+            });
+        } else {
+            let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || server.run(addr).unwrap());
+        }
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    for (addr, http_addr) in addrs {
+        // The gateway answers while a client of the main protocol stays
+        // connected, and the other way round.
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+
+        let stream = TcpStream::connect(http_addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut stream = BufReader::new(stream);
+        let mut call = |method, target, body| http_call(&mut stream, method, target, body).unwrap();
+        assert_eq!(
+            call("GET", "/keys/key1", ""),
+            (200, r#"{"key":"key1","value":"value1"}"#.to_owned())
+        );
+        assert_eq!(
+            call("PUT", "/keys/key%202", r#"{"value":"value2"}"#),
+            (204, String::new())
+        );
+        assert_eq!(
+            call("PUT", "/keys/other", r#"{"value":"value3"}"#),
+            (204, String::new())
+        );
+        assert_eq!(
+            call("GET", "/keys?prefix=key", ""),
+            (200, r#"{"keys":["key 2","key1"]}"#.to_owned())
+        );
+        assert_eq!(
+            call("GET", "/keys?limit=1", ""),
+            (200, r#"{"keys":["key 2"]}"#.to_owned())
+        );
+        assert_eq!(call("DELETE", "/keys/other", ""), (204, String::new()));
+        assert_eq!(
+            call("DELETE", "/keys/other", ""),
+            (404, r#"{"error":"Key not found"}"#.to_owned())
+        );
+        assert_eq!(
+            call("GET", "/keys/other", ""),
+            (404, r#"{"error":"Key not found"}"#.to_owned())
+        );
+        assert_eq!(call("PUT", "/keys/key3", "value3").0, 400);
+        assert_eq!(call("GET", "/keys?limit=few", "").0, 400);
+        assert_eq!(call("POST", "/keys/key1", "").0, 405);
+        assert_eq!(call("GET", "/values", "").0, 404);
+        assert_eq!(client.get("key 2".to_owned())?, Some("value2".to_owned()));
+        drop(client);
+        drop(stream);
+
+        // Garbage gets a 400, then the connection is closed.
+        let mut stream = TcpStream::connect(http_addr)?;
+        stream.write_all(b"NOT HTTP AT ALL\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+    Ok(())
+}
+
+// Servers and clients should work the same over Unix domain sockets, and the
+// socket file should be gone after a shutdown.
+#[test]
+fn unix_socket_transport() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("async.sock");
+    let blocking_path = temp_dir.path().join("blocking.sock");
+    let addr = format!("unix:{}", path.display());
+    let blocking_addr = format!("unix:{}", blocking_path.display());
+
+    let server = AsyncKvsServer::new(MemoryKvsEngine::new());
+    let handle = server.shutdown_handle();
+    let server_addr = addr.clone();
+    let async_server = thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.run(server_addr)).unwrap();
+    });
+    let server = KvsServer::new(MemoryKvsEngine::new());
+    let blocking_handle = server.shutdown_handle();
+    let server_addr = blocking_addr.clone();
+    let blocking_server = thread::spawn(move || server.run(server_addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [&addr, &blocking_addr] {
+        let mut client = KvsClient::connect(addr.as_str())?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(client);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let client = AsyncKvsClient::connect(addr.as_str()).await?;
+            assert_eq!(
+                client.get("key1".to_owned()).await?,
+                Some("value1".to_owned())
+            );
+            client.remove("key1".to_owned()).await
+        })?;
+    }
+
+    handle.shutdown();
+    blocking_handle.shutdown();
+    async_server.join().unwrap();
+    blocking_server.join().unwrap();
+    assert!(!path.exists());
+    assert!(!blocking_path.exists());
+    Ok(())
+}
+
+// Binding a Unix socket over a file that is not a socket should fail and leave
+// the file alone.
+#[test]
+fn unix_socket_over_regular_file() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let path = temp_dir.path().join("data.db");
+    fs::write(&path, "precious")?;
+    let addr = format!("unix:{}", path.display());
+
+    let server = KvsServer::new(MemoryKvsEngine::new());
+    assert!(server.run(addr.as_str()).is_err());
+    let runtime = tokio::runtime::Runtime::new()?;
+    let server = AsyncKvsServer::new(MemoryKvsEngine::new());
+    assert!(runtime.block_on(server.run(addr.as_str())).is_err());
+    assert_eq!(fs::read_to_string(&path)?, "precious");
+    Ok(())
+}
+
+// Servers and clients should talk over in-memory pipes, with no socket at all.
+#[test]
+fn in_memory_pipes() -> Result<()> {
+    let (server_reader, client_writer) = io::pipe()?;
+    let (client_reader, server_writer) = io::pipe()?;
+    let server = thread::spawn(move || {
+        KvsServer::new(MemoryKvsEngine::new()).serve_stream(server_reader, server_writer)
+    });
+    let mut client =
+        KvsClient::from_stream(client_reader, client_writer, &ClientConfig::default())?;
+    assert_eq!(client.server_info().engine, "memory");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    server.join().unwrap()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+        let server = AsyncKvsServer::new(MemoryKvsEngine::new());
+        let served = tokio::spawn(async move { server.serve_stream(server_end).await });
+        let client = AsyncKvsClient::from_stream(client_end, &ClientConfig::default()).await?;
+        client.set("key1".to_owned(), "value1".to_owned()).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        drop(client);
+        served.await.unwrap()
+    })
+}
+
+// Admin requests should report on the server and its engine, and compaction
+// should drop the stale bytes of a `KvStore`.
+#[test]
+fn admin_requests() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let blocking_addr = "127.0.0.1:4125";
+    let async_addr = "127.0.0.1:4126";
+    let store = KvStore::open(temp_dir.path().join("blocking"))?;
+    thread::spawn(move || KvsServer::new(store).run(blocking_addr).unwrap());
+    let store = KvStore::open(temp_dir.path().join("async"))?;
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(AsyncKvsServer::new(store).run(async_addr))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    for addr in [blocking_addr, async_addr] {
+        let mut client = KvsClient::connect(addr)?;
+        client.ping()?;
+        for value in ["value1", "value2", "value3"] {
+            client.set("key1".to_owned(), value.to_owned())?;
+        }
+        client.set("key2".to_owned(), "value1".to_owned())?;
+
+        let status = client.info()?;
+        assert_eq!(status.engine, "kvs");
+        assert_eq!(status.server_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(status.connections, 1);
+        assert_eq!(status.total_connections, 1);
+
+        let stats = client.stats()?;
+        assert_eq!(stats.keys, 2);
+        assert!(stats.live_bytes > 0);
+        assert!(stats.stale_bytes > 0);
+        assert_eq!(stats.generations, 1);
+        assert_eq!(stats.disk_bytes, stats.live_bytes + stats.stale_bytes);
+
+        client.compact()?;
+        let compacted = client.stats()?;
+        assert_eq!(compacted.keys, 2);
+        assert_eq!(compacted.live_bytes, stats.live_bytes);
+        assert_eq!(compacted.stale_bytes, 0);
+        assert_eq!(compacted.disk_bytes, compacted.live_bytes);
+        assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+        drop(client);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async {
+            let client = AsyncKvsClient::connect(addr).await?;
+            client.ping().await?;
+            assert_eq!(client.info().await?.total_connections, 2);
+            assert_eq!(client.stats().await?.keys, 2);
+            client.compact().await
+        })?;
+    }
+    Ok(())
+}
+
+// The metrics endpoint should count requests, bytes and connections, and
+// report the numbers of the engine, even while a client of the main protocol
+// stays connected.
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addrs = [
+        ("127.0.0.1:4127", "127.0.0.1:4128"),
+        ("127.0.0.1:4129", "127.0.0.1:4130"),
+    ];
+    for (i, (addr, metrics_addr)) in addrs.into_iter().enumerate() {
+        let config = ServerConfig {
+            metrics_addr: Some(metrics_addr.parse().unwrap()),
+            ..ServerConfig::default()
+        };
+        let store = KvStore::open(temp_dir.path().join(i.to_string()))?;
+        if i == 0 {
+            let server = KvsServer::with_config(store, config);
+            thread::spawn(move || server.run(addr).unwrap());
+        } else {
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                let server = AsyncKvsServer::with_config(store, config);
+                runtime.block_on(server.run(addr)).unwrap();
+            });
+        }
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    for (addr, metrics_addr) in addrs {
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key1".to_owned(), "value2".to_owned())?;
+        client.get("key1".to_owned())?;
+        assert!(client.remove("key2".to_owned()).is_err());
+        client.compact()?;
+
+        let stream = TcpStream::connect(metrics_addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut stream = BufReader::new(stream);
+        let (status, metrics) = http_call(&mut stream, "GET", "/metrics", "")?;
+        assert_eq!(status, 200);
+        for line in [
+            "kvs_requests_total{protocol=\"kvs\",type=\"set\",result=\"ok\"} 2",
+            "kvs_requests_total{protocol=\"kvs\",type=\"get\",result=\"ok\"} 1",
+            "kvs_requests_total{protocol=\"kvs\",type=\"remove\",result=\"error\"} 1",
+            "kvs_request_duration_seconds_bucket{protocol=\"kvs\",type=\"set\",le=\"+Inf\"} 2",
+            "kvs_request_duration_seconds_count{protocol=\"kvs\",type=\"set\"} 2",
+            "# TYPE kvs_request_duration_seconds histogram",
+            "kvs_connections 2",
+            "kvs_connections_total 2",
+            "kvs_engine_keys 1",
+            "kvs_engine_stale_bytes 0",
+            "kvs_engine_compactions_total 1",
+        ] {
+            assert!(
+                metrics.lines().any(|metric| metric == line),
+                "{} missing from\n{}",
+                line,
+                metrics
+            );
+        }
+        let received = metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("kvs_received_bytes_total "))
+            .unwrap();
+        assert!(received.parse::<u64>().unwrap() > 0);
+
+        assert_eq!(http_call(&mut stream, "GET", "/keys", "")?.0, 404);
+        assert_eq!(http_call(&mut stream, "PUT", "/metrics", "")?.0, 405);
+        drop(client);
+    }
+    Ok(())
+}
+
+// With a threshold of zero, every request should make it into the slow log,
+// in memory and in its file.
+#[test]
+fn slow_log() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for (addr, asynchronous) in [("127.0.0.1:4131", false), ("127.0.0.1:4132", true)] {
+        let path = temp_dir.path().join(format!("slow-{}.log", asynchronous));
+        let config = ServerConfig {
+            slow_log: Some(SlowLogConfig {
+                threshold: Duration::ZERO,
+                capacity: 2,
+                path: Some(path.clone()),
+            }),
+            ..ServerConfig::default()
+        };
+        if asynchronous {
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(server.run(addr)).unwrap();
+            });
+        } else {
+            let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || server.run(addr).unwrap());
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        client.ping()?;
+
+        // The entry of a request is logged once it is answered, so the
+        // slow log request itself is not among them.
+        let entries = client.slow_log(10)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[0].operation, "ping");
+        let get = &entries[1];
+        assert_eq!(get.id, 1);
+        assert_eq!(get.protocol, "kvs");
+        assert_eq!(get.operation, "get");
+        assert_eq!(get.key_len, 4);
+        assert_eq!(get.value_len, 6);
+        assert!(get.peer.starts_with("127.0.0.1:"));
+        assert_eq!(client.slow_log(1)?.len(), 1);
+
+        // The file is written by a thread of its own.
+        let mut logged = Vec::new();
+        for _ in 0..100 {
+            logged = fs::read_to_string(&path)
+                .unwrap_or_default()
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<serde_json::Result<Vec<SlowLogEntry>>>()?;
+            if logged.len() >= 4 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(logged.len() >= 4);
+        assert_eq!(logged[0].operation, "set");
+        assert_eq!(logged[0].value_len, 6);
+        assert_eq!(&logged[1], get);
+    }
+    Ok(())
+}
+
+// A client should not send a slow log request to a server that did not
+// announce the feature, which could not decode it.
+#[test]
+fn slow_log_needs_feature() -> Result<()> {
+    let welcome = format!(
+        r#"{{"Welcome":{{"codec":"Json","protocol_version":{},"features":["pipelining"]}}}}"#,
+        PROTOCOL_VERSION
+    );
+    let mut client = KvsClient::from_stream(
+        io::Cursor::new(welcome.into_bytes()),
+        io::sink(),
+        &ClientConfig {
+            codec: Codec::Json,
+            ..ClientConfig::default()
+        },
+    )?;
+    match client.slow_log(1) {
+        Err(KvsError::StringError(message)) => assert!(message.contains("slowlog")),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// Connections without a request for the idle timeout are closed, and so are
+// those stalling in the middle of a request for the read timeout.
+#[test]
+fn connection_timeouts() -> Result<()> {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        read_timeout: Some(Duration::from_millis(300)),
+        ..ServerConfig::default()
+    };
+    for (addr, use_async) in [("127.0.0.1:4133", false), ("127.0.0.1:4134", true)] {
+        if use_async {
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(server.run(addr)).unwrap();
+            });
+        } else {
+            let server = KvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+            thread::spawn(move || server.run(addr).unwrap());
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        let start = Instant::now();
+        let mut idle = TcpStream::connect(addr)?;
+        idle.set_read_timeout(Some(Duration::from_secs(3)))?;
+        // The blocking server only gets to the client once the idle
+        // connection is closed.
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(idle.read(&mut [0; 16])?, 0);
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        let start = Instant::now();
+        let mut partial = TcpStream::connect(addr)?;
+        partial.set_read_timeout(Some(Duration::from_secs(3)))?;
+        partial.write_all(br#"{"version":"#)?;
+        // The connection may be reset rather than closed.
+        assert!(matches!(partial.read(&mut [0; 16]), Ok(0) | Err(_)));
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+    Ok(())
+}
+
+#[test]
+fn max_connections() -> Result<()> {
+    let addr = "127.0.0.1:4135";
+    let config = ServerConfig {
+        max_connections: Some(1),
+        ..ServerConfig::default()
+    };
+    let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(server.run(addr)).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+    assert!(KvsClient::connect(addr).is_err());
+    client.ping()?;
+
+    // The connection is available again once the first one is closed.
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    KvsClient::connect(addr)?.ping()?;
+    Ok(())
+}
+
+// Clients give up on a server that accepts connections but never answers.
+#[test]
+fn client_timeouts() -> Result<()> {
+    let addr = "127.0.0.1:4136";
+    let listener = std::net::TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        let silent: Vec<_> = listener.incoming().collect();
+        drop(silent);
+    });
+    let config = ClientConfig {
+        connect_timeout: Some(Duration::from_millis(200)),
+        ..ClientConfig::default()
+    };
+
+    let start = Instant::now();
+    match KvsClient::connect_with_config(addr, &config) {
+        Err(KvsError::Timeout(_)) => {}
+        other => panic!("Unexpected result: {:?}", other.err()),
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    match runtime.block_on(AsyncKvsClient::connect_with_config(addr, &config)) {
+        Err(KvsError::Timeout(_)) => {}
+        other => panic!("Unexpected result: {:?}", other.err()),
+    }
+    assert!(start.elapsed() < Duration::from_secs(3));
+    Ok(())
+}
+
+// Requests past their deadline are answered with a timeout, which a client
+// that did not announce the feature gets as a plain error.
+#[test]
+fn request_timeouts() -> Result<()> {
+    let config = ServerConfig {
+        request_timeout: Some(Duration::ZERO),
+        ..ServerConfig::default()
+    };
+    let (server_reader, client_writer) = io::pipe()?;
+    let (client_reader, server_writer) = io::pipe()?;
+    let mut server = KvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+    let served = thread::spawn(move || server.serve_stream(server_reader, server_writer));
+    let mut client =
+        KvsClient::from_stream(client_reader, client_writer, &ClientConfig::default())?;
+    match client.get("key1".to_owned()) {
+        Err(KvsError::Timeout(_)) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    drop(client);
+    served.join().unwrap()?;
+
+    let mut server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+    let mut output = Vec::new();
+    server.serve_stream(
+        &br#"{"id":1,"request":{"Get":{"key":"key1"}}}"#[..],
+        &mut output,
+    )?;
+    let reply: serde_json::Value = serde_json::from_slice(&output)?;
+    let message = reply["response"]["Get"]["Err"]["Other"].as_str().unwrap();
+    assert!(message.contains("Timed out"));
+    Ok(())
+}
+
+// An async client whose request times out halfway through being written
+// should give up on the connection rather than send more after the torn frame.
+#[test]
+fn async_client_torn_request() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (client_end, mut server_end) = tokio::io::duplex(1024);
+        // A server that welcomes the client and then stops reading.
+        let server = tokio::spawn(async move {
+            let mut hello = [0; 1024];
+            let _ = server_end.read(&mut hello).await?;
+            let welcome = format!(
+                r#"{{"Welcome":{{"codec":"Json","protocol_version":{}}}}}"#,
+                PROTOCOL_VERSION
+            );
+            server_end.write_all(welcome.as_bytes()).await?;
+            Ok::<_, io::Error>(server_end)
+        });
+        let config = ClientConfig {
+            codec: Codec::Json,
+            timeout: Some(Duration::from_millis(200)),
+            ..ClientConfig::default()
+        };
+        let client = AsyncKvsClient::from_stream(client_end, &config).await?;
+        let _server_end = server.await.unwrap()?;
+
+        match client.set("key1".to_owned(), "x".repeat(4096)).await {
+            Err(KvsError::Timeout(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match client.get("key1".to_owned()).await {
+            Err(KvsError::StringError(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        Ok(())
+    })
+}
+
+// Writes over the budget of a connection are throttled, without closing the
+// connection or holding reads back.
+#[test]
+fn rate_limits() -> Result<()> {
+    let addrs = [
+        ("127.0.0.1:4137", "127.0.0.1:4138", false),
+        ("127.0.0.1:4139", "127.0.0.1:4140", true),
+    ];
+    for (addr, http_addr, use_async) in addrs {
+        let config = ServerConfig {
+            http_addr: Some(http_addr.parse().unwrap()),
+            rate_limit: Some(RateLimitConfig {
+                connection_writes: Some(RateLimit {
+                    per_second: 4.0,
+                    burst: 2,
+                }),
+                ..RateLimitConfig::default()
+            }),
+            ..ServerConfig::default()
+        };
+        if use_async {
+            let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(server.run(addr)).unwrap();
+            });
+        } else {
+            let server = KvsServer::with_config(MemoryKvsEngine::new(), config);
+            thread::spawn(move || server.run(addr).unwrap());
+        }
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        client.set("key2".to_owned(), "value2".to_owned())?;
+        match client.set("key3".to_owned(), "value3".to_owned()) {
+            Err(KvsError::Throttled(wait)) => {
+                assert!(!wait.is_zero() && wait <= Duration::from_millis(250))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(client);
+
+        // A client honouring the hint gets through, late.
+        let config = ClientConfig {
+            max_throttle_wait: Some(Duration::from_secs(2)),
+            ..ClientConfig::default()
+        };
+        let mut client = KvsClient::connect_with_config(addr, &config)?;
+        let start = Instant::now();
+        for i in 0..4 {
+            client.set(format!("key{}", i), "value".to_owned())?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        drop(client);
+
+        let mut stream = BufReader::new(TcpStream::connect(http_addr)?);
+        let mut call = |method, target, body| http_call(&mut stream, method, target, body).unwrap();
+        let body = r#"{"value":"value"}"#;
+        assert_eq!(call("PUT", "/keys/key1", body).0, 204);
+        assert_eq!(call("PUT", "/keys/key1", body).0, 204);
+        let (status, error) = call("PUT", "/keys/key1", body);
+        assert_eq!(status, 429);
+        assert!(error.contains("Throttled"));
+        assert_eq!(call("GET", "/keys/key1", "").0, 200);
+
+        // A client that did not announce the feature is told in plain words.
+        let mut stream = TcpStream::connect(addr)?;
+        for id in 0..3 {
+            let request = serde_json::json!({
+                "id": id,
+                "request": { "Set": { "key": "key1", "value": "value" } },
+            });
+            serde_json::to_writer(&mut stream, &request)?;
+        }
+        stream.flush()?;
+        let replies: Vec<serde_json::Value> = serde_json::Deserializer::from_reader(&stream)
+            .into_iter()
+            .take(3)
+            .collect::<serde_json::Result<_>>()?;
+        let throttled = replies.iter().find(|reply| reply["id"] == 2).unwrap();
+        let message = throttled["response"]["Set"]["Err"]["Other"]
+            .as_str()
+            .unwrap();
+        assert!(message.contains("Throttled"));
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, ClientConfig, ClientTlsConfig, KvsClient, KvsError, KvsServer,
+    MemoryKvsEngine, Result, ServerConfig, ServerTlsConfig,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::thread;
+use std::time::Duration;
+
+/// A certificate authority, generated afresh for each test.
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new() -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        Ca { cert, key }
+    }
+
+    fn pem(&self) -> Vec<u8> {
+        self.cert.pem().into_bytes()
+    }
+
+    /// Issues a certificate for `names`, returning it and its key in PEM.
+    fn issue(&self, names: &[&str]) -> (Vec<u8>, Vec<u8>) {
+        let key = KeyPair::generate().unwrap();
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let params = CertificateParams::new(names).unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        (cert.pem().into_bytes(), key.serialize_pem().into_bytes())
+    }
+
+    fn server_tls(&self, client_ca: Option<&Ca>) -> Result<ServerTlsConfig> {
+        let (cert, key) = self.issue(&["localhost", "127.0.0.1"]);
+        let client_ca = client_ca.map(Ca::pem);
+        ServerTlsConfig::new(&cert, &key, client_ca.as_deref())
+    }
+}
+
+fn client_config(tls: ClientTlsConfig) -> ClientConfig {
+    ClientConfig {
+        tls: Some(tls),
+        ..ClientConfig::default()
+    }
+}
+
+/// Starts a blocking and an async server speaking TLS on the given ports.
+fn spawn_servers(tls: ServerTlsConfig, blocking_addr: &'static str, async_addr: &'static str) {
+    let config = ServerConfig {
+        tls: Some(tls),
+        ..ServerConfig::default()
+    };
+    let server = KvsServer::with_config(MemoryKvsEngine::new(), config.clone());
+    thread::spawn(move || server.run(blocking_addr).unwrap());
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = AsyncKvsServer::with_config(MemoryKvsEngine::new(), config);
+        runtime.block_on(server.run(async_addr)).unwrap();
+    });
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn round_trip(addr: &str, config: &ClientConfig) -> Result<()> {
+    let mut client = KvsClient::connect_with_config(addr, config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let client = AsyncKvsClient::connect_with_config(addr, config).await?;
+        assert_eq!(
+            client.get("key1".to_owned()).await?,
+            Some("value1".to_owned())
+        );
+        client.remove("key1".to_owned()).await
+    })
+}
+
+// Clients trusting the server's certificate authority should talk to it over
+// TLS, while plaintext clients and clients trusting another authority should
+// be turned away.
+#[test]
+fn tls_connections() -> Result<()> {
+    let ca = Ca::new();
+    spawn_servers(ca.server_tls(None)?, "127.0.0.1:4200", "127.0.0.1:4201");
+
+    let trusting = client_config(ClientTlsConfig::new(&ca.pem(), None)?);
+    let distrusting = client_config(ClientTlsConfig::new(&Ca::new().pem(), None)?);
+    for addr in ["127.0.0.1:4200", "127.0.0.1:4201"] {
+        round_trip(addr, &trusting)?;
+
+        assert!(KvsClient::connect(addr).is_err());
+        match KvsClient::connect_with_config(addr, &distrusting) {
+            Err(KvsError::Tls(_)) => {}
+            Err(e) => panic!("Expected a TLS error, got {}", e),
+            Ok(_) => panic!("Connected to a server with an untrusted certificate"),
+        }
+    }
+
+    // The name checked against the certificate can be set explicitly.
+    let named = client_config(ClientTlsConfig::new(&ca.pem(), None)?.with_server_name("localhost"));
+    round_trip("127.0.0.1:4200", &named)?;
+    let misnamed =
+        client_config(ClientTlsConfig::new(&ca.pem(), None)?.with_server_name("kvs.example"));
+    assert!(KvsClient::connect_with_config("127.0.0.1:4200", &misnamed).is_err());
+    Ok(())
+}
+
+// With mutual TLS, only clients presenting a certificate from the client
+// certificate authority should be served.
+#[test]
+fn mutual_tls() -> Result<()> {
+    let ca = Ca::new();
+    let client_ca = Ca::new();
+    let tls = ca.server_tls(Some(&client_ca))?;
+    spawn_servers(tls, "127.0.0.1:4202", "127.0.0.1:4203");
+
+    let (cert, key) = client_ca.issue(&["client"]);
+    let identified = client_config(ClientTlsConfig::new(&ca.pem(), Some((&cert, &key)))?);
+    let anonymous = client_config(ClientTlsConfig::new(&ca.pem(), None)?);
+    let (cert, key) = ca.issue(&["client"]);
+    let misidentified = client_config(ClientTlsConfig::new(&ca.pem(), Some((&cert, &key)))?);
+    for addr in ["127.0.0.1:4202", "127.0.0.1:4203"] {
+        round_trip(addr, &identified)?;
+        assert!(KvsClient::connect_with_config(addr, &anonymous).is_err());
+        assert!(KvsClient::connect_with_config(addr, &misidentified).is_err());
+    }
+    Ok(())
+}
+
+// Certificates and keys that cannot be parsed should be configuration errors.
+#[test]
+fn invalid_tls_configuration() {
+    assert!(matches!(
+        ServerTlsConfig::new(b"not a certificate", b"not a key", None),
+        Err(KvsError::TlsConfig(_))
+    ));
+    assert!(matches!(
+        ClientTlsConfig::new(b"", None),
+        Err(KvsError::TlsConfig(_))
+    ));
+}
@@ -0,0 +1,214 @@
+//! The invariants of the fuzz targets in `fuzz/`, checked on random and
+//! hand-picked inputs as part of the regular test suite.
+
+use kvs::{
+    KvStore, KvsEngine, KvsError, KvsServer, MemoryKvsEngine, PROTOCOL_VERSION, ServerConfig,
+    SimFs, Vfs,
+};
+use proptest::prelude::*;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+fn replay_log(data: &[u8]) {
+    let fs = SimFs::new();
+    let dir = Path::new("/data");
+    fs.create_dir_all(dir).unwrap();
+    fs.create_new(&dir.join("1.log"))
+        .unwrap()
+        .write_all(data)
+        .unwrap();
+
+    let Ok(mut store) = KvStore::open_with(fs.clone(), dir) else {
+        return;
+    };
+    for key in store.keys().unwrap() {
+        let _ = store.get(key);
+    }
+    let _ = store.compact();
+    store.set("key".to_owned(), "value".to_owned()).unwrap();
+}
+
+fn serve(data: &[u8]) -> Vec<u8> {
+    let mut server = KvsServer::new(MemoryKvsEngine::new());
+    let mut output = Vec::new();
+    let _ = server.serve_stream(data, &mut output);
+    output
+}
+
+#[test]
+fn log_replay_edge_cases() {
+    let nested = "[".repeat(100_000);
+    for data in [
+        &b""[..],
+        b"{",
+        b"{\"Set\":{\"key\":\"a\",\"value\":\"b\"}}{\"Set\":",
+        b"{\"Remove\":{\"key\":\"a\"}}",
+        b"{\"Set\":{\"key\":\"a\",\"value\":\"\\ud800\"}}",
+        b"{\"Get\":{\"key\":\"a\"}}",
+        b"\xff\xfe\x00",
+        nested.as_bytes(),
+    ] {
+        replay_log(data);
+    }
+}
+
+#[test]
+fn wire_protocol_edge_cases() {
+    let nested = "{\"id\":0,\"request\":{\"Get\":".repeat(100_000);
+    for data in [
+        &b""[..],
+        b"{\"id\":0,\"request\":{\"Get\":{\"key\":\"a\"}}}{\"id\":1,\"request\":",
+        b"{\"id\":0,\"request\":{\"Remove\":{\"key\":\"a\"}}}",
+        b"{\"id\":-1,\"request\":{\"Remove\":{\"key\":\"a\"}}}",
+        b"{\"id\":0,\"request\":{\"Set\":{\"key\":1}}}",
+        b"{\"Get\":{\"key\":\"a\"}}",
+        b"\xff\xfe\x00",
+        nested.as_bytes(),
+    ] {
+        serve(data);
+    }
+
+    let output = serve(
+        b"{\"id\":7,\"request\":{\"Set\":{\"key\":\"a\",\"value\":\"b\"}}}\
+          {\"id\":3,\"request\":{\"Get\":{\"key\":\"a\"}}}",
+    );
+    assert_eq!(
+        output,
+        b"{\"id\":7,\"response\":{\"Set\":{\"Ok\":null}}}\
+          {\"id\":3,\"response\":{\"Get\":{\"Ok\":\"b\"}}}"
+    );
+}
+
+#[test]
+fn oversized_frames_are_errors() {
+    let mut server = KvsServer::new(MemoryKvsEngine::new());
+    // A binary frame announcing 4 GiB after the handshake.
+    let mut data = format!(
+        r#"{{"Hello":{{"codecs":["Binary"],"protocol_version":{}}}}}"#,
+        PROTOCOL_VERSION
+    )
+    .into_bytes();
+    data.extend_from_slice(&[0xff; 4]);
+    assert!(matches!(
+        server.serve_stream(&data[..], io::sink()),
+        Err(KvsError::FrameTooLarge(_))
+    ));
+
+    // A JSON string that never ends.
+    let mut server = KvsServer::with_config(
+        MemoryKvsEngine::new(),
+        ServerConfig {
+            max_frame_len: 1024 * 1024,
+            ..ServerConfig::default()
+        },
+    );
+    let endless = io::repeat(b'a');
+    let data = (&b"{\"id\":0,\"request\":{\"Get\":{\"key\":\""[..]).chain(endless);
+    assert!(matches!(
+        server.serve_stream(data, io::sink()),
+        Err(KvsError::FrameTooLarge(_))
+    ));
+}
+
+#[test]
+fn unversioned_handshake_is_rejected() {
+    let mut server = KvsServer::new(MemoryKvsEngine::new());
+    let mut output = Vec::new();
+    let result = server.serve_stream(
+        &b"{\"Hello\":{\"codecs\":[\"Json\"]}}{\"id\":0,\"request\":{\"Get\":{\"key\":\"a\"}}}"[..],
+        &mut output,
+    );
+    assert!(matches!(
+        result,
+        Err(KvsError::ProtocolMismatch { client: 0, .. })
+    ));
+    assert!(output.starts_with(b"{\"Rejected\":"));
+}
+
+#[test]
+fn serve_stream_reports_garbage_as_error() {
+    let mut server = KvsServer::new(MemoryKvsEngine::new());
+    assert!(server.serve_stream(&b"garbage"[..], io::sink()).is_err());
+    assert!(server.serve_stream(&b""[..], io::sink()).is_ok());
+}
+
+// A reader handing out `data` in pieces of `len` bytes.
+struct Pieces<'a> {
+    data: &'a [u8],
+    len: usize,
+}
+
+impl Read for Pieces<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.len.min(buf.len()).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(len)
+    }
+}
+
+// A JSON frame has to be found whole however it is split, with brackets and
+// escaped quotes inside its strings.
+#[test]
+fn json_frames_arriving_in_pieces() {
+    let frames = |value: &str| {
+        format!(
+            r#"{{"id":0,"request":{{"Set":{{"key":"a\"}}","value":"{}"}}}}}} {{"id":1,"request":{{"Get":{{"key":"a\"}}"}}}}}}"#,
+            value
+        )
+    };
+    for (value, len) in [
+        (r#"{[\\\"]}"#.to_owned(), 1),
+        (r#"{[\\\"]}"#.to_owned(), 7),
+        ("x".repeat(1024 * 1024), 1024),
+    ] {
+        let data = frames(&value);
+        let mut server = KvsServer::new(MemoryKvsEngine::new());
+        let mut output = Vec::new();
+        server
+            .serve_stream(
+                Pieces {
+                    data: data.as_bytes(),
+                    len,
+                },
+                &mut output,
+            )
+            .unwrap();
+        assert_eq!(output, serve(data.as_bytes()));
+        let get = format!(r#"{{"id":1,"response":{{"Get":{{"Ok":"{}"}}}}}}"#, value);
+        assert!(output.ends_with(get.as_bytes()));
+    }
+}
+
+proptest! {
+    #[test]
+    fn log_replay_never_panics(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        replay_log(&data);
+    }
+
+    #[test]
+    fn log_replay_of_mangled_records_never_panics(
+        records in prop::collection::vec(("[a-c]{1,3}", "[a-z]{0,8}", any::<bool>()), 1..16),
+        cut in any::<prop::sample::Index>(),
+        flip in any::<prop::sample::Index>(),
+    ) {
+        let mut data = Vec::new();
+        for (key, value, remove) in records {
+            let record = if remove {
+                format!("{{\"Remove\":{{\"key\":\"{}\"}}}}", key)
+            } else {
+                format!("{{\"Set\":{{\"key\":\"{}\",\"value\":\"{}\"}}}}", key, value)
+            };
+            data.extend_from_slice(record.as_bytes());
+        }
+        let flip = flip.index(data.len());
+        data[flip] ^= 0x20;
+        data.truncate(cut.index(data.len() + 1));
+        replay_log(&data);
+    }
+
+    #[test]
+    fn wire_protocol_never_panics(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        serve(&data);
+    }
+}